@@ -0,0 +1,151 @@
+//! Allergies recorded on the patient chart
+
+use crate::audit::{audited, audited_create, audited_read, record_reads};
+use crate::auth::caller_context;
+use crate::error::Error;
+use crate::index::{allergy_ids_by_patient, index_allergy, unindex_allergy};
+use crate::models::{Allergy, AllergySeverity, AuditAction, AuditEntity, ConsentScope};
+use crate::patient::get_patient_by_id;
+use crate::storage::ALLERGY_STORAGE;
+use crate::utils::generate_id;
+
+#[ic_cdk::update]
+pub fn add_allergy(
+    patient_id: u64,
+    substance: String,
+    reaction: String,
+    severity: AllergySeverity,
+) -> Result<Allergy, Error> {
+    audited_create(
+        "add_allergy",
+        AuditEntity::Allergy,
+        |allergy: &Allergy| allergy.id,
+        || {
+            validate_allergy(&substance, &reaction)?;
+
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
+
+            let caller = caller_context();
+            caller.require_chart_writer(patient_id)?;
+
+            let allergy = Allergy {
+                id: generate_id(),
+                patient_id,
+                substance,
+                reaction,
+                severity,
+                recorded_at: ic_cdk::api::time(),
+                recorded_by: caller.principal,
+            };
+
+            insert_allergy(&allergy);
+            index_allergy(&allergy);
+            Ok(allergy)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn get_allergy(allergy_id: u64) -> Result<Allergy, Error> {
+    audited_read("get_allergy", AuditEntity::Allergy, allergy_id, || {
+        let allergy = get_existing_allergy(allergy_id)?;
+        caller_context().require_patient_access(allergy.patient_id, ConsentScope::ReadRecords)?;
+        Ok(allergy)
+    })
+}
+
+#[ic_cdk::update]
+pub fn list_allergies(patient_id: u64) -> Result<Vec<Allergy>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadRecords)?;
+
+    let allergies = get_allergies_by_patient(patient_id);
+    record_reads(
+        "list_allergies",
+        AuditEntity::Allergy,
+        allergies.iter().map(|allergy| allergy.id),
+    );
+    Ok(allergies)
+}
+
+#[ic_cdk::update]
+pub fn update_allergy(
+    allergy_id: u64,
+    substance: String,
+    reaction: String,
+    severity: AllergySeverity,
+) -> Result<Allergy, Error> {
+    audited(
+        "update_allergy",
+        AuditEntity::Allergy,
+        allergy_id,
+        AuditAction::Update,
+        || {
+            validate_allergy(&substance, &reaction)?;
+
+            let mut allergy = get_existing_allergy(allergy_id)?;
+            caller_context().require_chart_writer(allergy.patient_id)?;
+
+            allergy.substance = substance;
+            allergy.reaction = reaction;
+            allergy.severity = severity;
+            insert_allergy(&allergy);
+            Ok(allergy)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn delete_allergy(allergy_id: u64) -> Result<(), Error> {
+    audited(
+        "delete_allergy",
+        AuditEntity::Allergy,
+        allergy_id,
+        AuditAction::Delete,
+        || {
+            let allergy = get_existing_allergy(allergy_id)?;
+            caller_context().require_chart_writer(allergy.patient_id)?;
+
+            ALLERGY_STORAGE.with(|service| service.borrow_mut().remove(&allergy_id));
+            unindex_allergy(&allergy);
+            Ok(())
+        },
+    )
+}
+
+pub fn get_allergy_by_id(allergy_id: &u64) -> Option<Allergy> {
+    ALLERGY_STORAGE.with(|service| service.borrow().get(allergy_id))
+}
+
+pub fn get_allergies_by_patient(patient_id: u64) -> Vec<Allergy> {
+    ALLERGY_STORAGE.with(|service| {
+        let storage = service.borrow();
+        allergy_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|allergy_id| storage.get(allergy_id))
+            .collect()
+    })
+}
+
+fn get_existing_allergy(allergy_id: u64) -> Result<Allergy, Error> {
+    get_allergy_by_id(&allergy_id).ok_or(Error::NotFound {
+        msg: format!("Allergy with id={} not found", allergy_id),
+    })
+}
+
+fn insert_allergy(allergy: &Allergy) {
+    ALLERGY_STORAGE.with(|service| service.borrow_mut().insert(allergy.id, allergy.clone()));
+}
+
+fn validate_allergy(substance: &str, reaction: &str) -> Result<(), Error> {
+    if substance.trim().is_empty() || reaction.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Substance and reaction cannot be empty".to_string(),
+        });
+    }
+    Ok(())
+}
\ No newline at end of file
@@ -1,17 +1,36 @@
 //! Appointment management functionality
 
-use crate::availability::update_availability_status;
+use std::ops::RangeInclusive;
+
+use crate::auth::{caller_context, unauthorized, CallerContext};
 use crate::doctor::get_doctor_by_id;
 use crate::error::Error;
-use crate::models::Appointment;
+use crate::index::{
+    appointment_ids_by_doctor, appointment_ids_by_patient, index_appointment, unindex_appointment,
+};
+use crate::models::{
+    Appointment, AppointmentFilter, AppointmentStatus, IndexKey, Page, PageRequest, Reschedule,
+    Role, Slot, StatusChange,
+};
+use crate::pagination::{matches, paginate, paginate_ids};
 use crate::patient::get_patient_by_id;
-use crate::storage::{APPOINTMENT_STORAGE, AVAILABILITY_STORAGE};
+use crate::schedule::find_slot;
+use crate::storage::{APPOINTMENT_RESCHEDULES, APPOINTMENT_STATUS_HISTORY, APPOINTMENT_STORAGE};
 use crate::utils::generate_id;
 
+/// Field limits keeping an appointment within its `MAX_SIZE` of 1536 bytes;
+/// status changes and reschedules are kept in tables of their own.
+pub const MAX_PHONE_NO_LEN: usize = 32;
+pub const MAX_APPOINTMENT_TEXT_LEN: usize = 256;
+pub const MAX_APPOINTMENT_TYPE_LEN: usize = 64;
+
 #[ic_cdk::query]
 pub fn get_appointment(appointment_id: u64) -> Result<Appointment, Error> {
     match get_appointment_by_id(&appointment_id) {
-        Some(appointment) => Ok(appointment),
+        Some(appointment) => {
+            caller_context().require_appointment_party(&appointment)?;
+            Ok(appointment)
+        }
         None => Err(Error::NotFound {
             msg: format!("appointment with id={} not found", appointment_id),
         }),
@@ -23,10 +42,9 @@ pub fn add_appointment(
     patient_id: u64,
     doctor_id: u64,
     phone_no: String,
-    slot: String,
+    slot_start: u64,
     reason: String,
     symtoms: String,
-    status: String,
     appointment_type: String,
 ) -> Result<Appointment, Error> {
     // Validate input data
@@ -35,6 +53,11 @@ pub fn add_appointment(
             msg: "phone_no cannot be empty".to_string(),
         });
     }
+    validate_details(&phone_no, &reason, &symtoms, &appointment_type)?;
+
+    // Patients book for themselves, front-desk staff for anyone
+    let caller = caller_context();
+    caller.require_booking_for(patient_id)?;
 
     // Check if the doctor and patient exist
     if get_doctor_by_id(&doctor_id).is_none() {
@@ -48,29 +71,8 @@ pub fn add_appointment(
         });
     }
 
-    // Find the available slot for the doctor
-    let available_slot = AVAILABILITY_STORAGE.with(|service| {
-        service.borrow().iter().find(|(_, availability)| {
-            availability.doctor_id == doctor_id
-                && availability.is_available
-                && availability.start_time == slot
-        })
-    });
-
-    if available_slot.is_none() {
-        return Err(Error::InvalidInput {
-            msg: "Selected slot is not available".to_string(),
-        });
-    }
-
-    // Mark the slot as unavailable
-    let mut availability = available_slot.unwrap().1.clone();
-    availability.is_available = false;
-    AVAILABILITY_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .insert(availability.id, availability.clone())
-    });
+    // Find the doctor's slot containing the requested time
+    let slot = find_open_slot(doctor_id, patient_id, slot_start, None)?;
 
     let id = generate_id();
 
@@ -79,14 +81,24 @@ pub fn add_appointment(
         patient_id,
         doctor_id,
         phone_no,
-        slot,
+        slot_start: slot.start_time,
+        slot_end: slot.end_time,
         reason,
         symtoms,
-        status: "pending".to_string(),
+        status: AppointmentStatus::Requested,
         appointment_type,
     };
 
     APPOINTMENT_STORAGE.with(|service| service.borrow_mut().insert(id, appointment.clone()));
+    index_appointment(&appointment);
+    record_status_change(
+        id,
+        StatusChange {
+            status: AppointmentStatus::Requested,
+            at: ic_cdk::api::time(),
+            by: caller.principal,
+        },
+    );
     Ok(appointment)
 }
 
@@ -96,10 +108,8 @@ pub fn update_appointment(
     patient_id: u64,
     doctor_id: u64,
     phone_no: String,
-    slot: String,
     reason: String,
     symtoms: String,
-    status: String,
     appointment_type: String,
 ) -> Result<Appointment, Error> {
     // Validate input data
@@ -108,29 +118,70 @@ pub fn update_appointment(
             msg: "Phone number cannot be empty".to_string(),
         });
     }
+    validate_details(&phone_no, &reason, &symtoms, &appointment_type)?;
 
     // Check if the appointment exists
-    if get_appointment_by_id(&appointment_id).is_none() {
-        return Err(Error::NotFound {
-            msg: format!("Appointment with id={} not found", appointment_id),
+    let current_appointment = match get_appointment_by_id(&appointment_id) {
+        Some(appointment) => appointment,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Appointment with id={} not found", appointment_id),
+            })
+        }
+    };
+
+    // Only the parties of the appointment may change it, and not reassign it
+    let caller = caller_context();
+    caller.require_appointment_party(&current_appointment)?;
+    if !caller.is_admin
+        && (current_appointment.patient_id != patient_id
+            || current_appointment.doctor_id != doctor_id)
+    {
+        return Err(unauthorized(
+            "Appointments cannot be moved to another patient or doctor",
+        ));
+    }
+
+    // The new doctor must offer the booked slot
+    if doctor_id != current_appointment.doctor_id
+        && current_appointment.status != AppointmentStatus::Unscheduled
+    {
+        let offered = find_slot(doctor_id, current_appointment.slot_start).map_or(false, |slot| {
+            slot.start_time == current_appointment.slot_start
+                && slot.end_time == current_appointment.slot_end
         });
+        if !offered {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Doctor with id={} has no slot from {} to {}",
+                    doctor_id, current_appointment.slot_start, current_appointment.slot_end
+                ),
+            });
+        }
     }
 
-    // If the appointment is canceled or completed, mark the slot as available
-    if status == "cancelled" || status == "confirmed" {
-        update_availability_status(doctor_id, &slot, true);
+    // A reassigned appointment must not double-book its new parties
+    if let Some(conflict) = find_conflicting_appointment(
+        doctor_id,
+        Some(patient_id),
+        current_appointment.slot_start,
+        current_appointment.slot_end,
+        Some(appointment_id),
+    ) {
+        return Err(conflict_error(&conflict));
     }
 
+    // Status only changes through the transition endpoints and the slot
+    // through `reschedule_appointment`
     let updated_appointment = Appointment {
         id: appointment_id,
         patient_id,
         doctor_id,
         phone_no,
-        slot,
         reason,
         symtoms,
-        status,
         appointment_type,
+        ..current_appointment.clone()
     };
 
     // Update the appointment in storage
@@ -139,7 +190,11 @@ pub fn update_appointment(
             .borrow_mut()
             .insert(appointment_id, updated_appointment.clone())
     }) {
-        Some(_) => Ok(updated_appointment),
+        Some(_) => {
+            unindex_appointment(&current_appointment);
+            index_appointment(&updated_appointment);
+            Ok(updated_appointment)
+        }
         None => Err(Error::NotFound {
             msg: format!("Appointment with id={} not found", appointment_id),
         }),
@@ -147,98 +202,518 @@ pub fn update_appointment(
 }
 
 #[ic_cdk::update]
-pub fn cancel_appointment(appointment_id: u64) -> Result<Appointment, Error> {
-    let current_appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
+pub fn reschedule_appointment(appointment_id: u64, new_slot: u64) -> Result<Appointment, Error> {
+    let mut appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
         msg: format!("Appointment with id={} not found", appointment_id),
     })?;
 
-    let mut updated_appointment = current_appointment.clone();
-    updated_appointment.status = "cancelled".to_string();
+    let caller = caller_context();
+    caller.require_appointment_party(&appointment)?;
 
-    update_availability_status(
-        current_appointment.doctor_id,
-        &current_appointment.slot,
-        true,
-    );
+    // Everything is checked before the single write below, so a failed
+    // reschedule leaves the appointment holding its old slot
+    let slot = find_open_slot(
+        appointment.doctor_id,
+        appointment.patient_id,
+        new_slot,
+        Some(appointment_id),
+    )?;
+    if slot.start_time == appointment.slot_start {
+        return Err(Error::InvalidInput {
+            msg: "Appointment is already booked in the selected slot".to_string(),
+        });
+    }
+
+    // Occupancy follows the appointment, so moving it releases the old slot
+    let reschedule = Reschedule {
+        from_start: appointment.slot_start,
+        from_end: appointment.slot_end,
+        to_start: slot.start_time,
+        to_end: slot.end_time,
+        at: ic_cdk::api::time(),
+        by: caller.principal.clone(),
+    };
+    appointment.slot_start = slot.start_time;
+    appointment.slot_end = slot.end_time;
+
+    apply_transition(
+        &mut appointment,
+        AppointmentStatus::Rescheduled,
+        &caller.principal,
+    )?;
 
     APPOINTMENT_STORAGE.with(|service| {
         service
             .borrow_mut()
-            .insert(appointment_id, updated_appointment.clone())
+            .insert(appointment_id, appointment.clone())
     });
+    record_reschedule(appointment_id, reschedule);
 
-    Ok(updated_appointment)
+    Ok(appointment)
 }
 
 #[ic_cdk::update]
-pub fn complete_appointment(appointment_id: u64) -> Result<Appointment, Error> {
-    let current_appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
-        msg: format!("Appointment with id={} not found", appointment_id),
-    })?;
+pub fn confirm_appointment(appointment_id: u64) -> Result<Appointment, Error> {
+    transition_appointment(
+        appointment_id,
+        AppointmentStatus::Confirmed,
+        |caller, appointment| require_doctor_or_front_desk(caller, appointment),
+    )
+}
 
-    let mut updated_appointment = current_appointment.clone();
-    updated_appointment.status = "confirmed".to_string();
+#[ic_cdk::update]
+pub fn check_in_appointment(appointment_id: u64) -> Result<Appointment, Error> {
+    transition_appointment(
+        appointment_id,
+        AppointmentStatus::CheckedIn,
+        |caller, appointment| {
+            if caller.has_role(Role::Nurse) {
+                return Ok(());
+            }
+            require_doctor_or_front_desk(caller, appointment)
+        },
+    )
+}
 
-    update_availability_status(
-        current_appointment.doctor_id,
-        &current_appointment.slot,
-        true,
-    );
+#[ic_cdk::update]
+pub fn start_appointment(appointment_id: u64) -> Result<Appointment, Error> {
+    transition_appointment(
+        appointment_id,
+        AppointmentStatus::InProgress,
+        |caller, appointment| caller.require_doctor(appointment.doctor_id),
+    )
+}
 
-    APPOINTMENT_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .insert(appointment_id, updated_appointment.clone())
-    });
+#[ic_cdk::update]
+pub fn complete_appointment(appointment_id: u64) -> Result<Appointment, Error> {
+    // Only the treating doctor completes an appointment
+    transition_appointment(
+        appointment_id,
+        AppointmentStatus::Completed,
+        |caller, appointment| caller.require_doctor(appointment.doctor_id),
+    )
+}
+
+#[ic_cdk::update]
+pub fn cancel_appointment(appointment_id: u64) -> Result<Appointment, Error> {
+    transition_appointment(
+        appointment_id,
+        AppointmentStatus::Cancelled,
+        |caller, appointment| caller.require_appointment_party(appointment),
+    )
+}
 
-    Ok(updated_appointment)
+#[ic_cdk::update]
+pub fn mark_appointment_no_show(appointment_id: u64) -> Result<Appointment, Error> {
+    transition_appointment(
+        appointment_id,
+        AppointmentStatus::NoShow,
+        |caller, appointment| require_doctor_or_front_desk(caller, appointment),
+    )
 }
 
 #[ic_cdk::update]
 pub fn delete_appointment(appointment_id: u64) -> Result<(), Error> {
+    if let Some(appointment) = get_appointment_by_id(&appointment_id) {
+        caller_context().require_doctor(appointment.doctor_id)?;
+    }
+
     match APPOINTMENT_STORAGE.with(|service| service.borrow_mut().remove(&appointment_id)) {
-        Some(_) => Ok(()),
+        Some(appointment) => {
+            unindex_appointment(&appointment);
+            remove_status_history(appointment_id);
+            remove_reschedules(appointment_id);
+            Ok(())
+        }
         None => Err(Error::NotFound {
             msg: format!("Appointment with id={} not found", appointment_id),
         }),
     }
 }
 
+/// Status changes of the appointment, oldest first.
+#[ic_cdk::query]
+pub fn get_appointment_history(appointment_id: u64) -> Result<Vec<StatusChange>, Error> {
+    let appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
+        msg: format!("Appointment with id={} not found", appointment_id),
+    })?;
+
+    caller_context().require_appointment_party(&appointment)?;
+    Ok(get_status_history(appointment_id))
+}
+
+/// Moves of the appointment between slots, oldest first.
+#[ic_cdk::query]
+pub fn get_appointment_reschedules(appointment_id: u64) -> Result<Vec<Reschedule>, Error> {
+    let appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
+        msg: format!("Appointment with id={} not found", appointment_id),
+    })?;
+
+    caller_context().require_appointment_party(&appointment)?;
+    Ok(get_reschedules(appointment_id))
+}
+
+#[ic_cdk::query]
+pub fn list_appointments(page: PageRequest, filter: AppointmentFilter) -> Page<Appointment> {
+    let caller = caller_context();
+    let keep = |appointment: &Appointment| {
+        matches(&filter.doctor_id, &appointment.doctor_id)
+            && matches(&filter.patient_id, &appointment.patient_id)
+            && matches(&filter.status, &appointment.status)
+            && filter
+                .from
+                .map_or(true, |from| appointment.slot_start >= from)
+            && filter.to.map_or(true, |to| appointment.slot_start < to)
+            && caller.is_appointment_party(appointment)
+    };
+
+    let ids = match (filter.doctor_id, filter.patient_id) {
+        (Some(doctor_id), _) => appointment_ids_by_doctor(doctor_id),
+        (None, Some(patient_id)) => appointment_ids_by_patient(patient_id),
+        (None, None) if caller.is_admin || caller.has_role(Role::Receptionist) => {
+            return paginate(&APPOINTMENT_STORAGE, &page, keep)
+        }
+        // Anyone else only sees their own appointments, found by index
+        (None, None) => party_appointment_ids(&caller),
+    };
+    paginate_ids(ids, &page, get_appointment_by_id, keep)
+}
+
+#[ic_cdk::query]
+pub fn filter_appointments_by_doctor_id(doctor_id: u64) -> Vec<Appointment> {
+    let caller = caller_context();
+    get_appointments_by_ids(&appointment_ids_by_doctor(doctor_id))
+        .into_iter()
+        .filter(|appointment| caller.is_appointment_party(appointment))
+        .collect()
+}
+
 #[ic_cdk::query]
-pub fn list_appointments() -> Vec<Appointment> {
+pub fn filter_appointments_by_patient_id(patient_id: u64) -> Vec<Appointment> {
+    let caller = caller_context();
+    get_appointments_by_ids(&appointment_ids_by_patient(patient_id))
+        .into_iter()
+        .filter(|appointment| caller.is_appointment_party(appointment))
+        .collect()
+}
+
+pub fn get_appointment_by_id(appointment_id: &u64) -> Option<Appointment> {
+    APPOINTMENT_STORAGE.with(|service| service.borrow().get(appointment_id))
+}
+
+pub fn get_appointments_by_ids(appointment_ids: &[u64]) -> Vec<Appointment> {
     APPOINTMENT_STORAGE.with(|service| {
-        service
-            .borrow()
+        let storage = service.borrow();
+        appointment_ids
             .iter()
-            .map(|(_, appointment)| appointment.clone())
+            .filter_map(|appointment_id| storage.get(appointment_id))
             .collect()
     })
 }
 
-#[ic_cdk::query]
-pub fn filter_appointments_by_doctor_id(doctor_id: u64) -> Vec<Appointment> {
-    APPOINTMENT_STORAGE.with(|service| {
+/// Statuses an appointment may move to from `status`.
+pub fn allowed_transitions(status: AppointmentStatus) -> &'static [AppointmentStatus] {
+    use AppointmentStatus::*;
+
+    match status {
+        Requested => &[Confirmed, Cancelled, Rescheduled],
+        Confirmed => &[CheckedIn, Cancelled, NoShow, Rescheduled],
+        Rescheduled => &[Confirmed, Cancelled, Rescheduled],
+        Unscheduled => &[Cancelled, Rescheduled],
+        CheckedIn => &[InProgress, Cancelled],
+        InProgress => &[Completed],
+        Completed | Cancelled | NoShow => &[],
+    }
+}
+
+/// Moves the appointment to `to` and records the transition in its history,
+/// rejecting transitions missing from `allowed_transitions`. Callers store the
+/// appointment afterwards.
+pub fn apply_transition(
+    appointment: &mut Appointment,
+    to: AppointmentStatus,
+    by: &str,
+) -> Result<(), Error> {
+    if !allowed_transitions(appointment.status).contains(&to) {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "Appointment with id={} cannot move from {:?} to {:?}",
+                appointment.id, appointment.status, to
+            ),
+        });
+    }
+
+    appointment.status = to;
+    record_status_change(
+        appointment.id,
+        StatusChange {
+            status: to,
+            at: ic_cdk::api::time(),
+            by: by.to_string(),
+        },
+    );
+    Ok(())
+}
+
+pub fn get_status_history(appointment_id: u64) -> Vec<StatusChange> {
+    APPOINTMENT_STATUS_HISTORY.with(|service| {
         service
             .borrow()
-            .iter()
-            .filter(|(_, appointment)| appointment.doctor_id == doctor_id)
-            .map(|(_, appointment)| appointment.clone())
+            .range(history_range(appointment_id))
+            .map(|(_, change)| change)
             .collect()
     })
 }
 
-#[ic_cdk::query]
-pub fn filter_appointments_by_patient_id(patient_id: u64) -> Vec<Appointment> {
-    APPOINTMENT_STORAGE.with(|service| {
+/// Replaces the status history of an appointment, e.g. when erasure drops
+/// the patient's principal from it.
+pub fn set_status_history(appointment_id: u64, changes: Vec<StatusChange>) {
+    remove_status_history(appointment_id);
+    for change in changes {
+        record_status_change(appointment_id, change);
+    }
+}
+
+pub fn remove_status_history(appointment_id: u64) {
+    let keys: Vec<IndexKey> = APPOINTMENT_STATUS_HISTORY.with(|service| {
         service
             .borrow()
-            .iter()
-            .filter(|(_, appointment)| appointment.patient_id == patient_id)
-            .map(|(_, appointment)| appointment.clone())
+            .range(history_range(appointment_id))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    APPOINTMENT_STATUS_HISTORY.with(|service| {
+        let mut history = service.borrow_mut();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+}
+
+/// Moves the history an appointment kept inline into the history table,
+/// unless the appointment already has one there.
+pub fn store_legacy_status_history(appointment_id: u64, changes: Vec<StatusChange>) {
+    if get_status_history(appointment_id).is_empty() {
+        set_status_history(appointment_id, changes);
+    }
+}
+
+fn record_status_change(appointment_id: u64, change: StatusChange) {
+    APPOINTMENT_STATUS_HISTORY.with(|service| {
+        let mut history = service.borrow_mut();
+        let seq = history.range(history_range(appointment_id)).count() as u64;
+        history.insert(
+            IndexKey {
+                owner: appointment_id,
+                id: seq,
+            },
+            change,
+        );
+    });
+}
+
+// Appointments of the caller as a patient and as a doctor, in id order
+fn party_appointment_ids(caller: &CallerContext) -> Vec<u64> {
+    let mut ids = caller
+        .patient_id
+        .map(appointment_ids_by_patient)
+        .unwrap_or_default();
+    if let Some(doctor_id) = caller.doctor_id {
+        ids.extend(appointment_ids_by_doctor(doctor_id));
+        ids.sort_unstable();
+        ids.dedup();
+    }
+    ids
+}
+
+pub fn get_reschedules(appointment_id: u64) -> Vec<Reschedule> {
+    APPOINTMENT_RESCHEDULES.with(|service| {
+        service
+            .borrow()
+            .range(history_range(appointment_id))
+            .map(|(_, reschedule)| reschedule)
             .collect()
     })
 }
 
-pub fn get_appointment_by_id(appointment_id: &u64) -> Option<Appointment> {
-    APPOINTMENT_STORAGE.with(|service| service.borrow().get(appointment_id))
+/// Replaces the reschedules of an appointment, e.g. when erasure drops the
+/// patient's principal from them.
+pub fn set_reschedules(appointment_id: u64, reschedules: Vec<Reschedule>) {
+    remove_reschedules(appointment_id);
+    for reschedule in reschedules {
+        record_reschedule(appointment_id, reschedule);
+    }
+}
+
+pub fn remove_reschedules(appointment_id: u64) {
+    let keys: Vec<IndexKey> = APPOINTMENT_RESCHEDULES.with(|service| {
+        service
+            .borrow()
+            .range(history_range(appointment_id))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    APPOINTMENT_RESCHEDULES.with(|service| {
+        let mut reschedules = service.borrow_mut();
+        for key in keys {
+            reschedules.remove(&key);
+        }
+    });
+}
+
+/// Moves the reschedules an appointment kept inline into their table, unless
+/// the appointment already has some there.
+pub fn store_legacy_reschedules(appointment_id: u64, reschedules: Vec<Reschedule>) {
+    if get_reschedules(appointment_id).is_empty() {
+        set_reschedules(appointment_id, reschedules);
+    }
+}
+
+fn record_reschedule(appointment_id: u64, reschedule: Reschedule) {
+    APPOINTMENT_RESCHEDULES.with(|service| {
+        let mut reschedules = service.borrow_mut();
+        let seq = reschedules.range(history_range(appointment_id)).count() as u64;
+        reschedules.insert(
+            IndexKey {
+                owner: appointment_id,
+                id: seq,
+            },
+            reschedule,
+        );
+    });
+}
+
+// Keys of one appointment in the status history and reschedule tables
+fn history_range(appointment_id: u64) -> RangeInclusive<IndexKey> {
+    IndexKey {
+        owner: appointment_id,
+        id: 0,
+    }..=IndexKey {
+        owner: appointment_id,
+        id: u64::MAX,
+    }
+}
+
+/// Whether an appointment in `status` keeps its slot occupied.
+pub fn holds_slot(status: AppointmentStatus) -> bool {
+    !matches!(
+        status,
+        AppointmentStatus::Cancelled | AppointmentStatus::NoShow | AppointmentStatus::Unscheduled
+    )
+}
+
+/// Finds an appointment other than `except` that holds its slot and overlaps
+/// `[start, end)` for the doctor or, if given, the patient.
+pub fn find_conflicting_appointment(
+    doctor_id: u64,
+    patient_id: Option<u64>,
+    start: u64,
+    end: u64,
+    except: Option<u64>,
+) -> Option<Appointment> {
+    let mut candidates = appointment_ids_by_doctor(doctor_id);
+    if let Some(patient_id) = patient_id {
+        candidates.extend(appointment_ids_by_patient(patient_id));
+    }
+
+    get_appointments_by_ids(&candidates)
+        .into_iter()
+        .find(|appointment| {
+            Some(appointment.id) != except
+                && holds_slot(appointment.status)
+                && appointment.slot_start < end
+                && start < appointment.slot_end
+        })
+}
+
+pub fn conflict_error(appointment: &Appointment) -> Error {
+    Error::AppointmentConflict {
+        msg: format!(
+            "Conflicts with appointment id={} of doctor_id={} and patient_id={} from {} to {}",
+            appointment.id,
+            appointment.doctor_id,
+            appointment.patient_id,
+            appointment.slot_start,
+            appointment.slot_end
+        ),
+    }
+}
+
+// Neither the doctor nor the patient may be double-booked
+fn find_open_slot(
+    doctor_id: u64,
+    patient_id: u64,
+    at: u64,
+    except: Option<u64>,
+) -> Result<Slot, Error> {
+    let slot = find_slot(doctor_id, at).ok_or(Error::InvalidInput {
+        msg: "Selected slot is not available".to_string(),
+    })?;
+
+    match find_conflicting_appointment(
+        doctor_id,
+        Some(patient_id),
+        slot.start_time,
+        slot.end_time,
+        except,
+    ) {
+        Some(conflict) => Err(conflict_error(&conflict)),
+        None => Ok(slot),
+    }
+}
+
+fn transition_appointment(
+    appointment_id: u64,
+    to: AppointmentStatus,
+    authorize: impl FnOnce(&CallerContext, &Appointment) -> Result<(), Error>,
+) -> Result<Appointment, Error> {
+    let mut appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
+        msg: format!("Appointment with id={} not found", appointment_id),
+    })?;
+
+    let caller = caller_context();
+    authorize(&caller, &appointment)?;
+    apply_transition(&mut appointment, to, &caller.principal)?;
+
+    APPOINTMENT_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(appointment_id, appointment.clone())
+    });
+
+    Ok(appointment)
+}
+
+fn validate_details(
+    phone_no: &str,
+    reason: &str,
+    symtoms: &str,
+    appointment_type: &str,
+) -> Result<(), Error> {
+    if phone_no.len() > MAX_PHONE_NO_LEN
+        || reason.len() > MAX_APPOINTMENT_TEXT_LEN
+        || symtoms.len() > MAX_APPOINTMENT_TEXT_LEN
+        || appointment_type.len() > MAX_APPOINTMENT_TYPE_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Phone number, reason, symptoms and type are limited to {}, {}, {} and {} bytes",
+                MAX_PHONE_NO_LEN,
+                MAX_APPOINTMENT_TEXT_LEN,
+                MAX_APPOINTMENT_TEXT_LEN,
+                MAX_APPOINTMENT_TYPE_LEN
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn require_doctor_or_front_desk(
+    caller: &CallerContext,
+    appointment: &Appointment,
+) -> Result<(), Error> {
+    if caller.has_role(Role::Receptionist) {
+        return Ok(());
+    }
+    caller.require_doctor(appointment.doctor_id)
 }
\ No newline at end of file
@@ -0,0 +1,399 @@
+//! Tamper-evident audit log of clinical data access
+//!
+//! Every read and write of medical records, reports, prescriptions, data records
+//! and blob contents, and every change of consent, appends an entry, whether it
+//! succeeded or was refused; reads by callers without a role are refused
+//! without an entry. Entries are hash-chained: each hash covers the
+//! entry and the hash of its predecessor, so editing, dropping or reordering
+//! entries breaks `verify_audit_log`.
+
+use sha2::{Digest, Sha256};
+
+use crate::auth::caller_context;
+use crate::error::Error;
+use crate::models::{
+    AuditAction, AuditEntity, AuditEntry, AuditFailure, AuditFilter, AuditOutcome,
+    AuditVerification, Page, PageRequest, SortOrder,
+};
+use crate::pagination::{matches, page_limit};
+use crate::storage::AUDIT_LOG;
+use crate::utils::to_hex;
+
+/// `prev_hash` of the first entry.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[ic_cdk::query]
+pub fn list_audit_entries(
+    page: PageRequest,
+    filter: AuditFilter,
+) -> Result<Page<AuditEntry>, Error> {
+    caller_context().require_admin()?;
+
+    let limit = page_limit(page.limit);
+    let keep = |entry: &AuditEntry| {
+        matches(&filter.caller, &entry.caller)
+            && matches(&filter.entity, &entry.entity)
+            && matches(&filter.entity_id, &entry.entity_id)
+            && filter.from.map_or(true, |from| entry.at >= from)
+            && filter.to.map_or(true, |to| entry.at < to)
+    };
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let len = log.len();
+        let seqs: Box<dyn Iterator<Item = u64>> = match page.order {
+            SortOrder::Ascending => Box::new(page.cursor.unwrap_or(0)..len),
+            SortOrder::Descending => {
+                let last = page
+                    .cursor
+                    .map_or(len, |cursor| len.min(cursor.saturating_add(1)));
+                Box::new((0..last).rev())
+            }
+        };
+
+        let mut items = Vec::with_capacity(limit);
+        let mut next_cursor = None;
+        for seq in seqs {
+            let entry = match log.get(seq) {
+                Some(entry) if keep(&entry) => entry,
+                _ => continue,
+            };
+            if items.len() == limit {
+                next_cursor = Some(seq);
+                break;
+            }
+            items.push(entry);
+        }
+
+        Ok(Page { items, next_cursor })
+    })
+}
+
+/// Checks up to `limit` entries starting at `from_seq`; continue from
+/// `next_seq` until it is `None` to verify the whole chain.
+#[ic_cdk::query]
+pub fn verify_audit_log(from_seq: u64, limit: u64) -> Result<AuditVerification, Error> {
+    caller_context().require_admin()?;
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        Ok(verify_chain(log.len(), |seq| log.get(seq), from_seq, limit))
+    })
+}
+
+/// Runs `body` and records its outcome against `entity_id`.
+pub fn audited<T>(
+    endpoint: &str,
+    entity: AuditEntity,
+    entity_id: u64,
+    action: AuditAction,
+    body: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = body();
+    record(endpoint, entity, entity_id, action, outcome_of(&result));
+    result
+}
+
+/// Like `audited` for reads. Clinical reads are update calls so that they land
+/// in the audit log; callers without a role are refused before an entry is
+/// appended, so anonymous calls cannot grow the log.
+pub fn audited_read<T>(
+    endpoint: &str,
+    entity: AuditEntity,
+    entity_id: u64,
+    body: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    caller_context().require_registered()?;
+    audited(endpoint, entity, entity_id, AuditAction::Read, body)
+}
+
+/// Like `audited` for creations, where the id is only known from the result.
+pub fn audited_create<T>(
+    endpoint: &str,
+    entity: AuditEntity,
+    id_of: impl Fn(&T) -> u64,
+    body: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = body();
+    let entity_id = result.as_ref().map(&id_of).unwrap_or(0);
+    record(
+        endpoint,
+        entity,
+        entity_id,
+        AuditAction::Create,
+        outcome_of(&result),
+    );
+    result
+}
+
+/// Records a successful read of each of `entity_ids`, e.g. the items of a page.
+pub fn record_reads(endpoint: &str, entity: AuditEntity, entity_ids: impl Iterator<Item = u64>) {
+    for entity_id in entity_ids {
+        record(
+            endpoint,
+            entity,
+            entity_id,
+            AuditAction::Read,
+            AuditOutcome::Success,
+        );
+    }
+}
+
+/// Appends an entry and returns it.
+pub fn record(
+    endpoint: &str,
+    entity: AuditEntity,
+    entity_id: u64,
+    action: AuditAction,
+    outcome: AuditOutcome,
+) -> AuditEntry {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow_mut();
+        let seq = log.len();
+        let prev_hash = head_hash(seq, |seq| log.get(seq));
+
+        let mut entry = AuditEntry {
+            seq,
+            at: ic_cdk::api::time(),
+            caller: ic_cdk::caller().to_text(),
+            endpoint: endpoint.to_string(),
+            entity,
+            entity_id,
+            action,
+            outcome,
+            prev_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry_hash(&entry);
+
+        log.append(&entry).expect("cannot append to the audit log");
+        entry
+    })
+}
+
+fn outcome_of<T>(result: &Result<T, Error>) -> AuditOutcome {
+    match result {
+        Ok(_) => AuditOutcome::Success,
+        Err(Error::Unauthorized { msg }) => AuditOutcome::Denied(msg.clone()),
+        Err(Error::NotFound { msg })
+        | Err(Error::InvalidInput { msg })
+        | Err(Error::AppointmentConflict { msg })
+        | Err(Error::InvalidTransition { msg })
+        | Err(Error::AlreadyExists { msg })
+        | Err(Error::InteractionConflict { msg }) => AuditOutcome::Failed(msg.clone()),
+    }
+}
+
+// Checks entries `from_seq..from_seq + limit` of a log holding `len` entries
+fn verify_chain(
+    len: u64,
+    get: impl Fn(u64) -> Option<AuditEntry>,
+    from_seq: u64,
+    limit: u64,
+) -> AuditVerification {
+    let head_hash = head_hash(len, &get);
+    let end = len.min(from_seq.saturating_add(limit));
+
+    let mut verification = AuditVerification {
+        checked: 0,
+        next_seq: if end < len { Some(end) } else { None },
+        head_hash,
+        failure: None,
+    };
+
+    let mut prev_hash = match from_seq {
+        0 => GENESIS_HASH.to_string(),
+        seq => match get(seq - 1) {
+            Some(entry) => entry.hash,
+            None => {
+                verification.failure = Some(AuditFailure {
+                    seq: seq - 1,
+                    reason: "Entry is missing".to_string(),
+                });
+                return verification;
+            }
+        },
+    };
+
+    for seq in from_seq..end {
+        let reason = match get(seq) {
+            None => Some("Entry is missing".to_string()),
+            Some(entry) if entry.seq != seq => Some(format!(
+                "Entry records sequence number {}, the log has a gap",
+                entry.seq
+            )),
+            Some(entry) if entry.prev_hash != prev_hash => {
+                Some("Entry does not chain to its predecessor".to_string())
+            }
+            Some(entry) if entry_hash(&entry) != entry.hash => {
+                Some("Entry hash does not match its contents".to_string())
+            }
+            Some(entry) => {
+                prev_hash = entry.hash;
+                None
+            }
+        };
+
+        if let Some(reason) = reason {
+            verification.failure = Some(AuditFailure { seq, reason });
+            verification.next_seq = None;
+            break;
+        }
+        verification.checked += 1;
+    }
+
+    verification
+}
+
+fn head_hash(len: u64, get: impl Fn(u64) -> Option<AuditEntry>) -> String {
+    match len.checked_sub(1).and_then(get) {
+        Some(entry) => entry.hash,
+        None => GENESIS_HASH.to_string(),
+    }
+}
+
+// Covers every field but `hash` itself
+fn entry_hash(entry: &AuditEntry) -> String {
+    let fields = candid::encode_args((
+        entry.seq,
+        entry.at,
+        &entry.caller,
+        &entry.endpoint,
+        entry.entity,
+        entry.entity_id,
+        entry.action,
+        &entry.outcome,
+        &entry.prev_hash,
+    ))
+    .expect("cannot encode audit entry");
+
+    to_hex(&Sha256::digest(&fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A well-formed log of `len` reads of medical record 1
+    fn chain(len: u64) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = Vec::new();
+        for seq in 0..len {
+            let mut entry = AuditEntry {
+                seq,
+                at: 100 + seq,
+                caller: "aaaaa-aa".to_string(),
+                endpoint: "get_medical_record".to_string(),
+                entity: AuditEntity::MedicalRecord,
+                entity_id: 1,
+                action: AuditAction::Read,
+                outcome: AuditOutcome::Success,
+                prev_hash: head_hash(seq, |seq| entries.get(seq as usize).cloned()),
+                hash: String::new(),
+            };
+            entry.hash = entry_hash(&entry);
+            entries.push(entry);
+        }
+        entries
+    }
+
+    fn verify(entries: &[AuditEntry], from_seq: u64, limit: u64) -> AuditVerification {
+        verify_chain(
+            entries.len() as u64,
+            |seq| entries.get(seq as usize).cloned(),
+            from_seq,
+            limit,
+        )
+    }
+
+    fn failure(verification: &AuditVerification) -> (u64, &str) {
+        let failure = verification.failure.as_ref().expect("expected a failure");
+        (failure.seq, failure.reason.as_str())
+    }
+
+    #[test]
+    fn an_empty_log_verifies() {
+        let verification = verify(&[], 0, 10);
+        assert_eq!(verification.checked, 0);
+        assert_eq!(verification.next_seq, None);
+        assert_eq!(verification.head_hash, GENESIS_HASH);
+        assert!(verification.failure.is_none());
+    }
+
+    #[test]
+    fn an_intact_chain_verifies() {
+        let entries = chain(3);
+        let verification = verify(&entries, 0, 10);
+
+        assert_eq!(verification.checked, 3);
+        assert_eq!(verification.next_seq, None);
+        assert_eq!(verification.head_hash, entries[2].hash);
+        assert!(verification.failure.is_none());
+    }
+
+    #[test]
+    fn verification_continues_from_next_seq() {
+        let entries = chain(5);
+
+        let first = verify(&entries, 0, 2);
+        assert_eq!(first.checked, 2);
+        assert_eq!(first.next_seq, Some(2));
+
+        let rest = verify(&entries, 2, 10);
+        assert_eq!(rest.checked, 3);
+        assert_eq!(rest.next_seq, None);
+        assert!(rest.failure.is_none());
+    }
+
+    #[test]
+    fn an_edited_entry_is_detected() {
+        let mut entries = chain(3);
+        entries[1].entity_id = 2;
+
+        let verification = verify(&entries, 0, 10);
+        assert_eq!(
+            failure(&verification),
+            (1, "Entry hash does not match its contents")
+        );
+        assert_eq!(verification.checked, 1);
+        assert_eq!(verification.next_seq, None);
+    }
+
+    #[test]
+    fn a_rehashed_edit_breaks_the_next_link() {
+        let mut entries = chain(3);
+        entries[1].caller = "2vxsx-fae".to_string();
+        entries[1].hash = entry_hash(&entries[1]);
+
+        let verification = verify(&entries, 0, 10);
+        assert_eq!(
+            failure(&verification),
+            (2, "Entry does not chain to its predecessor")
+        );
+    }
+
+    #[test]
+    fn a_dropped_entry_is_detected() {
+        let mut entries = chain(3);
+        entries.remove(1);
+
+        let verification = verify(&entries, 0, 10);
+        assert_eq!(
+            failure(&verification),
+            (1, "Entry records sequence number 2, the log has a gap")
+        );
+    }
+
+    #[test]
+    fn reordered_entries_are_detected() {
+        let mut entries = chain(3);
+        entries.swap(1, 2);
+        entries[1].seq = 1;
+        entries[2].seq = 2;
+
+        let verification = verify(&entries, 0, 10);
+        assert_eq!(
+            failure(&verification),
+            (1, "Entry does not chain to its predecessor")
+        );
+    }
+}
\ No newline at end of file
@@ -0,0 +1,308 @@
+//! Caller-based authorization shared by every canister endpoint
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::consent::has_active_grant;
+use crate::doctor::get_doctor_by_id;
+use crate::error::Error;
+use crate::identity::{find_identity_by_principal, get_identity_by_id};
+use crate::index::{appointment_ids_by_doctor, doctor_id_by_principal, patient_id_by_identity};
+use crate::models::{Appointment, ConsentScope, Doctor, Role};
+use crate::patient::get_patient_by_id;
+use crate::storage::APPOINTMENT_STORAGE;
+
+/// Who is calling, resolved against the identity, patient and doctor tables.
+pub struct CallerContext {
+    pub principal: String,
+    pub roles: Vec<Role>,
+    pub is_admin: bool,
+    pub patient_id: Option<u64>,
+    pub doctor_id: Option<u64>,
+}
+
+impl CallerContext {
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+
+    /// True when the caller may access the patient's clinical data within
+    /// `scope`: the patient themselves, an admin or the holder of an active grant.
+    pub fn can_access_patient(&self, patient_id: u64, scope: ConsentScope) -> bool {
+        self.is_admin || self.patient_id == Some(patient_id) || self.has_consent(patient_id, scope)
+    }
+
+    /// True when the caller may see the patient's profile; front-desk and care
+    /// staff need this for booking and treatment but see no clinical data
+    /// without consent.
+    pub fn can_view_patient_profile(&self, patient_id: u64) -> bool {
+        self.has_role(Role::Receptionist)
+            || self.has_role(Role::Nurse)
+            || self.treats_patient(patient_id)
+            || self.can_access_patient(patient_id, ConsentScope::ReadRecords)
+    }
+
+    /// `can_access_patient` for the records of one listing, checking each
+    /// patient's grants once however many of their records it visits.
+    pub fn patient_access(&self, scope: ConsentScope) -> impl Fn(u64) -> bool + '_ {
+        let checked = RefCell::new(HashMap::new());
+        move |patient_id| {
+            *checked
+                .borrow_mut()
+                .entry(patient_id)
+                .or_insert_with(|| self.can_access_patient(patient_id, scope))
+        }
+    }
+
+    /// `can_view_patient_profile` for one listing; a doctor's patients are
+    /// loaded once rather than for every profile.
+    pub fn profile_access(&self) -> impl Fn(u64) -> bool + '_ {
+        let staff = self.has_role(Role::Receptionist) || self.has_role(Role::Nurse);
+        let treated = match self.doctor_id {
+            Some(doctor_id) if !staff => doctor_patient_ids(doctor_id),
+            _ => HashSet::new(),
+        };
+        let access = self.patient_access(ConsentScope::ReadRecords);
+        move |patient_id| staff || treated.contains(&patient_id) || access(patient_id)
+    }
+
+    /// True when the patient granted `scope` to the caller as a doctor or to
+    /// one of the caller's roles.
+    pub fn has_consent(&self, patient_id: u64, scope: ConsentScope) -> bool {
+        has_active_grant(patient_id, self.doctor_id, &self.roles, scope)
+    }
+
+    /// True when the caller is a doctor with at least one appointment with the patient.
+    pub fn treats_patient(&self, patient_id: u64) -> bool {
+        match self.doctor_id {
+            Some(doctor_id) => doctor_has_patient(doctor_id, patient_id),
+            None => false,
+        }
+    }
+
+    /// True when the caller is the given patient or doctor (ids are globally unique).
+    pub fn is_party(&self, id: u64) -> bool {
+        self.patient_id == Some(id) || self.doctor_id == Some(id)
+    }
+
+    /// True when the caller is the patient or doctor of the appointment,
+    /// front-desk staff or an admin.
+    pub fn is_appointment_party(&self, appointment: &Appointment) -> bool {
+        self.is_admin
+            || self.has_role(Role::Receptionist)
+            || self.patient_id == Some(appointment.patient_id)
+            || self.doctor_id == Some(appointment.doctor_id)
+    }
+
+    pub fn require_role(&self, role: Role) -> Result<(), Error> {
+        self.require_any_role(&[role])
+    }
+
+    pub fn require_any_role(&self, roles: &[Role]) -> Result<(), Error> {
+        if roles.iter().any(|role| self.has_role(*role)) {
+            Ok(())
+        } else {
+            Err(unauthorized(&format!(
+                "Caller lacks one of the required roles: {:?}",
+                roles
+            )))
+        }
+    }
+
+    pub fn require_registered(&self) -> Result<(), Error> {
+        if self.roles.is_empty() {
+            Err(unauthorized("Only registered users may call this endpoint"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn require_admin(&self) -> Result<(), Error> {
+        self.require_role(Role::Admin)
+    }
+
+    /// The caller must be the given patient (or an admin).
+    pub fn require_patient(&self, patient_id: u64) -> Result<(), Error> {
+        if self.is_admin || self.patient_id == Some(patient_id) {
+            Ok(())
+        } else {
+            Err(unauthorized(&format!(
+                "Caller is not patient with id={}",
+                patient_id
+            )))
+        }
+    }
+
+    /// The caller must be the given patient, front-desk staff or an admin.
+    pub fn require_booking_for(&self, patient_id: u64) -> Result<(), Error> {
+        if self.has_role(Role::Receptionist) {
+            Ok(())
+        } else {
+            self.require_patient(patient_id)
+        }
+    }
+
+    /// The caller must be the given doctor (or an admin).
+    pub fn require_doctor(&self, doctor_id: u64) -> Result<(), Error> {
+        if self.is_admin || self.doctor_id == Some(doctor_id) {
+            Ok(())
+        } else {
+            Err(unauthorized(&format!(
+                "Caller is not doctor with id={}",
+                doctor_id
+            )))
+        }
+    }
+
+    /// The caller must be allowed to access the patient's clinical data within `scope`.
+    pub fn require_patient_access(
+        &self,
+        patient_id: u64,
+        scope: ConsentScope,
+    ) -> Result<(), Error> {
+        if self.can_access_patient(patient_id, scope) {
+            Ok(())
+        } else {
+            Err(unauthorized(&format!(
+                "Caller lacks {:?} consent of patient with id={}",
+                scope, patient_id
+            )))
+        }
+    }
+
+    /// The caller must be a doctor the patient allowed to write reports (or an admin).
+    pub fn require_report_writer(&self, patient_id: u64) -> Result<(), Error> {
+        if self.is_admin
+            || (self.doctor_id.is_some()
+                && self.has_consent(patient_id, ConsentScope::WriteReports))
+        {
+            Ok(())
+        } else {
+            Err(unauthorized(&format!(
+                "Caller may not write reports of patient with id={}",
+                patient_id
+            )))
+        }
+    }
+
+    /// The caller must be a doctor with an appointment with the patient (or an admin).
+    pub fn require_treating_doctor(&self, patient_id: u64) -> Result<(), Error> {
+        if self.is_admin || self.treats_patient(patient_id) {
+            Ok(())
+        } else {
+            Err(unauthorized(&format!(
+                "Caller is not a doctor of patient with id={}",
+                patient_id
+            )))
+        }
+    }
+
+    /// The caller must be a doctor of the patient allowed to write their
+    /// records (or an admin).
+    pub fn require_record_writer(&self, patient_id: u64) -> Result<(), Error> {
+        self.require_treating_doctor(patient_id)?;
+        self.require_patient_access(patient_id, ConsentScope::WriteRecords)
+    }
+
+    /// Like `require_record_writer`, but the patient may write too. Patients
+    /// keep parts of their own chart, such as allergies and immunizations
+    /// given elsewhere.
+    pub fn require_chart_writer(&self, patient_id: u64) -> Result<(), Error> {
+        if self.patient_id == Some(patient_id) {
+            return Ok(());
+        }
+        self.require_record_writer(patient_id)
+    }
+
+    pub fn require_appointment_party(&self, appointment: &Appointment) -> Result<(), Error> {
+        if self.is_appointment_party(appointment) {
+            Ok(())
+        } else {
+            Err(unauthorized(&format!(
+                "Caller is not a party to appointment with id={}",
+                appointment.id
+            )))
+        }
+    }
+}
+
+/// Resolves the principal of the current call into a `CallerContext`.
+pub fn caller_context() -> CallerContext {
+    let caller = ic_cdk::caller();
+    let principal = caller.to_text();
+
+    let identity = if caller == Principal::anonymous() {
+        None
+    } else {
+        find_identity_by_principal(&principal)
+    };
+
+    let (identity_id, roles) = match identity {
+        Some(identity) => (Some(identity.id), identity.roles),
+        None => (None, Vec::new()),
+    };
+
+    let patient_id = identity_id.and_then(find_patient_by_identity_id);
+    let doctor_id = if roles.contains(&Role::Doctor) {
+        find_doctor_by_principal(&principal).map(|doctor| doctor.id)
+    } else {
+        None
+    };
+
+    CallerContext {
+        is_admin: roles.contains(&Role::Admin),
+        principal,
+        roles,
+        patient_id,
+        doctor_id,
+    }
+}
+
+pub fn find_patient_by_identity_id(identity_id: u64) -> Option<u64> {
+    patient_id_by_identity(identity_id)
+}
+
+pub fn find_doctor_by_principal(principal: &str) -> Option<Doctor> {
+    doctor_id_by_principal(principal)
+        .and_then(|doctor_id| get_doctor_by_id(&doctor_id))
+        .filter(|doctor| doctor.principal_str == principal)
+}
+
+/// Principal of a patient or doctor id (ids are globally unique).
+pub fn party_principal(party_id: u64) -> Option<String> {
+    if let Some(doctor) = get_doctor_by_id(&party_id) {
+        return Some(doctor.principal_str);
+    }
+    get_patient_by_id(&party_id)
+        .and_then(|patient| get_identity_by_id(&patient.identity_id))
+        .map(|identity| identity.principal)
+}
+
+pub fn doctor_has_patient(doctor_id: u64, patient_id: u64) -> bool {
+    APPOINTMENT_STORAGE.with(|service| {
+        let storage = service.borrow();
+        appointment_ids_by_doctor(doctor_id)
+            .iter()
+            .filter_map(|appointment_id| storage.get(appointment_id))
+            .any(|appointment| appointment.patient_id == patient_id)
+    })
+}
+
+/// Patients the doctor has at least one appointment with.
+pub fn doctor_patient_ids(doctor_id: u64) -> HashSet<u64> {
+    APPOINTMENT_STORAGE.with(|service| {
+        let storage = service.borrow();
+        appointment_ids_by_doctor(doctor_id)
+            .iter()
+            .filter_map(|appointment_id| storage.get(appointment_id))
+            .map(|appointment| appointment.patient_id)
+            .collect()
+    })
+}
+
+pub fn unauthorized(msg: &str) -> Error {
+    Error::Unauthorized {
+        msg: msg.to_string(),
+    }
+}
\ No newline at end of file
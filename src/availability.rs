@@ -1,30 +1,31 @@
 //! Availability management functionality
 
+use crate::auth::caller_context;
 use crate::doctor::get_doctor_by_id;
 use crate::error::Error;
-use crate::models::Availability;
+use crate::index::{availability_ids_by_doctor, index_availability, unindex_availability};
+use crate::models::{Availability, AvailabilityFilter, Page, PageRequest};
+use crate::pagination::{matches, paginate, paginate_ids};
+use crate::schedule::check_window_overlap;
 use crate::storage::AVAILABILITY_STORAGE;
 use crate::utils::generate_id;
 
+/// Longest one-off window. Overlap checks expand the doctor's rules over the
+/// whole window, so the span has to stay small.
+pub const MAX_WINDOW_SPAN: u64 = 24 * 60 * 60 * 1_000_000_000;
+
 #[ic_cdk::update]
 pub fn add_availability(
     doctor_id: u64,
-    day_of_week: u8,
-    start_time: String,
-    end_time: String,
+    start_time: u64,
+    end_time: u64,
     is_available: bool,
 ) -> Result<Availability, Error> {
     // Validate input data
-    if day_of_week > 6 {
-        return Err(Error::InvalidInput {
-            msg: "Invalid day of the week".to_string(),
-        });
-    }
-    if start_time.is_empty() || end_time.is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Start time or end time cannot be empty".to_string(),
-        });
-    }
+    validate_window(start_time, end_time)?;
+
+    // Doctors manage their own availability
+    caller_context().require_doctor(doctor_id)?;
 
     // Check if the doctor exists
     if get_doctor_by_id(&doctor_id).is_none() {
@@ -33,18 +34,20 @@ pub fn add_availability(
         });
     }
 
+    check_window_overlap(doctor_id, start_time, end_time, None)?;
+
     let id = generate_id();
 
     let availability = Availability {
         id,
         doctor_id,
-        day_of_week,
         start_time,
         end_time,
         is_available,
     };
 
     AVAILABILITY_STORAGE.with(|service| service.borrow_mut().insert(id, availability.clone()));
+    index_availability(&availability);
     Ok(availability)
 }
 
@@ -62,48 +65,54 @@ pub fn get_availability(availability_id: u64) -> Result<Availability, Error> {
 pub fn update_availability(
     availability_id: u64,
     doctor_id: u64,
-    day_of_week: u8,
-    start_time: String,
-    end_time: String,
+    start_time: u64,
+    end_time: u64,
     is_available: bool,
 ) -> Result<Availability, Error> {
     // Validate input data
-    if day_of_week > 6 {
-        return Err(Error::InvalidInput {
-            msg: "Invalid day of the week".to_string(),
-        });
-    }
-    if start_time.is_empty() || end_time.is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Start time or end time cannot be empty".to_string(),
-        });
-    }
+    validate_window(start_time, end_time)?;
+
+    let current = get_availability_by_id(&availability_id).ok_or(Error::NotFound {
+        msg: format!("Availability with id={} not found", availability_id),
+    })?;
+
+    // Doctors manage their own availability
+    let caller = caller_context();
+    caller.require_doctor(doctor_id)?;
+    caller.require_doctor(current.doctor_id)?;
+
+    check_window_overlap(doctor_id, start_time, end_time, Some(availability_id))?;
 
     let updated_availability = Availability {
         id: availability_id,
         doctor_id,
-        day_of_week,
         start_time,
         end_time,
         is_available,
     };
 
-    match AVAILABILITY_STORAGE.with(|service| {
+    AVAILABILITY_STORAGE.with(|service| {
         service
             .borrow_mut()
             .insert(availability_id, updated_availability.clone())
-    }) {
-        Some(_) => Ok(updated_availability),
-        None => Err(Error::NotFound {
-            msg: format!("Availability with id={} not found", availability_id),
-        }),
-    }
+    });
+    unindex_availability(&current);
+    index_availability(&updated_availability);
+
+    Ok(updated_availability)
 }
 
 #[ic_cdk::update]
 pub fn delete_availability(availability_id: u64) -> Result<(), Error> {
+    if let Some(availability) = get_availability_by_id(&availability_id) {
+        caller_context().require_doctor(availability.doctor_id)?;
+    }
+
     match AVAILABILITY_STORAGE.with(|service| service.borrow_mut().remove(&availability_id)) {
-        Some(_) => Ok(()),
+        Some(availability) => {
+            unindex_availability(&availability);
+            Ok(())
+        }
         None => Err(Error::NotFound {
             msg: format!("Availability with id={} not found", availability_id),
         }),
@@ -111,38 +120,37 @@ pub fn delete_availability(availability_id: u64) -> Result<(), Error> {
 }
 
 #[ic_cdk::query]
-pub fn list_availabilities() -> Vec<Availability> {
-    AVAILABILITY_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, availability)| availability.clone())
-            .collect()
-    })
+pub fn list_availabilities(page: PageRequest, filter: AvailabilityFilter) -> Page<Availability> {
+    let keep = |availability: &Availability| {
+        matches(&filter.doctor_id, &availability.doctor_id)
+            && matches(&filter.is_available, &availability.is_available)
+    };
+    match filter.doctor_id {
+        Some(doctor_id) => paginate_ids(
+            availability_ids_by_doctor(doctor_id),
+            &page,
+            get_availability_by_id,
+            keep,
+        ),
+        None => paginate(&AVAILABILITY_STORAGE, &page, keep),
+    }
 }
 
 #[ic_cdk::query]
 pub fn filter_available_slots_by_doctor_id(doctor_id: u64) -> Vec<Availability> {
-    AVAILABILITY_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .filter(|(_, availability)| {
-                availability.doctor_id == doctor_id && availability.is_available
-            })
-            .map(|(_, availability)| availability.clone())
-            .collect()
-    })
+    filter_availability_by_doctor_id(doctor_id)
+        .into_iter()
+        .filter(|availability| availability.is_available)
+        .collect()
 }
 
 #[ic_cdk::query]
 pub fn filter_availability_by_doctor_id(doctor_id: u64) -> Vec<Availability> {
     AVAILABILITY_STORAGE.with(|service| {
-        service
-            .borrow()
+        let storage = service.borrow();
+        availability_ids_by_doctor(doctor_id)
             .iter()
-            .filter(|(_, availability)| availability.doctor_id == doctor_id)
-            .map(|(_, availability)| availability.clone())
+            .filter_map(|availability_id| storage.get(availability_id))
             .collect()
     })
 }
@@ -151,18 +159,23 @@ pub fn get_availability_by_id(availability_id: &u64) -> Option<Availability> {
     AVAILABILITY_STORAGE.with(|service| service.borrow().get(availability_id))
 }
 
-pub fn update_availability_status(doctor_id: u64, slot: &str, is_available: bool) {
-    AVAILABILITY_STORAGE.with(|service| {
-        let mut storage = service.borrow_mut();
-        if let Some((id, mut availability)) = storage
-            .iter()
-            .find(|(_, availability)| {
-                availability.doctor_id == doctor_id && availability.start_time == slot
-            })
-            .map(|(id, availability)| (id, availability.clone()))
-        {
-            availability.is_available = is_available;
-            storage.insert(id, availability);
-        }
-    });
+/// Finds the doctor's open window containing the instant `at`.
+pub fn find_availability_at(doctor_id: u64, at: u64) -> Option<Availability> {
+    filter_available_slots_by_doctor_id(doctor_id)
+        .into_iter()
+        .find(|availability| availability.start_time <= at && at < availability.end_time)
+}
+
+fn validate_window(start_time: u64, end_time: u64) -> Result<(), Error> {
+    if end_time <= start_time {
+        return Err(Error::InvalidInput {
+            msg: "End time must be after start time".to_string(),
+        });
+    }
+    if end_time - start_time > MAX_WINDOW_SPAN {
+        return Err(Error::InvalidInput {
+            msg: "A window may span at most a day".to_string(),
+        });
+    }
+    Ok(())
 }
\ No newline at end of file
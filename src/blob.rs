@@ -0,0 +1,433 @@
+//! Chunked blob store for attachments and other binary payloads
+//!
+//! Uploads are `begin_blob_upload`, any number of `append_blob_chunk` calls and
+//! `finalize_blob_upload`, which checks the SHA-256 of the content. Every chunk
+//! but the last is exactly `BLOB_CHUNK_SIZE` bytes so ranged reads can locate
+//! chunks directly. Messages, reports and data records reference blobs by id.
+//! Uploads never finalized are removed by `purge_stale_uploads`. Blobs not
+//! attached to a record yet count against a quota of their owner.
+
+use sha2::{Digest, Sha256};
+
+use crate::audit::audited_read;
+use crate::auth::{caller_context, unauthorized};
+use crate::data::{get_data_by_id, require_data_access};
+use crate::error::Error;
+use crate::index::{blob_ids_by_owner, index_blob, unindex_blob};
+use crate::message::{get_message_by_id, require_message_party};
+use crate::models::{
+    AuditEntity, Blob, BlobChunk, BlobChunkKey, BlobLink, BlobStatus, ConsentScope, Page,
+    BLOB_CHUNK_SIZE,
+};
+use crate::pagination::page_limit;
+use crate::report::get_report_by_id;
+use crate::storage::{BLOB_CHUNK_STORAGE, BLOB_STORAGE};
+use crate::utils::{generate_id, to_hex};
+
+/// Largest blob accepted by `begin_blob_upload`.
+pub const MAX_BLOB_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Largest range returned by a single `read_blob` call, below the reply limit.
+pub const MAX_BLOB_READ: u64 = 1024 * 1024;
+
+/// Uploads not finalized within a day are dropped by `purge_stale_uploads`.
+pub const UPLOAD_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Blobs an owner may hold unattached at a time, uploading or finalized.
+pub const MAX_PENDING_BLOBS: usize = 16;
+
+/// Bytes the unattached blobs of an owner may declare together.
+pub const MAX_PENDING_BYTES: u64 = 2 * MAX_BLOB_SIZE;
+
+#[ic_cdk::update]
+pub fn begin_blob_upload(content_type: String, total_size: u64) -> Result<Blob, Error> {
+    let caller = caller_context();
+    if caller.roles.is_empty() {
+        return Err(unauthorized("Only registered users may upload blobs"));
+    }
+
+    if content_type.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Content type cannot be empty".to_string(),
+        });
+    }
+    if total_size == 0 || total_size > MAX_BLOB_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("Blob size must be between 1 and {} bytes", MAX_BLOB_SIZE),
+        });
+    }
+    require_upload_quota(&caller.principal, total_size)?;
+
+    let id = generate_id();
+
+    let blob = Blob {
+        id,
+        owner: caller.principal,
+        content_type,
+        declared_size: total_size,
+        size: 0,
+        chunk_count: 0,
+        sha256: None,
+        status: BlobStatus::Uploading,
+        attached_to: None,
+        created_at: ic_cdk::api::time(),
+    };
+
+    BLOB_STORAGE.with(|service| service.borrow_mut().insert(id, blob.clone()));
+    index_blob(&blob);
+    Ok(blob)
+}
+
+#[ic_cdk::update]
+pub fn append_blob_chunk(blob_id: u64, chunk: Vec<u8>) -> Result<Blob, Error> {
+    let mut blob = get_owned_blob(blob_id)?;
+
+    if blob.status != BlobStatus::Uploading {
+        return Err(Error::InvalidInput {
+            msg: format!("Blob with id={} is already finalized", blob_id),
+        });
+    }
+
+    let remaining = blob.declared_size - blob.size;
+    let chunk_len = chunk.len() as u64;
+    if chunk_len == 0 || chunk_len > remaining {
+        return Err(Error::InvalidInput {
+            msg: format!("Chunk must hold between 1 and {} bytes", remaining),
+        });
+    }
+    if chunk_len != BLOB_CHUNK_SIZE as u64 && chunk_len != remaining {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Only the last chunk may be shorter than {} bytes",
+                BLOB_CHUNK_SIZE
+            ),
+        });
+    }
+
+    let key = BlobChunkKey {
+        blob_id,
+        index: blob.chunk_count,
+    };
+    BLOB_CHUNK_STORAGE.with(|service| service.borrow_mut().insert(key, BlobChunk(chunk)));
+
+    blob.size += chunk_len;
+    blob.chunk_count += 1;
+    BLOB_STORAGE.with(|service| service.borrow_mut().insert(blob_id, blob.clone()));
+    Ok(blob)
+}
+
+#[ic_cdk::update]
+pub fn finalize_blob_upload(blob_id: u64, sha256: String) -> Result<Blob, Error> {
+    let mut blob = get_owned_blob(blob_id)?;
+
+    if blob.status != BlobStatus::Uploading {
+        return Err(Error::InvalidInput {
+            msg: format!("Blob with id={} is already finalized", blob_id),
+        });
+    }
+    if blob.size != blob.declared_size {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Blob with id={} holds {} of {} bytes",
+                blob_id, blob.size, blob.declared_size
+            ),
+        });
+    }
+
+    let digest = hash_chunks(blob_id, blob.chunk_count);
+    if !digest.eq_ignore_ascii_case(&sha256) {
+        return Err(Error::InvalidInput {
+            msg: format!("Content hash mismatch, computed {}", digest),
+        });
+    }
+
+    blob.sha256 = Some(digest);
+    blob.status = BlobStatus::Finalized;
+    BLOB_STORAGE.with(|service| service.borrow_mut().insert(blob_id, blob.clone()));
+    Ok(blob)
+}
+
+#[ic_cdk::query]
+pub fn get_blob(blob_id: u64) -> Result<Blob, Error> {
+    let blob = get_blob_by_id(&blob_id).ok_or(Error::NotFound {
+        msg: format!("Blob with id={} not found", blob_id),
+    })?;
+
+    require_blob_read(&blob)?;
+    Ok(blob)
+}
+
+#[ic_cdk::update]
+pub fn read_blob(blob_id: u64, offset: u64, length: u64) -> Result<Vec<u8>, Error> {
+    audited_read("read_blob", AuditEntity::Blob, blob_id, || {
+        let blob = get_blob(blob_id)?;
+
+        if blob.status != BlobStatus::Finalized {
+            return Err(Error::InvalidInput {
+                msg: format!("Blob with id={} is still uploading", blob_id),
+            });
+        }
+        if length == 0 || length > MAX_BLOB_READ {
+            return Err(Error::InvalidInput {
+                msg: format!("Read length must be between 1 and {} bytes", MAX_BLOB_READ),
+            });
+        }
+        if offset >= blob.size {
+            return Err(Error::InvalidInput {
+                msg: format!("Offset {} is beyond the blob size {}", offset, blob.size),
+            });
+        }
+
+        Ok(read_range(blob_id, offset, length.min(blob.size - offset)))
+    })
+}
+
+#[ic_cdk::update]
+pub fn delete_blob(blob_id: u64) -> Result<(), Error> {
+    let blob = get_owned_blob(blob_id)?;
+
+    if let Some(link) = blob.attached_to {
+        return Err(Error::InvalidInput {
+            msg: format!("Blob with id={} is attached to {:?}", blob_id, link),
+        });
+    }
+
+    remove_blob(blob_id);
+    Ok(())
+}
+
+/// Removes abandoned uploads, scanning one page of blobs per call. Returns the
+/// ids of the removed blobs; `next_cursor` continues the sweep.
+#[ic_cdk::update]
+pub fn purge_stale_uploads(cursor: Option<u64>, limit: u32) -> Result<Page<u64>, Error> {
+    caller_context().require_admin()?;
+
+    let limit = page_limit(limit);
+    let cutoff = ic_cdk::api::time().saturating_sub(UPLOAD_TTL);
+    let mut scanned: Vec<Blob> = BLOB_STORAGE.with(|service| {
+        service
+            .borrow()
+            .range(cursor.unwrap_or(0)..)
+            .take(limit + 1)
+            .map(|(_, blob)| blob)
+            .collect()
+    });
+
+    let next_cursor = if scanned.len() > limit {
+        scanned.pop().map(|blob| blob.id)
+    } else {
+        None
+    };
+
+    let items: Vec<u64> = scanned
+        .into_iter()
+        .filter(|blob| blob.status == BlobStatus::Uploading && blob.created_at < cutoff)
+        .map(|blob| blob.id)
+        .collect();
+    for blob_id in &items {
+        remove_blob(*blob_id);
+    }
+
+    Ok(Page { items, next_cursor })
+}
+
+pub fn get_blob_by_id(blob_id: &u64) -> Option<Blob> {
+    BLOB_STORAGE.with(|service| service.borrow().get(blob_id))
+}
+
+/// Checks that the caller may attach the blob to `link`: it is already
+/// attached there, or they uploaded it, it is finalized and not attached elsewhere.
+pub fn validate_attachment(blob_id: u64, link: BlobLink) -> Result<(), Error> {
+    if get_blob_by_id(&blob_id).and_then(|blob| blob.attached_to) == Some(link) {
+        return Ok(());
+    }
+
+    let blob = get_owned_blob(blob_id)?;
+
+    if blob.status != BlobStatus::Finalized {
+        return Err(Error::InvalidInput {
+            msg: format!("Blob with id={} is still uploading", blob_id),
+        });
+    }
+
+    match blob.attached_to {
+        Some(existing) => Err(Error::AlreadyExists {
+            msg: format!("Blob with id={} is attached to {:?}", blob_id, existing),
+        }),
+        None => Ok(()),
+    }
+}
+
+pub fn attach_blob(blob_id: u64, link: BlobLink) {
+    if let Some(mut blob) = get_blob_by_id(&blob_id) {
+        blob.attached_to = Some(link);
+        BLOB_STORAGE.with(|service| service.borrow_mut().insert(blob_id, blob));
+    }
+}
+
+// Stale uploads of the owner are dropped here rather than left to count
+// against the quota until the next purge
+fn require_upload_quota(owner: &str, total_size: u64) -> Result<(), Error> {
+    let cutoff = ic_cdk::api::time().saturating_sub(UPLOAD_TTL);
+    let mut pending = 0;
+    let mut pending_bytes = 0;
+    for blob in blob_ids_by_owner(owner).iter().filter_map(get_blob_by_id) {
+        if blob.owner != owner || blob.attached_to.is_some() {
+            continue;
+        }
+        if blob.status == BlobStatus::Uploading && blob.created_at < cutoff {
+            remove_blob(blob.id);
+            continue;
+        }
+        pending += 1;
+        pending_bytes += blob.declared_size;
+    }
+
+    if pending >= MAX_PENDING_BLOBS || pending_bytes + total_size > MAX_PENDING_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Caller holds {} unattached blobs; at most {} of {} bytes in total are allowed",
+                pending, MAX_PENDING_BLOBS, MAX_PENDING_BYTES
+            ),
+        });
+    }
+    Ok(())
+}
+
+pub fn remove_blob(blob_id: u64) {
+    if let Some(blob) = BLOB_STORAGE.with(|service| service.borrow_mut().remove(&blob_id)) {
+        unindex_blob(&blob);
+        BLOB_CHUNK_STORAGE.with(|service| {
+            let mut chunks = service.borrow_mut();
+            for index in 0..blob.chunk_count {
+                chunks.remove(&BlobChunkKey { blob_id, index });
+            }
+        });
+    }
+}
+
+/// Moves a payload that older layouts stored inline into the blob store.
+/// The blob reuses the id of the owning record, so storing the same legacy
+/// payload again is a no-op.
+pub fn store_legacy_payload(
+    record_id: u64,
+    link: BlobLink,
+    owner: String,
+    content_type: String,
+    data: Vec<u8>,
+) {
+    if get_blob_by_id(&record_id).is_some() {
+        return;
+    }
+
+    let size = data.len() as u64;
+    let chunks: Vec<Vec<u8>> = data
+        .chunks(BLOB_CHUNK_SIZE as usize)
+        .map(|chunk| chunk.to_vec())
+        .collect();
+    let chunk_count = chunks.len() as u32;
+
+    BLOB_CHUNK_STORAGE.with(|service| {
+        let mut storage = service.borrow_mut();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let key = BlobChunkKey {
+                blob_id: record_id,
+                index: index as u32,
+            };
+            storage.insert(key, BlobChunk(chunk));
+        }
+    });
+
+    let blob = Blob {
+        id: record_id,
+        owner,
+        content_type,
+        declared_size: size,
+        size,
+        chunk_count,
+        sha256: Some(hash_chunks(record_id, chunk_count)),
+        status: BlobStatus::Finalized,
+        attached_to: Some(link),
+        created_at: ic_cdk::api::time(),
+    };
+
+    index_blob(&blob);
+    BLOB_STORAGE.with(|service| service.borrow_mut().insert(record_id, blob));
+}
+
+pub fn read_range(blob_id: u64, offset: u64, length: u64) -> Vec<u8> {
+    let chunk_size = BLOB_CHUNK_SIZE as u64;
+    let end = offset + length;
+    let mut bytes = Vec::with_capacity(length as usize);
+
+    BLOB_CHUNK_STORAGE.with(|service| {
+        let chunks = service.borrow();
+        for index in offset / chunk_size..=(end - 1) / chunk_size {
+            let key = BlobChunkKey {
+                blob_id,
+                index: index as u32,
+            };
+            if let Some(chunk) = chunks.get(&key) {
+                let chunk_start = index * chunk_size;
+                let from = offset.saturating_sub(chunk_start) as usize;
+                let to = ((end - chunk_start) as usize).min(chunk.0.len());
+                bytes.extend_from_slice(&chunk.0[from..to]);
+            }
+        }
+    });
+
+    bytes
+}
+
+fn hash_chunks(blob_id: u64, chunk_count: u32) -> String {
+    let mut hasher = Sha256::new();
+    BLOB_CHUNK_STORAGE.with(|service| {
+        let chunks = service.borrow();
+        for index in 0..chunk_count {
+            if let Some(chunk) = chunks.get(&BlobChunkKey { blob_id, index }) {
+                hasher.update(&chunk.0);
+            }
+        }
+    });
+
+    to_hex(&hasher.finalize())
+}
+
+fn get_owned_blob(blob_id: u64) -> Result<Blob, Error> {
+    let blob = get_blob_by_id(&blob_id).ok_or(Error::NotFound {
+        msg: format!("Blob with id={} not found", blob_id),
+    })?;
+
+    let caller = caller_context();
+    if !caller.is_admin && caller.principal != blob.owner {
+        return Err(unauthorized("Caller did not upload this blob"));
+    }
+    Ok(blob)
+}
+
+// Uploaders can always read their blobs; everyone else needs access to the
+// record the blob is attached to.
+fn require_blob_read(blob: &Blob) -> Result<(), Error> {
+    let caller = caller_context();
+    if caller.is_admin || caller.principal == blob.owner {
+        return Ok(());
+    }
+
+    match blob.attached_to {
+        Some(BlobLink::Message(message_id)) => match get_message_by_id(&message_id) {
+            Some(message) => require_message_party(&caller, &message),
+            None => Err(unauthorized("Attachment of a deleted message")),
+        },
+        Some(BlobLink::Report(report_id)) => match get_report_by_id(&report_id) {
+            Some(report) => {
+                caller.require_patient_access(report.patient_id, ConsentScope::ReadReports)
+            }
+            None => Err(unauthorized("Attachment of a deleted report")),
+        },
+        Some(BlobLink::Data(data_id)) => match get_data_by_id(&data_id) {
+            Some(data) => require_data_access(&data.patient_username, ConsentScope::ReadRecords),
+            None => Err(unauthorized("Attachment of deleted data")),
+        },
+        None => Err(unauthorized("Caller did not upload this blob")),
+    }
+}
\ No newline at end of file
@@ -1,5 +1,6 @@
 //! Calendly integration functionality
 
+use crate::auth::{caller_context, unauthorized};
 use crate::error::Error;
 use crate::models::Calendly;
 use crate::storage::CALENDLY_STORAGE;
@@ -7,6 +8,11 @@ use crate::utils::generate_id;
 
 #[ic_cdk::update]
 pub fn add_calendly(principle_id: String, calendly: String) -> Result<Calendly, Error> {
+    let caller = caller_context();
+    if !caller.is_admin && caller.principal != principle_id {
+        return Err(unauthorized("Callers may only add their own Calendly link"));
+    }
+
     let id = generate_id();
 
     let calendly = Calendly {
@@ -34,8 +40,16 @@ pub fn get_calendly(id: u64) -> Result<Calendly, Error> {
 
 #[ic_cdk::update]
 pub fn delete_calendly(id: u64) -> Result<(), Error> {
+    let caller = caller_context();
     CALENDLY_STORAGE.with(|service| {
         let mut storage = service.borrow_mut();
+        if let Some(calendly) = storage.get(&id) {
+            if !caller.is_admin && caller.principal != calendly.principle_id {
+                return Err(unauthorized(
+                    "Callers may only delete their own Calendly link",
+                ));
+            }
+        }
         if storage.remove(&id).is_some() {
             Ok(())
         } else {
@@ -0,0 +1,70 @@
+//! The patient chart: one view over the patient's clinical lists
+
+use crate::allergy::get_allergies_by_patient;
+use crate::audit::record_reads;
+use crate::auth::caller_context;
+use crate::error::Error;
+use crate::immunization::get_immunizations_by_patient;
+use crate::lab_result::get_lab_results_by_patient;
+use crate::medical_record::get_medical_records_by_patient;
+use crate::models::{AuditEntity, ConsentScope, PatientChart, VitalFilter};
+use crate::patient::get_patient_by_id;
+use crate::problem::get_problems_by_patient;
+use crate::vital::{get_vitals_by_patient, vital_stats};
+
+const ENDPOINT: &str = "get_patient_chart";
+
+#[ic_cdk::update]
+pub fn get_patient_chart(patient_id: u64) -> Result<PatientChart, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadRecords)?;
+
+    let patient = get_patient_by_id(&patient_id).ok_or(Error::NotFound {
+        msg: format!("Patient with id={} not found", patient_id),
+    })?;
+
+    let vitals = get_vitals_by_patient(patient_id, &VitalFilter::default());
+    let chart = PatientChart {
+        patient,
+        allergies: get_allergies_by_patient(patient_id),
+        immunizations: get_immunizations_by_patient(patient_id),
+        problems: get_problems_by_patient(patient_id),
+        medical_records: get_medical_records_by_patient(patient_id),
+        lab_results: get_lab_results_by_patient(patient_id),
+        vital_stats: vital_stats(&vitals),
+    };
+
+    record_reads(
+        ENDPOINT,
+        AuditEntity::Allergy,
+        chart.allergies.iter().map(|allergy| allergy.id),
+    );
+    record_reads(
+        ENDPOINT,
+        AuditEntity::Immunization,
+        chart
+            .immunizations
+            .iter()
+            .map(|immunization| immunization.id),
+    );
+    record_reads(
+        ENDPOINT,
+        AuditEntity::Problem,
+        chart.problems.iter().map(|problem| problem.id),
+    );
+    record_reads(
+        ENDPOINT,
+        AuditEntity::MedicalRecord,
+        chart.medical_records.iter().map(|record| record.id),
+    );
+    record_reads(
+        ENDPOINT,
+        AuditEntity::LabResult,
+        chart.lab_results.iter().map(|result| result.id),
+    );
+    record_reads(
+        ENDPOINT,
+        AuditEntity::Vital,
+        vitals.iter().map(|vital| vital.id),
+    );
+    Ok(chart)
+}
\ No newline at end of file
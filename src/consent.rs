@@ -0,0 +1,182 @@
+//! Patient consent to share clinical data
+//!
+//! A patient grants one doctor, or every holder of a role, scoped access to
+//! their records and reports. Apart from the patient and admins, clinical
+//! endpoints only serve callers holding an active grant, one that has neither
+//! expired nor been revoked.
+
+use crate::audit::{audited, audited_create};
+use crate::auth::caller_context;
+use crate::doctor::get_doctor_by_id;
+use crate::error::Error;
+use crate::index::{consent_ids_by_patient, index_consent};
+use crate::models::{AuditAction, AuditEntity, ConsentGrant, ConsentGrantee, ConsentScope, Role};
+use crate::patient::get_patient_by_id;
+use crate::storage::CONSENT_STORAGE;
+use crate::utils::generate_id;
+
+#[ic_cdk::update]
+pub fn grant_consent(
+    patient_id: u64,
+    grantee: ConsentGrantee,
+    scopes: Vec<ConsentScope>,
+    expires_at: Option<u64>,
+) -> Result<ConsentGrant, Error> {
+    audited_create(
+        "grant_consent",
+        AuditEntity::Consent,
+        |grant: &ConsentGrant| grant.id,
+        || {
+            // Only the patient decides who sees their data
+            caller_context().require_patient(patient_id)?;
+
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
+
+            let now = ic_cdk::api::time();
+            let scopes = validate_grant(grantee, scopes, expires_at, now)?;
+
+            let grant = ConsentGrant {
+                id: generate_id(),
+                patient_id,
+                grantee,
+                scopes,
+                granted_at: now,
+                expires_at,
+                revoked_at: None,
+            };
+
+            CONSENT_STORAGE.with(|service| service.borrow_mut().insert(grant.id, grant.clone()));
+            index_consent(&grant);
+            Ok(grant)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn revoke_consent(grant_id: u64) -> Result<ConsentGrant, Error> {
+    audited(
+        "revoke_consent",
+        AuditEntity::Consent,
+        grant_id,
+        AuditAction::Update,
+        || {
+            let mut grant = get_consent_by_id(&grant_id).ok_or(Error::NotFound {
+                msg: format!("Consent grant with id={} not found", grant_id),
+            })?;
+
+            caller_context().require_patient(grant.patient_id)?;
+
+            if grant.revoked_at.is_some() {
+                return Err(Error::InvalidTransition {
+                    msg: format!("Consent grant with id={} is already revoked", grant_id),
+                });
+            }
+
+            grant.revoked_at = Some(ic_cdk::api::time());
+            CONSENT_STORAGE.with(|service| service.borrow_mut().insert(grant_id, grant.clone()));
+            Ok(grant)
+        },
+    )
+}
+
+/// Every grant the patient has given, including expired and revoked ones.
+#[ic_cdk::query]
+pub fn list_consents(patient_id: u64) -> Result<Vec<ConsentGrant>, Error> {
+    caller_context().require_patient(patient_id)?;
+
+    Ok(CONSENT_STORAGE.with(|service| {
+        let storage = service.borrow();
+        consent_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|grant_id| storage.get(grant_id))
+            .collect()
+    }))
+}
+
+pub fn get_consent_by_id(grant_id: &u64) -> Option<ConsentGrant> {
+    CONSENT_STORAGE.with(|service| service.borrow().get(grant_id))
+}
+
+/// True when the patient has an active grant covering `scope` for the doctor
+/// or one of the roles.
+pub fn has_active_grant(
+    patient_id: u64,
+    doctor_id: Option<u64>,
+    roles: &[Role],
+    scope: ConsentScope,
+) -> bool {
+    let now = ic_cdk::api::time();
+    CONSENT_STORAGE.with(|service| {
+        let storage = service.borrow();
+        consent_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|grant_id| storage.get(grant_id))
+            .any(|grant| {
+                is_active(&grant, now)
+                    && grant.scopes.contains(&scope)
+                    && match grant.grantee {
+                        ConsentGrantee::Doctor(grantee) => doctor_id == Some(grantee),
+                        ConsentGrantee::Role(role) => roles.contains(&role),
+                    }
+            })
+    })
+}
+
+fn is_active(grant: &ConsentGrant, now: u64) -> bool {
+    grant.revoked_at.is_none() && grant.expires_at.map_or(true, |expires_at| now < expires_at)
+}
+
+// Returns the scopes without duplicates
+fn validate_grant(
+    grantee: ConsentGrantee,
+    scopes: Vec<ConsentScope>,
+    expires_at: Option<u64>,
+    now: u64,
+) -> Result<Vec<ConsentScope>, Error> {
+    if scopes.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "A grant needs at least one scope".to_string(),
+        });
+    }
+    if expires_at.map_or(false, |expires_at| expires_at <= now) {
+        return Err(Error::InvalidInput {
+            msg: "Expiry must be in the future".to_string(),
+        });
+    }
+
+    let grants_doctors = match grantee {
+        ConsentGrantee::Doctor(doctor_id) => {
+            if get_doctor_by_id(&doctor_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Doctor with id={} not found", doctor_id),
+                });
+            }
+            true
+        }
+        // Would share the data with every other patient
+        ConsentGrantee::Role(Role::Patient) => {
+            return Err(Error::InvalidInput {
+                msg: "Consent cannot be granted to the Patient role".to_string(),
+            });
+        }
+        ConsentGrantee::Role(role) => role == Role::Doctor,
+    };
+    if scopes.contains(&ConsentScope::WriteReports) && !grants_doctors {
+        return Err(Error::InvalidInput {
+            msg: "Only doctors can be allowed to write reports".to_string(),
+        });
+    }
+
+    let mut unique = Vec::with_capacity(scopes.len());
+    for scope in scopes {
+        if !unique.contains(&scope) {
+            unique.push(scope);
+        }
+    }
+    Ok(unique)
+}
\ No newline at end of file
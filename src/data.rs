@@ -1,7 +1,12 @@
 //! Data management functionality
 
+use crate::audit::{audited_create, audited_read};
+use crate::auth::caller_context;
+use crate::blob::{attach_blob, validate_attachment};
 use crate::error::Error;
-use crate::models::Data;
+use crate::index::index_data;
+use crate::models::{AuditEntity, BlobLink, ConsentScope, Data};
+use crate::patient::get_patient_by_username;
 use crate::storage::DATA_STORAGE;
 use crate::utils::generate_id;
 
@@ -9,30 +14,56 @@ use crate::utils::generate_id;
 pub fn add_data(
     patient_username: String,
     doctor_username: String,
-    data: Vec<u8>,
+    blob_id: u64,
 ) -> Result<Data, Error> {
-    let id = generate_id();
+    audited_create(
+        "add_data",
+        AuditEntity::Data,
+        |data: &Data| data.id,
+        || {
+            require_data_access(&patient_username, ConsentScope::WriteRecords)?;
 
-    let data = Data {
-        id,
-        patient_username,
-        doctor_username,
-        data,
-    };
+            let id = generate_id();
 
-    DATA_STORAGE.with(|service| service.borrow_mut().insert(id, data.clone()));
-    Ok(data)
+            validate_attachment(blob_id, BlobLink::Data(id))?;
+
+            let data = Data {
+                id,
+                patient_username,
+                doctor_username,
+                blob_id,
+            };
+
+            DATA_STORAGE.with(|service| service.borrow_mut().insert(id, data.clone()));
+            index_data(&data);
+            attach_blob(blob_id, BlobLink::Data(id));
+            Ok(data)
+        },
+    )
 }
 
-#[ic_cdk::query]
+#[ic_cdk::update]
 pub fn get_data(id: u64) -> Result<Data, Error> {
-    DATA_STORAGE.with(|service| {
-        service
-            .borrow()
-            .get(&id)
-            .map(|data| data.clone())
-            .ok_or(Error::NotFound {
-                msg: format!("Data with id={} not found", id),
-            })
+    audited_read("get_data", AuditEntity::Data, id, || {
+        let data = get_data_by_id(&id).ok_or(Error::NotFound {
+            msg: format!("Data with id={} not found", id),
+        })?;
+
+        require_data_access(&data.patient_username, ConsentScope::ReadRecords)?;
+        Ok(data)
     })
+}
+
+pub fn get_data_by_id(data_id: &u64) -> Option<Data> {
+    DATA_STORAGE.with(|service| service.borrow().get(data_id))
+}
+
+// The patient owning the data and those they shared their records with within
+// `scope` may read or write it
+pub fn require_data_access(patient_username: &str, scope: ConsentScope) -> Result<(), Error> {
+    let patient = get_patient_by_username(patient_username).ok_or(Error::NotFound {
+        msg: format!("Patient with username={} not found", patient_username),
+    })?;
+
+    caller_context().require_patient_access(patient.id, scope)
 }
\ No newline at end of file
@@ -1,7 +1,11 @@
 //! Doctor management functionality
 
+use crate::auth::{caller_context, unauthorized};
 use crate::error::Error;
-use crate::models::Doctor;
+use crate::identity::{add_role, remove_role};
+use crate::index::{doctor_id_by_principal, index_doctor, unindex_doctor};
+use crate::models::{Doctor, DoctorFilter, Page, PageRequest, Role};
+use crate::pagination::{matches, paginate};
 use crate::storage::DOCTOR_STORAGE;
 use crate::utils::generate_id;
 
@@ -17,6 +21,7 @@ pub fn add_doctor(
     sex: String,
     country: String,
     city: String,
+    time_zone: String,
 ) -> Result<Doctor, Error> {
     // Validate input data
     if principal_str.is_empty()
@@ -32,16 +37,18 @@ pub fn add_doctor(
             msg: "All fields must be filled".to_string(),
         });
     }
+    validate_time_zone(&time_zone)?;
+
+    // Doctors register their own profile once an admin granted them the Doctor role
+    let caller = caller_context();
+    if !caller.is_admin && (caller.principal != principal_str || !caller.has_role(Role::Doctor)) {
+        return Err(unauthorized(
+            "Caller must register a doctor profile for their own doctor identity",
+        ));
+    }
 
     // Check if the principal already exists
-    let exists = DOCTOR_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .any(|(_, doctor)| doctor.principal_str == principal_str)
-    });
-
-    if exists {
+    if doctor_id_by_principal(&principal_str).is_some() {
         return Err(Error::AlreadyExists {
             msg: "Principal already exists".to_string(),
         });
@@ -61,9 +68,12 @@ pub fn add_doctor(
         sex,
         country,
         city,
+        time_zone,
     };
 
     DOCTOR_STORAGE.with(|service| service.borrow_mut().insert(id, doctor.clone()));
+    index_doctor(&doctor);
+    add_role(doctor.principal_str.clone(), Role::Doctor);
     Ok(doctor)
 }
 
@@ -79,6 +89,7 @@ pub fn update_doctor(
     sex: String,
     country: String,
     city: String,
+    time_zone: String,
 ) -> Result<Doctor, Error> {
     // Validate input data
     if fname.is_empty()
@@ -93,6 +104,7 @@ pub fn update_doctor(
             msg: "All fields must be provided".to_string(),
         });
     }
+    validate_time_zone(&time_zone)?;
 
     let identity_id = principal_str
         .parse::<u64>()
@@ -100,17 +112,21 @@ pub fn update_doctor(
             msg: "Invalid Identity ID format".to_string(),
         })?;
 
-    let doctor_exists = DOCTOR_STORAGE.with(|service| service.borrow().contains_key(&identity_id));
+    caller_context().require_doctor(identity_id)?;
 
-    if !doctor_exists {
-        return Err(Error::NotFound {
-            msg: "Doctor with this Identity ID does not exist".to_string(),
-        });
-    }
+    let existing_doctor = match get_doctor_by_id(&identity_id) {
+        Some(doctor) => doctor,
+        None => {
+            return Err(Error::NotFound {
+                msg: "Doctor with this Identity ID does not exist".to_string(),
+            })
+        }
+    };
 
+    // Keep the stored principal so the doctor can still authenticate
     let updated_doctor = Doctor {
         id: identity_id,
-        principal_str: identity_id.to_string(),
+        principal_str: existing_doctor.principal_str,
         fname,
         lname,
         dob,
@@ -120,6 +136,7 @@ pub fn update_doctor(
         sex,
         country,
         city,
+        time_zone,
     };
 
     DOCTOR_STORAGE.with(|service| {
@@ -143,8 +160,14 @@ pub fn get_doctor(docidentity_id: u64) -> Result<Doctor, Error> {
 
 #[ic_cdk::update]
 pub fn delete_doctor(doctor_id: u64) -> Result<(), Error> {
+    caller_context().require_doctor(doctor_id)?;
+
     match DOCTOR_STORAGE.with(|service| service.borrow_mut().remove(&doctor_id)) {
-        Some(_) => Ok(()),
+        Some(doctor) => {
+            unindex_doctor(&doctor);
+            remove_role(&doctor.principal_str, Role::Doctor);
+            Ok(())
+        }
         None => Err(Error::NotFound {
             msg: format!("Doctor with id={} not found", doctor_id),
         }),
@@ -152,16 +175,23 @@ pub fn delete_doctor(doctor_id: u64) -> Result<(), Error> {
 }
 
 #[ic_cdk::query]
-pub fn list_doctors() -> Vec<Doctor> {
-    DOCTOR_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, doctor)| doctor.clone())
-            .collect()
+pub fn list_doctors(page: PageRequest, filter: DoctorFilter) -> Page<Doctor> {
+    paginate(&DOCTOR_STORAGE, &page, |doctor| {
+        matches(&filter.specialism, &doctor.specialism)
+            && matches(&filter.country, &doctor.country)
+            && matches(&filter.city, &doctor.city)
     })
 }
 
 pub fn get_doctor_by_id(docidentity_id: &u64) -> Option<Doctor> {
     DOCTOR_STORAGE.with(|service| service.borrow().get(docidentity_id))
+}
+
+fn validate_time_zone(time_zone: &str) -> Result<(), Error> {
+    match time_zone.parse::<chrono_tz::Tz>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::InvalidInput {
+            msg: format!("Unknown IANA time zone {}", time_zone),
+        }),
+    }
 }
\ No newline at end of file
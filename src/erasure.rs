@@ -0,0 +1,391 @@
+//! Right to erasure: removing a patient and everything linked to them
+//!
+//! Retention rules: clinical records, reports, prescriptions, chart entries,
+//! data, messages, consent grants and their attachments are deleted.
+//! Appointments that have not ended are deleted, which frees their slots; past
+//! ones stay in the doctor's history with the patient's details stripped. The
+//! identity is deleted unless it holds other roles. The audit log is
+//! append-only and keeps its entries; the erasure itself is recorded there item
+//! by item.
+
+use crate::allergy::get_allergy_by_id;
+use crate::appointment::{
+    get_appointment_by_id, get_reschedules, get_status_history, remove_reschedules,
+    remove_status_history, set_reschedules, set_status_history,
+};
+use crate::audit::record;
+use crate::auth::caller_context;
+use crate::blob::{get_blob_by_id, remove_blob};
+use crate::consent::get_consent_by_id;
+use crate::data::get_data_by_id;
+use crate::error::Error;
+use crate::identity::get_identity_by_id;
+use crate::immunization::get_immunization_by_id;
+use crate::index::{
+    allergy_ids_by_patient, appointment_ids_by_patient, blob_ids_by_owner, consent_ids_by_patient,
+    data_ids_by_patient, immunization_ids_by_patient, index_appointment, index_identity,
+    lab_result_ids_by_patient, medical_record_ids_by_patient, message_ids_by_party,
+    prescription_ids_by_patient, problem_ids_by_patient, report_ids_by_patient, unindex_allergy,
+    unindex_appointment, unindex_consent, unindex_data, unindex_identity, unindex_immunization,
+    unindex_lab_result, unindex_medical_record, unindex_message, unindex_patient,
+    unindex_prescription, unindex_problem, unindex_report, unindex_vital, vital_ids_by_patient,
+};
+use crate::lab_result::get_lab_result_by_id;
+use crate::medical_record::{get_stored_medical_record, remove_medical_record_revisions};
+use crate::message::get_message_by_id;
+use crate::models::{
+    AuditAction, AuditEntity, AuditOutcome, ErasurePlan, ErasureReceipt, Patient, Role,
+};
+use crate::patient::get_patient_by_id;
+use crate::prescription::get_prescription_by_id;
+use crate::problem::get_problem_by_id;
+use crate::report::get_report_by_id;
+use crate::storage::{
+    ALLERGY_STORAGE, APPOINTMENT_STORAGE, CONSENT_STORAGE, DATA_STORAGE, IDENTITY_STORAGE,
+    IMMUNIZATION_STORAGE, LAB_RESULT_STORAGE, MEDICAL_RECORD_STORAGE, MESSAGE_STORAGE,
+    PATIENT_STORAGE, PRESCRIPTION_STORAGE, PROBLEM_STORAGE, REPORT_STORAGE, VITAL_STORAGE,
+};
+use crate::vital::get_vital_by_id;
+
+/// Patient id left on anonymized appointments.
+pub const ERASED_PATIENT_ID: u64 = 0;
+
+const ENDPOINT: &str = "erase_patient";
+
+/// Lists what `erase_patient` would delete or anonymize, without changing anything.
+#[ic_cdk::query]
+pub fn preview_patient_erasure(patient_id: u64) -> Result<ErasurePlan, Error> {
+    caller_context().require_patient(patient_id)?;
+    Ok(plan_erasure(&get_erasable_patient(patient_id)?))
+}
+
+#[ic_cdk::update]
+pub fn erase_patient(patient_id: u64) -> Result<ErasureReceipt, Error> {
+    let caller = caller_context();
+    caller.require_patient(patient_id)?;
+
+    let patient = get_erasable_patient(patient_id)?;
+    let plan = plan_erasure(&patient);
+    let principal = get_identity_by_id(&patient.identity_id).map(|identity| identity.principal);
+    let now = ic_cdk::api::time();
+
+    let mut first_audit_seq = None;
+    let mut erased = |entity: AuditEntity, entity_id: u64, action: AuditAction| {
+        let entry = record(ENDPOINT, entity, entity_id, action, AuditOutcome::Success);
+        first_audit_seq.get_or_insert(entry.seq);
+    };
+
+    for record_id in &plan.medical_record_ids {
+        if let Some(record) =
+            MEDICAL_RECORD_STORAGE.with(|service| service.borrow_mut().remove(record_id))
+        {
+            unindex_medical_record(&record);
+        }
+        remove_medical_record_revisions(*record_id);
+        erased(AuditEntity::MedicalRecord, *record_id, AuditAction::Delete);
+    }
+    for report_id in &plan.report_ids {
+        if let Some(report) = REPORT_STORAGE.with(|service| service.borrow_mut().remove(report_id))
+        {
+            unindex_report(&report);
+        }
+        erased(AuditEntity::Report, *report_id, AuditAction::Delete);
+    }
+    for prescription_id in &plan.prescription_ids {
+        if let Some(prescription) =
+            PRESCRIPTION_STORAGE.with(|service| service.borrow_mut().remove(prescription_id))
+        {
+            unindex_prescription(&prescription);
+        }
+        erased(
+            AuditEntity::Prescription,
+            *prescription_id,
+            AuditAction::Delete,
+        );
+    }
+    for allergy_id in &plan.allergy_ids {
+        if let Some(allergy) =
+            ALLERGY_STORAGE.with(|service| service.borrow_mut().remove(allergy_id))
+        {
+            unindex_allergy(&allergy);
+        }
+        erased(AuditEntity::Allergy, *allergy_id, AuditAction::Delete);
+    }
+    for immunization_id in &plan.immunization_ids {
+        if let Some(immunization) =
+            IMMUNIZATION_STORAGE.with(|service| service.borrow_mut().remove(immunization_id))
+        {
+            unindex_immunization(&immunization);
+        }
+        erased(
+            AuditEntity::Immunization,
+            *immunization_id,
+            AuditAction::Delete,
+        );
+    }
+    for problem_id in &plan.problem_ids {
+        if let Some(problem) =
+            PROBLEM_STORAGE.with(|service| service.borrow_mut().remove(problem_id))
+        {
+            unindex_problem(&problem);
+        }
+        erased(AuditEntity::Problem, *problem_id, AuditAction::Delete);
+    }
+    for result_id in &plan.lab_result_ids {
+        if let Some(result) =
+            LAB_RESULT_STORAGE.with(|service| service.borrow_mut().remove(result_id))
+        {
+            unindex_lab_result(&result);
+        }
+        erased(AuditEntity::LabResult, *result_id, AuditAction::Delete);
+    }
+    for vital_id in &plan.vital_ids {
+        if let Some(vital) = VITAL_STORAGE.with(|service| service.borrow_mut().remove(vital_id)) {
+            unindex_vital(&vital);
+        }
+        erased(AuditEntity::Vital, *vital_id, AuditAction::Delete);
+    }
+    for data_id in &plan.data_ids {
+        if let Some(data) = DATA_STORAGE.with(|service| service.borrow_mut().remove(data_id)) {
+            unindex_data(&data);
+        }
+        erased(AuditEntity::Data, *data_id, AuditAction::Delete);
+    }
+    for message_id in &plan.message_ids {
+        if let Some(message) =
+            MESSAGE_STORAGE.with(|service| service.borrow_mut().remove(message_id))
+        {
+            unindex_message(&message);
+        }
+        erased(AuditEntity::Message, *message_id, AuditAction::Delete);
+    }
+    for blob_id in &plan.blob_ids {
+        remove_blob(*blob_id);
+        erased(AuditEntity::Blob, *blob_id, AuditAction::Delete);
+    }
+    for grant_id in &plan.consent_ids {
+        if let Some(grant) = CONSENT_STORAGE.with(|service| service.borrow_mut().remove(grant_id)) {
+            unindex_consent(&grant);
+        }
+        erased(AuditEntity::Consent, *grant_id, AuditAction::Delete);
+    }
+
+    for appointment_id in &plan.deleted_appointment_ids {
+        if let Some(appointment) =
+            APPOINTMENT_STORAGE.with(|service| service.borrow_mut().remove(appointment_id))
+        {
+            unindex_appointment(&appointment);
+            remove_status_history(*appointment_id);
+            remove_reschedules(*appointment_id);
+        }
+        erased(
+            AuditEntity::Appointment,
+            *appointment_id,
+            AuditAction::Delete,
+        );
+    }
+    for appointment_id in &plan.anonymized_appointment_ids {
+        if let Some(mut appointment) = get_appointment_by_id(appointment_id) {
+            unindex_appointment(&appointment);
+            appointment.patient_id = ERASED_PATIENT_ID;
+            appointment.phone_no = String::new();
+            appointment.reason = String::new();
+            appointment.symtoms = String::new();
+            // Changes the patient made themselves would still name their principal
+            let mut history = get_status_history(*appointment_id);
+            for change in &mut history {
+                if Some(&change.by) == principal.as_ref() {
+                    change.by = String::new();
+                }
+            }
+            set_status_history(*appointment_id, history);
+            let mut reschedules = get_reschedules(*appointment_id);
+            for reschedule in &mut reschedules {
+                if Some(&reschedule.by) == principal.as_ref() {
+                    reschedule.by = String::new();
+                }
+            }
+            set_reschedules(*appointment_id, reschedules);
+            APPOINTMENT_STORAGE.with(|service| {
+                service
+                    .borrow_mut()
+                    .insert(*appointment_id, appointment.clone())
+            });
+            index_appointment(&appointment);
+        }
+        erased(
+            AuditEntity::Appointment,
+            *appointment_id,
+            AuditAction::Update,
+        );
+    }
+
+    if let Some(mut identity) = plan
+        .identity_id
+        .and_then(|identity_id| get_identity_by_id(&identity_id))
+    {
+        if plan.identity_deleted {
+            IDENTITY_STORAGE.with(|service| service.borrow_mut().remove(&identity.id));
+            unindex_identity(&identity);
+            erased(AuditEntity::Identity, identity.id, AuditAction::Delete);
+        } else {
+            identity.roles.retain(|role| *role != Role::Patient);
+            IDENTITY_STORAGE
+                .with(|service| service.borrow_mut().insert(identity.id, identity.clone()));
+            index_identity(&identity);
+            erased(AuditEntity::Identity, identity.id, AuditAction::Update);
+        }
+    }
+
+    PATIENT_STORAGE.with(|service| service.borrow_mut().remove(&patient_id));
+    unindex_patient(&patient);
+
+    // The patient entry closes the erasure, so the receipt points at it
+    let last = record(
+        ENDPOINT,
+        AuditEntity::Patient,
+        patient_id,
+        AuditAction::Delete,
+        AuditOutcome::Success,
+    );
+
+    Ok(ErasureReceipt {
+        plan,
+        erased_at: now,
+        erased_by: caller.principal,
+        first_audit_seq: first_audit_seq.unwrap_or(last.seq),
+        last_audit_seq: last.seq,
+        audit_hash: last.hash,
+    })
+}
+
+fn get_erasable_patient(patient_id: u64) -> Result<Patient, Error> {
+    get_patient_by_id(&patient_id).ok_or(Error::NotFound {
+        msg: format!("Patient with id={} not found", patient_id),
+    })
+}
+
+/// Collects everything linked to the patient.
+pub fn plan_erasure(patient: &Patient) -> ErasurePlan {
+    let patient_id = patient.id;
+    let now = ic_cdk::api::time();
+    let mut blob_ids = Vec::new();
+
+    let medical_record_ids = medical_record_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|record_id| get_stored_medical_record(record_id).is_some())
+        .collect();
+
+    let mut report_ids = Vec::new();
+    for report in report_ids_by_patient(patient_id)
+        .iter()
+        .filter_map(get_report_by_id)
+    {
+        blob_ids.extend(report.multimedia_content.map(|content| content.blob_id));
+        report_ids.push(report.id);
+    }
+
+    let mut data_ids = Vec::new();
+    for data in data_ids_by_patient(&patient.username)
+        .iter()
+        .filter_map(get_data_by_id)
+        .filter(|data| data.patient_username == patient.username)
+    {
+        blob_ids.push(data.blob_id);
+        data_ids.push(data.id);
+    }
+
+    let mut message_ids = Vec::new();
+    for message in message_ids_by_party(patient_id)
+        .iter()
+        .filter_map(get_message_by_id)
+    {
+        blob_ids.extend(message.multimedia_content.map(|content| content.blob_id));
+        message_ids.push(message.id);
+    }
+
+    let identity = get_identity_by_id(&patient.identity_id);
+
+    // Uploads the patient never attached to anything
+    if let Some(identity) = &identity {
+        blob_ids.extend(
+            blob_ids_by_owner(&identity.principal)
+                .iter()
+                .filter_map(get_blob_by_id)
+                .filter(|blob| blob.owner == identity.principal && blob.attached_to.is_none())
+                .map(|blob| blob.id),
+        );
+    }
+    blob_ids.sort_unstable();
+    blob_ids.dedup();
+
+    let mut deleted_appointment_ids = Vec::new();
+    let mut anonymized_appointment_ids = Vec::new();
+    for appointment_id in appointment_ids_by_patient(patient_id) {
+        match get_appointment_by_id(&appointment_id) {
+            Some(appointment) if appointment.patient_id != patient_id => {}
+            Some(appointment) if appointment.slot_end <= now => {
+                anonymized_appointment_ids.push(appointment_id)
+            }
+            Some(_) => deleted_appointment_ids.push(appointment_id),
+            None => {}
+        }
+    }
+
+    let prescription_ids = prescription_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|prescription_id| get_prescription_by_id(prescription_id).is_some())
+        .collect();
+
+    let allergy_ids = allergy_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|allergy_id| get_allergy_by_id(allergy_id).is_some())
+        .collect();
+
+    let immunization_ids = immunization_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|immunization_id| get_immunization_by_id(immunization_id).is_some())
+        .collect();
+
+    let problem_ids = problem_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|problem_id| get_problem_by_id(problem_id).is_some())
+        .collect();
+
+    let lab_result_ids = lab_result_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|result_id| get_lab_result_by_id(result_id).is_some())
+        .collect();
+
+    let vital_ids = vital_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|vital_id| get_vital_by_id(vital_id).is_some())
+        .collect();
+
+    let consent_ids = consent_ids_by_patient(patient_id)
+        .into_iter()
+        .filter(|grant_id| get_consent_by_id(grant_id).is_some())
+        .collect();
+
+    ErasurePlan {
+        patient_id,
+        medical_record_ids,
+        report_ids,
+        data_ids,
+        message_ids,
+        consent_ids,
+        prescription_ids,
+        allergy_ids,
+        immunization_ids,
+        problem_ids,
+        lab_result_ids,
+        vital_ids,
+        deleted_appointment_ids,
+        anonymized_appointment_ids,
+        blob_ids,
+        identity_id: identity.as_ref().map(|identity| identity.id),
+        identity_deleted: identity.map_or(false, |identity| {
+            identity.roles.iter().all(|role| *role == Role::Patient)
+        }),
+    }
+}
\ No newline at end of file
@@ -6,5 +6,7 @@ pub enum Error {
     InvalidInput { msg: String },
     Unauthorized { msg: String },
     AppointmentConflict { msg: String },
+    InvalidTransition { msg: String },
     AlreadyExists { msg: String },
+    InteractionConflict { msg: String },
 }
\ No newline at end of file
@@ -0,0 +1,240 @@
+//! Take-out of everything held about a patient
+//!
+//! The export covers exactly what `erase_patient` would remove. Records come
+//! back in pages of at most `EXPORT_PAGE_BYTES`; attachments are listed by
+//! their blob metadata and downloaded in chunks with `read_blob`, so large
+//! files never have to fit in a single reply. Records added or removed between
+//! two pages may shift the positions the cursor counts.
+
+use serde::Serialize;
+
+use crate::allergy::get_allergy_by_id;
+use crate::appointment::{get_appointment_by_id, get_reschedules, get_status_history};
+use crate::audit::record_reads;
+use crate::auth::caller_context;
+use crate::blob::get_blob_by_id;
+use crate::consent::get_consent_by_id;
+use crate::data::get_data_by_id;
+use crate::erasure::plan_erasure;
+use crate::error::Error;
+use crate::immunization::get_immunization_by_id;
+use crate::lab_result::get_lab_result_by_id;
+use crate::medical_record::{get_medical_record_by_id, get_medical_record_revisions};
+use crate::message::get_message_by_id;
+use crate::models::{AppointmentHistory, AuditEntity, PatientExport};
+use crate::patient::get_patient_by_id;
+use crate::prescription::get_prescription_by_id;
+use crate::problem::get_problem_by_id;
+use crate::report::get_report_by_id;
+use crate::vital::get_vital_by_id;
+
+/// JSON size of the records on one export page. JSON is at least as large as
+/// their candid encoding, so either rendering stays clear of the 2 MB reply
+/// limit.
+pub const EXPORT_PAGE_BYTES: usize = 1_500_000;
+
+/// One page of the export; continue from `next_cursor` until it is `None`.
+#[ic_cdk::update]
+pub fn export_patient_data(patient_id: u64, cursor: Option<u64>) -> Result<PatientExport, Error> {
+    export("export_patient_data", patient_id, cursor)
+}
+
+/// The same export page rendered as JSON.
+#[ic_cdk::update]
+pub fn export_patient_data_json(patient_id: u64, cursor: Option<u64>) -> Result<String, Error> {
+    let export = export("export_patient_data_json", patient_id, cursor)?;
+    serde_json::to_string_pretty(&export).map_err(|err| Error::InvalidInput {
+        msg: format!("Cannot render export as JSON: {}", err),
+    })
+}
+
+fn export(endpoint: &str, patient_id: u64, cursor: Option<u64>) -> Result<PatientExport, Error> {
+    caller_context().require_patient(patient_id)?;
+
+    let patient = get_patient_by_id(&patient_id).ok_or(Error::NotFound {
+        msg: format!("Patient with id={} not found", patient_id),
+    })?;
+    let plan = plan_erasure(&patient);
+
+    let mut appointment_ids = plan.anonymized_appointment_ids.clone();
+    appointment_ids.extend(&plan.deleted_appointment_ids);
+    appointment_ids.sort_unstable();
+
+    let mut export = PatientExport {
+        exported_at: ic_cdk::api::time(),
+        patient,
+        appointments: Vec::new(),
+        appointment_history: Vec::new(),
+        medical_records: Vec::new(),
+        medical_record_revisions: Vec::new(),
+        reports: Vec::new(),
+        prescriptions: Vec::new(),
+        allergies: Vec::new(),
+        immunizations: Vec::new(),
+        problems: Vec::new(),
+        lab_results: Vec::new(),
+        vitals: Vec::new(),
+        messages: Vec::new(),
+        data: Vec::new(),
+        consents: Vec::new(),
+        blobs: Vec::new(),
+        next_cursor: None,
+    };
+
+    let mut page = ExportPage::new(cursor.unwrap_or(0));
+    page.take(
+        &mut export.appointments,
+        &appointment_ids,
+        get_appointment_by_id,
+    );
+    page.take(
+        &mut export.appointment_history,
+        &appointment_ids,
+        |appointment_id| {
+            Some(AppointmentHistory {
+                appointment_id: *appointment_id,
+                status_history: get_status_history(*appointment_id),
+                reschedules: get_reschedules(*appointment_id),
+            })
+        },
+    );
+    page.take(
+        &mut export.medical_records,
+        &plan.medical_record_ids,
+        get_medical_record_by_id,
+    );
+    page.take(
+        &mut export.medical_record_revisions,
+        &plan.medical_record_ids,
+        |record_id| get_medical_record_revisions(*record_id),
+    );
+    page.take(&mut export.reports, &plan.report_ids, get_report_by_id);
+    page.take(
+        &mut export.prescriptions,
+        &plan.prescription_ids,
+        get_prescription_by_id,
+    );
+    page.take(&mut export.allergies, &plan.allergy_ids, get_allergy_by_id);
+    page.take(
+        &mut export.immunizations,
+        &plan.immunization_ids,
+        get_immunization_by_id,
+    );
+    page.take(&mut export.problems, &plan.problem_ids, get_problem_by_id);
+    page.take(
+        &mut export.lab_results,
+        &plan.lab_result_ids,
+        get_lab_result_by_id,
+    );
+    page.take(&mut export.vitals, &plan.vital_ids, get_vital_by_id);
+    page.take(&mut export.messages, &plan.message_ids, get_message_by_id);
+    page.take(&mut export.data, &plan.data_ids, get_data_by_id);
+    page.take(&mut export.consents, &plan.consent_ids, get_consent_by_id);
+    page.take(&mut export.blobs, &plan.blob_ids, get_blob_by_id);
+    export.next_cursor = page.next_cursor;
+
+    record_reads(
+        endpoint,
+        AuditEntity::MedicalRecord,
+        export.medical_records.iter().map(|record| record.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::Report,
+        export.reports.iter().map(|report| report.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::Prescription,
+        export
+            .prescriptions
+            .iter()
+            .map(|prescription| prescription.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::Allergy,
+        export.allergies.iter().map(|allergy| allergy.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::Immunization,
+        export
+            .immunizations
+            .iter()
+            .map(|immunization| immunization.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::Problem,
+        export.problems.iter().map(|problem| problem.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::LabResult,
+        export.lab_results.iter().map(|result| result.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::Vital,
+        export.vitals.iter().map(|vital| vital.id),
+    );
+    record_reads(
+        endpoint,
+        AuditEntity::Data,
+        export.data.iter().map(|data| data.id),
+    );
+    Ok(export)
+}
+
+// Walks the sections of the export in order, counting a cursor position per
+// id. A page holds at least one position, so a history larger than the budget
+// still makes progress.
+struct ExportPage {
+    start: u64,
+    position: u64,
+    remaining: usize,
+    next_cursor: Option<u64>,
+}
+
+impl ExportPage {
+    fn new(start: u64) -> Self {
+        ExportPage {
+            start,
+            position: 0,
+            remaining: EXPORT_PAGE_BYTES,
+            next_cursor: None,
+        }
+    }
+
+    fn take<T: Serialize, L: IntoIterator<Item = T>>(
+        &mut self,
+        out: &mut Vec<T>,
+        ids: &[u64],
+        load: impl Fn(&u64) -> L,
+    ) {
+        let section_end = self.position + ids.len() as u64;
+        if self.next_cursor.is_some() || section_end <= self.start {
+            self.position = section_end;
+            return;
+        }
+
+        let skip = self.start.saturating_sub(self.position) as usize;
+        self.position += skip as u64;
+        for id in &ids[skip..] {
+            let items: Vec<T> = load(id).into_iter().collect();
+            let size: usize = items
+                .iter()
+                .map(|item| serde_json::to_vec_pretty(item).map_or(0, |json| json.len()))
+                .sum();
+            if size > self.remaining && self.position > self.start {
+                self.next_cursor = Some(self.position);
+                return;
+            }
+
+            self.remaining = self.remaining.saturating_sub(size);
+            out.extend(items);
+            self.position += 1;
+        }
+    }
+}
\ No newline at end of file
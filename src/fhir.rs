@@ -0,0 +1,462 @@
+//! FHIR R4 rendering and import of patients, practitioners, appointments,
+//! reports and medical records
+//!
+//! Rendering goes through the regular getters and imports through the regular
+//! constructors, so both are subject to the same authorization, validation and
+//! audit as the native endpoints. Fields FHIR has no element for travel as
+//! identifiers or extensions under the `urn:helcon` namespace. Imports always
+//! create new records; appointment statuses, attachments and resource ids are
+//! not carried over, except for the Bundle id of a medical record.
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, TimeZone, Utc};
+use serde_json::{json, Value};
+
+use crate::appointment::{add_appointment, get_appointment};
+use crate::doctor::{add_doctor, get_doctor};
+use crate::error::Error;
+use crate::medical_record::{create_medical_record, get_medical_record};
+use crate::models::{Appointment, AppointmentStatus, Doctor, MedicalRecord, Patient, Report};
+use crate::patient::{get_patient, register_patient};
+use crate::report::{add_report, get_report};
+
+const USERNAME_SYSTEM: &str = "urn:helcon:username";
+const IDENTITY_SYSTEM: &str = "urn:helcon:identity-id";
+const PRINCIPAL_SYSTEM: &str = "urn:helcon:principal";
+const LICENCE_SYSTEM: &str = "urn:helcon:licence-no";
+const ID_NO_SYSTEM: &str = "urn:helcon:id-no";
+const CODE_SYSTEM: &str = "urn:helcon:code";
+const TIME_ZONE_EXTENSION: &str = "urn:helcon:time-zone";
+const PHONE_EXTENSION: &str = "urn:helcon:phone-no";
+const APPOINTMENT_EXTENSION: &str = "urn:helcon:appointment-id";
+const SEX_EXTENSION: &str = "urn:helcon:sex";
+const DOB_EXTENSION: &str = "urn:helcon:dob";
+
+/// Date formats `dob` is read in, rendered as the FHIR `YYYY-MM-DD`.
+const DOB_FORMATS: [&str; 4] = ["%Y-%m-%d", "%d/%m/%Y", "%d-%m-%Y", "%d.%m.%Y"];
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[ic_cdk::query]
+pub fn get_fhir_patient(patient_id: u64) -> Result<String, Error> {
+    Ok(patient_to_fhir(&get_patient(patient_id)?).to_string())
+}
+
+#[ic_cdk::query]
+pub fn get_fhir_practitioner(doctor_id: u64) -> Result<String, Error> {
+    Ok(doctor_to_fhir(&get_doctor(doctor_id)?).to_string())
+}
+
+#[ic_cdk::query]
+pub fn get_fhir_appointment(appointment_id: u64) -> Result<String, Error> {
+    Ok(appointment_to_fhir(&get_appointment(appointment_id)?).to_string())
+}
+
+// Report and record reads are audited, hence update calls
+#[ic_cdk::update]
+pub fn get_fhir_diagnostic_report(report_id: u64) -> Result<String, Error> {
+    Ok(report_to_fhir(&get_report(report_id)?).to_string())
+}
+
+#[ic_cdk::update]
+pub fn get_fhir_medical_record(record_id: u64) -> Result<String, Error> {
+    Ok(medical_record_to_fhir(&get_medical_record(record_id)?).to_string())
+}
+
+#[ic_cdk::update]
+pub fn import_fhir_patient(resource: String) -> Result<Patient, Error> {
+    let resource = parse(&resource, "Patient")?;
+    let identity_id = parse_id(&identifier(&resource, IDENTITY_SYSTEM)?)?;
+    register_patient(identifier(&resource, USERNAME_SYSTEM)?, identity_id)
+}
+
+#[ic_cdk::update]
+pub fn import_fhir_practitioner(resource: String) -> Result<Doctor, Error> {
+    let resource = parse(&resource, "Practitioner")?;
+    add_doctor(
+        identifier(&resource, PRINCIPAL_SYSTEM)?,
+        string_at(&resource, "/name/0/given/0")?,
+        string_at(&resource, "/name/0/family")?,
+        string_at(&resource, "/birthDate").or_else(|_| extension(&resource, DOB_EXTENSION))?,
+        string_at(&resource, "/qualification/0/code/text")?,
+        parse_id(&identifier(&resource, LICENCE_SYSTEM)?)?,
+        parse_id(&identifier(&resource, ID_NO_SYSTEM)?)?,
+        string_at(&resource, "/gender").or_else(|_| extension(&resource, SEX_EXTENSION))?,
+        string_at(&resource, "/address/0/country")?,
+        string_at(&resource, "/address/0/city")?,
+        extension(&resource, TIME_ZONE_EXTENSION)?,
+    )
+}
+
+/// Books the appointment as a new request for the slot containing `start`.
+#[ic_cdk::update]
+pub fn import_fhir_appointment(resource: String) -> Result<Appointment, Error> {
+    let resource = parse(&resource, "Appointment")?;
+    add_appointment(
+        participant(&resource, "Patient")?,
+        participant(&resource, "Practitioner")?,
+        extension(&resource, PHONE_EXTENSION)?,
+        parse_instant(&string_at(&resource, "/start")?)?,
+        optional_string_at(&resource, "/reasonCode/0/text"),
+        optional_string_at(&resource, "/description"),
+        optional_string_at(&resource, "/appointmentType/text"),
+    )
+}
+
+#[ic_cdk::update]
+pub fn import_fhir_diagnostic_report(resource: String) -> Result<Report, Error> {
+    let resource = parse(&resource, "DiagnosticReport")?;
+    add_report(
+        reference(&resource, "/subject/reference", "Patient")?,
+        parse_id(&extension(&resource, APPOINTMENT_EXTENSION)?)?,
+        identifier(&resource, USERNAME_SYSTEM)?,
+        observation_value(&resource, "/contained", "symptoms")?,
+        string_at(&resource, "/conclusion")?,
+        observation_value(&resource, "/contained", "recommendations")?,
+        None,
+    )
+}
+
+/// Imports a Bundle rendered by `get_fhir_medical_record` as a new record; the
+/// Bundle id is not kept, records get their id from the canister.
+#[ic_cdk::update]
+pub fn import_fhir_medical_record(resource: String) -> Result<MedicalRecord, Error> {
+    let resource = parse(&resource, "Bundle")?;
+    let lab_results = bundle_observation(&resource, "lab-results")?;
+    create_medical_record(
+        reference(&lab_results, "/subject/reference", "Patient")?,
+        string_at(&lab_results, "/valueString")?,
+        bundle_observation(&resource, "treatment-history")
+            .and_then(|observation| string_at(&observation, "/valueString"))?,
+    )
+}
+
+pub fn patient_to_fhir(patient: &Patient) -> Value {
+    json!({
+        "resourceType": "Patient",
+        "id": patient.id.to_string(),
+        "identifier": [
+            { "system": USERNAME_SYSTEM, "value": patient.username },
+            { "system": IDENTITY_SYSTEM, "value": patient.identity_id.to_string() },
+        ],
+    })
+}
+
+/// `sex` and `dob` are free text; values that are no AdministrativeGender or
+/// readable date travel as extensions instead of `gender` and `birthDate`.
+pub fn doctor_to_fhir(doctor: &Doctor) -> Value {
+    let mut resource = json!({
+        "resourceType": "Practitioner",
+        "id": doctor.id.to_string(),
+        "identifier": [
+            { "system": PRINCIPAL_SYSTEM, "value": doctor.principal_str },
+            { "system": LICENCE_SYSTEM, "value": doctor.licence_no.to_string() },
+            { "system": ID_NO_SYSTEM, "value": doctor.id_no.to_string() },
+        ],
+        "name": [{ "family": doctor.lname, "given": [doctor.fname] }],
+        "address": [{ "city": doctor.city, "country": doctor.country }],
+        "qualification": [{ "code": { "text": doctor.specialism } }],
+        "extension": [{ "url": TIME_ZONE_EXTENSION, "valueString": doctor.time_zone }],
+    });
+
+    match administrative_gender(&doctor.sex) {
+        Some(gender) => resource["gender"] = json!(gender),
+        None => push_extension(&mut resource, SEX_EXTENSION, &doctor.sex),
+    }
+    match birth_date(&doctor.dob) {
+        Some(date) => resource["birthDate"] = json!(date),
+        None => push_extension(&mut resource, DOB_EXTENSION, &doctor.dob),
+    }
+    resource
+}
+
+pub fn appointment_to_fhir(appointment: &Appointment) -> Value {
+    let mut resource = json!({
+        "resourceType": "Appointment",
+        "id": appointment.id.to_string(),
+        "status": appointment_status(appointment.status),
+        "appointmentType": { "text": appointment.appointment_type },
+        "reasonCode": [{ "text": appointment.reason }],
+        "description": appointment.symtoms,
+        "start": format_instant(appointment.slot_start),
+        "end": format_instant(appointment.slot_end),
+        "participant": [
+            {
+                "actor": { "reference": format!("Patient/{}", appointment.patient_id) },
+                "status": "accepted",
+            },
+            {
+                "actor": { "reference": format!("Practitioner/{}", appointment.doctor_id) },
+                "status": "accepted",
+            },
+        ],
+        "extension": [{ "url": PHONE_EXTENSION, "valueString": appointment.phone_no }],
+    });
+    // An unscheduled appointment has no known window
+    if appointment.status == AppointmentStatus::Unscheduled {
+        if let Some(fields) = resource.as_object_mut() {
+            fields.remove("start");
+            fields.remove("end");
+        }
+    }
+    resource
+}
+
+/// Symptoms and recommendations become contained Observations
+/// referenced from `result`; the diagnosis is the report's conclusion.
+pub fn report_to_fhir(report: &Report) -> Value {
+    let subject = format!("Patient/{}", report.patient_id);
+    // Only a signed report is final; until then it may still change
+    let status = if report.signature.is_some() {
+        "final"
+    } else {
+        "preliminary"
+    };
+    let mut resource = json!({
+        "resourceType": "DiagnosticReport",
+        "id": report.id.to_string(),
+        "identifier": [{ "system": USERNAME_SYSTEM, "value": report.username }],
+        "status": status,
+        "code": { "text": "Clinical report" },
+        "subject": { "reference": subject },
+        "conclusion": report.diagnostic,
+        "contained": [
+            observation("symptoms", "Symptoms", &subject, &report.symptoms),
+            observation("recommendations", "Recommendations", &subject, &report.recommendations),
+        ],
+        "result": [
+            { "reference": "#symptoms" },
+            { "reference": "#recommendations" },
+        ],
+    });
+
+    if let Some(doctor_id) = report.doctor_id {
+        resource["performer"] = json!([{ "reference": format!("Practitioner/{}", doctor_id) }]);
+    }
+    if let Some(appointment_id) = report.appointment_id {
+        resource["extension"] = json!([{
+            "url": APPOINTMENT_EXTENSION,
+            "valueString": appointment_id.to_string(),
+        }]);
+    }
+    if let Some(content) = &report.multimedia_content {
+        resource["presentedForm"] = json!([{
+            "contentType": content.content_type,
+            "url": format!("blob:{}", content.blob_id),
+        }]);
+    }
+    resource
+}
+
+pub fn medical_record_to_fhir(record: &MedicalRecord) -> Value {
+    let subject = format!("Patient/{}", record.patient_id);
+    json!({
+        "resourceType": "Bundle",
+        "id": record.id.to_string(),
+        "type": "collection",
+        "entry": [
+            {
+                "resource": observation(
+                    "lab-results",
+                    "Lab results",
+                    &subject,
+                    &record.lab_results,
+                ),
+            },
+            {
+                "resource": observation(
+                    "treatment-history",
+                    "Treatment history",
+                    &subject,
+                    &record.treatment_history,
+                ),
+            },
+        ],
+    })
+}
+
+fn observation(code: &str, display: &str, subject: &str, value: &str) -> Value {
+    json!({
+        "resourceType": "Observation",
+        "id": code,
+        "status": "final",
+        "code": {
+            "coding": [{ "system": CODE_SYSTEM, "code": code }],
+            "text": display,
+        },
+        "subject": { "reference": subject },
+        "valueString": value,
+    })
+}
+
+// FHIR R4 has no in-progress appointment status; a patient being seen has arrived
+fn appointment_status(status: AppointmentStatus) -> &'static str {
+    match status {
+        AppointmentStatus::Requested => "proposed",
+        AppointmentStatus::Rescheduled => "pending",
+        AppointmentStatus::Confirmed => "booked",
+        AppointmentStatus::CheckedIn => "checked-in",
+        AppointmentStatus::InProgress => "arrived",
+        AppointmentStatus::Completed => "fulfilled",
+        AppointmentStatus::Cancelled => "cancelled",
+        AppointmentStatus::NoShow => "noshow",
+        AppointmentStatus::Unscheduled => "waitlist",
+    }
+}
+
+fn parse(resource: &str, resource_type: &str) -> Result<Value, Error> {
+    let resource: Value = serde_json::from_str(resource).map_err(|err| Error::InvalidInput {
+        msg: format!("Invalid FHIR JSON: {}", err),
+    })?;
+
+    if resource["resourceType"] != resource_type {
+        return Err(Error::InvalidInput {
+            msg: format!("Expected a FHIR {} resource", resource_type),
+        });
+    }
+    Ok(resource)
+}
+
+fn string_at(resource: &Value, pointer: &str) -> Result<String, Error> {
+    resource
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or(Error::InvalidInput {
+            msg: format!("FHIR resource lacks a string at {}", pointer),
+        })
+}
+
+fn optional_string_at(resource: &Value, pointer: &str) -> String {
+    string_at(resource, pointer).unwrap_or_default()
+}
+
+fn identifier(resource: &Value, system: &str) -> Result<String, Error> {
+    find_in(resource, "identifier", "system", system)
+        .and_then(|identifier| identifier["value"].as_str())
+        .map(str::to_string)
+        .ok_or(Error::InvalidInput {
+            msg: format!("FHIR resource lacks an identifier of system {}", system),
+        })
+}
+
+fn extension(resource: &Value, url: &str) -> Result<String, Error> {
+    find_in(resource, "extension", "url", url)
+        .and_then(|extension| extension["valueString"].as_str())
+        .map(str::to_string)
+        .ok_or(Error::InvalidInput {
+            msg: format!("FHIR resource lacks the extension {}", url),
+        })
+}
+
+// The element of the array `field` whose `key` equals `value`
+fn find_in<'a>(resource: &'a Value, field: &str, key: &str, value: &str) -> Option<&'a Value> {
+    resource[field]
+        .as_array()?
+        .iter()
+        .find(|element| element[key] == value)
+}
+
+fn participant(resource: &Value, resource_type: &str) -> Result<u64, Error> {
+    let prefix = format!("{}/", resource_type);
+    resource["participant"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|participant| participant["actor"]["reference"].as_str())
+        .find_map(|reference| reference.strip_prefix(prefix.as_str()))
+        .ok_or(Error::InvalidInput {
+            msg: format!("FHIR Appointment lacks a {} participant", resource_type),
+        })
+        .and_then(parse_id)
+}
+
+fn reference(resource: &Value, pointer: &str, resource_type: &str) -> Result<u64, Error> {
+    let reference = string_at(resource, pointer)?;
+    match reference.strip_prefix(&format!("{}/", resource_type)) {
+        Some(id) => parse_id(id),
+        None => Err(Error::InvalidInput {
+            msg: format!("Expected a reference to a {} at {}", resource_type, pointer),
+        }),
+    }
+}
+
+// Observations are matched by their `urn:helcon:code` coding
+fn is_observation(resource: &Value, code: &str) -> bool {
+    resource["resourceType"] == "Observation"
+        && find_in(&resource["code"], "coding", "system", CODE_SYSTEM)
+            .map_or(false, |coding| coding["code"] == code)
+}
+
+fn observation_value(resource: &Value, pointer: &str, code: &str) -> Result<String, Error> {
+    resource
+        .pointer(pointer)
+        .and_then(Value::as_array)
+        .and_then(|resources| resources.iter().find(|entry| is_observation(entry, code)))
+        .ok_or(Error::InvalidInput {
+            msg: format!("FHIR resource lacks the {} Observation", code),
+        })
+        .and_then(|observation| string_at(observation, "/valueString"))
+}
+
+fn bundle_observation(bundle: &Value, code: &str) -> Result<Value, Error> {
+    bundle["entry"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|entry| &entry["resource"])
+        .find(|resource| is_observation(resource, code))
+        .cloned()
+        .ok_or(Error::InvalidInput {
+            msg: format!("FHIR Bundle lacks the {} Observation", code),
+        })
+}
+
+fn push_extension(resource: &mut Value, url: &str, value: &str) {
+    if let Some(extensions) = resource["extension"].as_array_mut() {
+        extensions.push(json!({ "url": url, "valueString": value }));
+    }
+}
+
+fn parse_id(id: &str) -> Result<u64, Error> {
+    id.parse().map_err(|_| Error::InvalidInput {
+        msg: format!("Expected a numeric id, got {}", id),
+    })
+}
+
+fn format_instant(at: u64) -> String {
+    let nanos = at.min(i64::MAX as u64) as i64;
+    Utc.timestamp_nanos(nanos)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn parse_instant(instant: &str) -> Result<u64, Error> {
+    let parsed = DateTime::parse_from_rfc3339(instant).map_err(|_| Error::InvalidInput {
+        msg: format!("Expected an RFC 3339 instant, got {}", instant),
+    })?;
+    let seconds = u64::try_from(parsed.timestamp()).map_err(|_| Error::InvalidInput {
+        msg: format!("Instant {} is before the Unix epoch", instant),
+    })?;
+    seconds
+        .checked_mul(NANOS_PER_SECOND)
+        .and_then(|nanos| nanos.checked_add(u64::from(parsed.timestamp_subsec_nanos())))
+        .ok_or(Error::InvalidInput {
+            msg: format!("Instant {} is out of range", instant),
+        })
+}
+
+fn administrative_gender(sex: &str) -> Option<&'static str> {
+    match sex.trim().to_lowercase().as_str() {
+        "male" | "m" => Some("male"),
+        "female" | "f" => Some("female"),
+        "other" => Some("other"),
+        "unknown" => Some("unknown"),
+        _ => None,
+    }
+}
+
+fn birth_date(dob: &str) -> Option<String> {
+    DOB_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(dob.trim(), format).ok())
+        .map(|date| date.format("%Y-%m-%d").to_string())
+}
\ No newline at end of file
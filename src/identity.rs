@@ -1,11 +1,26 @@
 //! Identity management functionality
 
+use candid::Principal;
+
+use crate::auth::{caller_context, unauthorized};
 use crate::error::Error;
-use crate::models::{DocIdentity, Identity};
-use crate::storage::{DOCIDENTITY_STORAGE, IDENTITY_STORAGE};
+use crate::index::{
+    doctor_id_by_principal, identity_id_by_principal, index_identity, patient_id_by_identity,
+    unindex_identity,
+};
+use crate::migration::mark_schema_current;
+use crate::models::{Identity, IdentityFilter, Page, PageRequest, Role};
+use crate::pagination::paginate;
+use crate::storage::IDENTITY_STORAGE;
 use crate::utils::generate_id;
 
-// Regular Identity functions
+/// Bootstraps the role registry with a first admin, defaulting to the installer.
+#[ic_cdk::init]
+pub fn init(admin: Option<Principal>) {
+    bootstrap_admin(admin);
+    mark_schema_current();
+}
+
 #[ic_cdk::update]
 pub fn add_identity(principal: String) -> Result<Identity, Error> {
     // Validate input data
@@ -14,16 +29,22 @@ pub fn add_identity(principal: String) -> Result<Identity, Error> {
             msg: "Principal cannot be empty".to_string(),
         });
     }
+    if principal == Principal::anonymous().to_text() {
+        return Err(Error::InvalidInput {
+            msg: "The anonymous principal cannot be registered".to_string(),
+        });
+    }
 
-    // Check if the principal already exists
-    let exists = IDENTITY_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .any(|(_, identity)| identity.principal == principal)
-    });
+    // Callers may only register their own principal
+    let caller = caller_context();
+    if !caller.is_admin && caller.principal != principal {
+        return Err(unauthorized(
+            "Callers may only register their own principal",
+        ));
+    }
 
-    if exists {
+    // Check if the principal already exists
+    if find_identity_by_principal(&principal).is_some() {
         return Err(Error::AlreadyExists {
             msg: "Principal already exists".to_string(),
         });
@@ -31,16 +52,24 @@ pub fn add_identity(principal: String) -> Result<Identity, Error> {
 
     let id = generate_id();
 
-    let identity = Identity { id, principal };
+    let identity = Identity {
+        id,
+        principal,
+        roles: Vec::new(),
+    };
 
     IDENTITY_STORAGE.with(|service| service.borrow_mut().insert(id, identity.clone()));
+    index_identity(&identity);
     Ok(identity)
 }
 
 #[ic_cdk::query]
 pub fn get_identity(identity_id: u64) -> Result<Identity, Error> {
     match get_identity_by_id(&identity_id) {
-        Some(identity) => Ok(identity),
+        Some(identity) => {
+            require_owner(&identity.principal)?;
+            Ok(identity)
+        }
         None => Err(Error::NotFound {
             msg: format!("Identity with id={} not found", identity_id),
         }),
@@ -48,103 +77,116 @@ pub fn get_identity(identity_id: u64) -> Result<Identity, Error> {
 }
 
 #[ic_cdk::query]
-pub fn list_identities() -> Vec<Identity> {
-    IDENTITY_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, identity)| identity.clone())
-            .collect()
+pub fn list_identities(page: PageRequest, filter: IdentityFilter) -> Page<Identity> {
+    let caller = caller_context();
+    paginate(&IDENTITY_STORAGE, &page, |identity| {
+        filter
+            .role
+            .map_or(true, |role| identity.roles.contains(&role))
+            && (caller.is_admin || identity.principal == caller.principal)
     })
 }
 
 #[ic_cdk::update]
 pub fn delete_identity(identity_id: u64) -> Result<(), Error> {
-    match IDENTITY_STORAGE.with(|service| service.borrow_mut().remove(&identity_id)) {
-        Some(_) => Ok(()),
-        None => Err(Error::NotFound {
-            msg: format!("Report with id={} not found", identity_id),
-        }),
+    let identity = get_identity_by_id(&identity_id).ok_or(Error::NotFound {
+        msg: format!("Identity with id={} not found", identity_id),
+    })?;
+    require_owner(&identity.principal)?;
+    if identity.roles.contains(&Role::Admin) && count_admins() == 1 {
+        return Err(Error::InvalidInput {
+            msg: "Cannot delete the last admin".to_string(),
+        });
     }
+
+    // Profiles keep pointing at the identity; erasing the patient removes it
+    // along with their records
+    if let Some(patient_id) = patient_id_by_identity(identity_id) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Identity with id={} belongs to patient with id={}; erase the patient instead",
+                identity_id, patient_id
+            ),
+        });
+    }
+    if let Some(doctor_id) = doctor_id_by_principal(&identity.principal) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Identity with id={} belongs to doctor with id={}; delete the doctor first",
+                identity_id, doctor_id
+            ),
+        });
+    }
+
+    IDENTITY_STORAGE.with(|service| service.borrow_mut().remove(&identity_id));
+    unindex_identity(&identity);
+    Ok(())
 }
 
 #[ic_cdk::query]
 pub fn does_identity_exist(input_principal: String) -> bool {
-    let identities = list_identities();
-    identities
-        .into_iter()
-        .any(|identity| identity.principal == input_principal)
+    find_identity_by_principal(&input_principal).is_some()
 }
 
-// Doctor Identity functions
+// Role registry functions
 #[ic_cdk::update]
-pub fn add_docidentity(principal: String) -> Result<DocIdentity, Error> {
-    // Validate input data
+pub fn grant_role(principal: String, role: Role) -> Result<Identity, Error> {
+    caller_context().require_admin()?;
+
     if principal.is_empty() {
         return Err(Error::InvalidInput {
             msg: "Principal cannot be empty".to_string(),
         });
     }
 
-    // Check if the principal already exists
-    let exists = DOCIDENTITY_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .any(|(_, docidentity)| docidentity.principal == principal)
-    });
-
-    if exists {
-        return Err(Error::AlreadyExists {
-            msg: "Principal already exists".to_string(),
-        });
-    }
+    Ok(add_role(principal, role))
+}
 
-    let id = generate_id();
+#[ic_cdk::update]
+pub fn revoke_role(principal: String, role: Role) -> Result<Identity, Error> {
+    caller_context().require_admin()?;
 
-    let docidentity = DocIdentity { id, principal };
+    let mut identity = find_identity_by_principal(&principal).ok_or(Error::NotFound {
+        msg: format!("Identity with principal={} not found", principal),
+    })?;
 
-    DOCIDENTITY_STORAGE.with(|service| service.borrow_mut().insert(id, docidentity.clone()));
-    Ok(docidentity)
-}
+    if !identity.roles.contains(&role) {
+        return Err(Error::NotFound {
+            msg: format!("Principal {} does not hold role {:?}", principal, role),
+        });
+    }
 
-#[ic_cdk::query]
-pub fn get_docidentity(docidentity_id: u64) -> Result<DocIdentity, Error> {
-    match DOCIDENTITY_STORAGE.with(|storage| storage.borrow().get(&docidentity_id)) {
-        Some(docidentity) => Ok(docidentity.clone()),
-        None => Err(Error::NotFound {
-            msg: format!("DocIdentity with id={} not found", docidentity_id),
-        }),
+    if role == Role::Admin && count_admins() == 1 {
+        return Err(Error::InvalidInput {
+            msg: "Cannot revoke the last admin".to_string(),
+        });
     }
+
+    identity.roles.retain(|held| *held != role);
+    IDENTITY_STORAGE.with(|service| service.borrow_mut().insert(identity.id, identity.clone()));
+    Ok(identity)
 }
 
-#[ic_cdk::update]
-pub fn delete_docidentity(docidentity_id: u64) -> Result<(), Error> {
-    match DOCIDENTITY_STORAGE.with(|service| service.borrow_mut().remove(&docidentity_id)) {
-        Some(_) => Ok(()),
-        None => Err(Error::NotFound {
-            msg: format!("Report with id={} not found", docidentity_id),
-        }),
+/// Drops a role without the admin checks of `revoke_role`, for internal callers.
+pub fn remove_role(principal: &str, role: Role) {
+    if let Some(mut identity) = find_identity_by_principal(principal) {
+        identity.roles.retain(|held| *held != role);
+        IDENTITY_STORAGE.with(|service| service.borrow_mut().insert(identity.id, identity));
     }
 }
 
 #[ic_cdk::query]
-pub fn list_docidentities() -> Vec<DocIdentity> {
-    DOCIDENTITY_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, docidentity)| docidentity.clone())
-            .collect()
-    })
+pub fn get_roles(principal: String) -> Result<Vec<Role>, Error> {
+    require_owner(&principal)?;
+
+    Ok(find_identity_by_principal(&principal)
+        .map(|identity| identity.roles)
+        .unwrap_or_default())
 }
 
 #[ic_cdk::query]
-pub fn does_docidentity_exist(principal: String) -> bool {
-    let identities = list_docidentities();
-    identities
-        .into_iter()
-        .any(|identity| identity.principal == principal)
+pub fn my_roles() -> Vec<Role> {
+    caller_context().roles
 }
 
 // Helper functions
@@ -152,6 +194,53 @@ pub fn get_identity_by_id(identity_id: &u64) -> Option<Identity> {
     IDENTITY_STORAGE.with(|service| service.borrow().get(identity_id))
 }
 
-pub fn get_docidentity_by_id(docidentity_id: &u64) -> Option<DocIdentity> {
-    DOCIDENTITY_STORAGE.with(|service| service.borrow().get(docidentity_id))
+pub fn find_identity_by_principal(principal: &str) -> Option<Identity> {
+    identity_id_by_principal(principal)
+        .and_then(|identity_id| get_identity_by_id(&identity_id))
+        .filter(|identity| identity.principal == principal)
+}
+
+/// Grants a role, creating the identity if the principal is not registered yet.
+pub fn add_role(principal: String, role: Role) -> Identity {
+    let mut identity = find_identity_by_principal(&principal).unwrap_or_else(|| Identity {
+        id: generate_id(),
+        principal,
+        roles: Vec::new(),
+    });
+
+    if !identity.roles.contains(&role) {
+        identity.roles.push(role);
+    }
+
+    IDENTITY_STORAGE.with(|service| service.borrow_mut().insert(identity.id, identity.clone()));
+    index_identity(&identity);
+    identity
+}
+
+/// Grants the Admin role when no identity holds it, defaulting to the caller
+/// of the install or upgrade, i.e. a controller.
+pub fn bootstrap_admin(admin: Option<Principal>) {
+    if count_admins() == 0 {
+        let admin = admin.unwrap_or_else(ic_cdk::caller);
+        add_role(admin.to_text(), Role::Admin);
+    }
+}
+
+fn count_admins() -> usize {
+    IDENTITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, identity)| identity.roles.contains(&Role::Admin))
+            .count()
+    })
+}
+
+fn require_owner(principal: &str) -> Result<(), Error> {
+    let caller = caller_context();
+    if caller.is_admin || caller.principal == principal {
+        Ok(())
+    } else {
+        Err(unauthorized("Caller does not own this identity"))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,182 @@
+//! Immunization history on the patient chart
+
+use crate::audit::{audited, audited_create, audited_read, record_reads};
+use crate::auth::caller_context;
+use crate::error::Error;
+use crate::index::{immunization_ids_by_patient, index_immunization, unindex_immunization};
+use crate::models::{AuditAction, AuditEntity, ConsentScope, Immunization};
+use crate::patient::get_patient_by_id;
+use crate::storage::IMMUNIZATION_STORAGE;
+use crate::utils::generate_id;
+
+#[ic_cdk::update]
+pub fn add_immunization(
+    patient_id: u64,
+    vaccine: String,
+    dose_number: u32,
+    administered_at: u64,
+    lot_number: String,
+) -> Result<Immunization, Error> {
+    audited_create(
+        "add_immunization",
+        AuditEntity::Immunization,
+        |immunization: &Immunization| immunization.id,
+        || {
+            validate_immunization(&vaccine, dose_number, administered_at)?;
+
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
+
+            let caller = caller_context();
+            caller.require_chart_writer(patient_id)?;
+
+            let immunization = Immunization {
+                id: generate_id(),
+                patient_id,
+                vaccine,
+                dose_number,
+                administered_at,
+                lot_number,
+                recorded_at: ic_cdk::api::time(),
+                recorded_by: caller.principal,
+            };
+
+            insert_immunization(&immunization);
+            index_immunization(&immunization);
+            Ok(immunization)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn get_immunization(immunization_id: u64) -> Result<Immunization, Error> {
+    audited_read(
+        "get_immunization",
+        AuditEntity::Immunization,
+        immunization_id,
+        || {
+            let immunization = get_existing_immunization(immunization_id)?;
+            caller_context()
+                .require_patient_access(immunization.patient_id, ConsentScope::ReadRecords)?;
+            Ok(immunization)
+        },
+    )
+}
+
+/// Immunizations of the patient in the order they were given.
+#[ic_cdk::update]
+pub fn list_immunizations(patient_id: u64) -> Result<Vec<Immunization>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadRecords)?;
+
+    let immunizations = get_immunizations_by_patient(patient_id);
+    record_reads(
+        "list_immunizations",
+        AuditEntity::Immunization,
+        immunizations.iter().map(|immunization| immunization.id),
+    );
+    Ok(immunizations)
+}
+
+#[ic_cdk::update]
+pub fn update_immunization(
+    immunization_id: u64,
+    vaccine: String,
+    dose_number: u32,
+    administered_at: u64,
+    lot_number: String,
+) -> Result<Immunization, Error> {
+    audited(
+        "update_immunization",
+        AuditEntity::Immunization,
+        immunization_id,
+        AuditAction::Update,
+        || {
+            validate_immunization(&vaccine, dose_number, administered_at)?;
+
+            let mut immunization = get_existing_immunization(immunization_id)?;
+            caller_context().require_chart_writer(immunization.patient_id)?;
+
+            immunization.vaccine = vaccine;
+            immunization.dose_number = dose_number;
+            immunization.administered_at = administered_at;
+            immunization.lot_number = lot_number;
+            insert_immunization(&immunization);
+            Ok(immunization)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn delete_immunization(immunization_id: u64) -> Result<(), Error> {
+    audited(
+        "delete_immunization",
+        AuditEntity::Immunization,
+        immunization_id,
+        AuditAction::Delete,
+        || {
+            let immunization = get_existing_immunization(immunization_id)?;
+            caller_context().require_chart_writer(immunization.patient_id)?;
+
+            IMMUNIZATION_STORAGE.with(|service| service.borrow_mut().remove(&immunization_id));
+            unindex_immunization(&immunization);
+            Ok(())
+        },
+    )
+}
+
+pub fn get_immunization_by_id(immunization_id: &u64) -> Option<Immunization> {
+    IMMUNIZATION_STORAGE.with(|service| service.borrow().get(immunization_id))
+}
+
+pub fn get_immunizations_by_patient(patient_id: u64) -> Vec<Immunization> {
+    let mut immunizations: Vec<Immunization> = IMMUNIZATION_STORAGE.with(|service| {
+        let storage = service.borrow();
+        immunization_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|immunization_id| storage.get(immunization_id))
+            .collect()
+    });
+    immunizations.sort_by_key(|immunization| immunization.administered_at);
+    immunizations
+}
+
+fn get_existing_immunization(immunization_id: u64) -> Result<Immunization, Error> {
+    get_immunization_by_id(&immunization_id).ok_or(Error::NotFound {
+        msg: format!("Immunization with id={} not found", immunization_id),
+    })
+}
+
+fn insert_immunization(immunization: &Immunization) {
+    IMMUNIZATION_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(immunization.id, immunization.clone())
+    });
+}
+
+fn validate_immunization(
+    vaccine: &str,
+    dose_number: u32,
+    administered_at: u64,
+) -> Result<(), Error> {
+    if vaccine.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Vaccine cannot be empty".to_string(),
+        });
+    }
+    if dose_number == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Dose numbers start at 1".to_string(),
+        });
+    }
+    if administered_at > ic_cdk::api::time() {
+        return Err(Error::InvalidInput {
+            msg: "Immunizations cannot be recorded ahead of time".to_string(),
+        });
+    }
+    Ok(())
+}
\ No newline at end of file
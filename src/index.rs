@@ -0,0 +1,648 @@
+//! Secondary indexes over the record tables
+//!
+//! Link indexes store `(owner, id)` keys with empty values, so the records of
+//! one doctor or patient are a single range scan; the interaction, data and
+//! blob tables link under a hash of the drug name, username or principal
+//! instead. Name indexes map the SHA-256
+//! of a username or principal to the record id. Every write path of an indexed
+//! table keeps its indexes in step. The migration pass, or `rebuild_indexes`,
+//! fills them from the tables in batches; until it completes, lookups scan the
+//! tables instead.
+
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::auth::caller_context;
+use crate::erasure::ERASED_PATIENT_ID;
+use crate::error::Error;
+use crate::migration::{indexes_complete, restart_migration};
+use crate::models::{
+    Allergy, Appointment, Availability, AvailabilityRule, Blob, ConsentGrant, Data, Doctor,
+    Identity, Immunization, IndexKey, Interaction, InteractionTarget, LabResult, MedicalRecord,
+    Message, MigrationState, MigrationTable, NameKey, Patient, Prescription, Problem, Report,
+    Vital,
+};
+use crate::storage::{
+    Memory, ALLERGIES_BY_PATIENT, ALLERGY_STORAGE, APPOINTMENTS_BY_DOCTOR, APPOINTMENTS_BY_PATIENT,
+    APPOINTMENT_STORAGE, AVAILABILITY_BY_DOCTOR, AVAILABILITY_RULES_BY_DOCTOR,
+    AVAILABILITY_RULE_STORAGE, AVAILABILITY_STORAGE, BLOBS_BY_OWNER, BLOB_STORAGE,
+    CONSENTS_BY_PATIENT, CONSENT_STORAGE, DATA_BY_PATIENT, DATA_STORAGE, DOCTOR_BY_PRINCIPAL,
+    DOCTOR_STORAGE, IDENTITY_BY_PRINCIPAL, IDENTITY_STORAGE, IMMUNIZATIONS_BY_PATIENT,
+    IMMUNIZATION_STORAGE, INTERACTIONS_BY_DRUG, INTERACTION_STORAGE, LAB_RESULTS_BY_PATIENT,
+    LAB_RESULT_STORAGE, MEDICAL_RECORDS_BY_PATIENT, MEDICAL_RECORD_STORAGE, MESSAGES_BY_PARTY,
+    MESSAGE_STORAGE, PATIENT_BY_IDENTITY, PATIENT_BY_USERNAME, PATIENT_STORAGE,
+    PRESCRIPTIONS_BY_PATIENT, PRESCRIPTION_STORAGE, PROBLEMS_BY_PATIENT, PROBLEM_STORAGE,
+    REPORTS_BY_APPOINTMENT, REPORTS_BY_DOCTOR, REPORTS_BY_PATIENT, REPORT_STORAGE,
+    VITALS_BY_PATIENT, VITAL_STORAGE,
+};
+
+type Index<K, V> = &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>;
+
+/// Clears every index and indexes the record tables again in migration
+/// batches; lookups scan the tables until the pass completes.
+#[ic_cdk::update]
+pub fn rebuild_indexes() -> Result<MigrationState, Error> {
+    caller_context().require_admin()?;
+    Ok(restart_migration(MigrationTable::Indexes))
+}
+
+/// Removes up to `limit` index entries, returning how many were removed and
+/// `Some(0)` while entries may remain.
+pub fn clear_indexes(limit: u64) -> (u64, Option<u64>) {
+    let mut removed = 0;
+    for index in link_indexes() {
+        removed += clear(index, limit - removed);
+    }
+    for index in name_indexes() {
+        removed += clear(index, limit - removed);
+    }
+    removed += clear(&PATIENT_BY_IDENTITY, limit - removed);
+
+    (removed, if removed == limit { Some(0) } else { None })
+}
+
+// Appointments anonymized by an erasure stay in the doctor's history only
+pub fn index_appointment(appointment: &Appointment) {
+    link(
+        &APPOINTMENTS_BY_DOCTOR,
+        appointment.doctor_id,
+        appointment.id,
+    );
+    if appointment.patient_id != ERASED_PATIENT_ID {
+        link(
+            &APPOINTMENTS_BY_PATIENT,
+            appointment.patient_id,
+            appointment.id,
+        );
+    }
+}
+
+pub fn unindex_appointment(appointment: &Appointment) {
+    unlink(
+        &APPOINTMENTS_BY_DOCTOR,
+        appointment.doctor_id,
+        appointment.id,
+    );
+    unlink(
+        &APPOINTMENTS_BY_PATIENT,
+        appointment.patient_id,
+        appointment.id,
+    );
+}
+
+pub fn appointment_ids_by_doctor(doctor_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&APPOINTMENTS_BY_DOCTOR, doctor_id)
+    } else {
+        scan_ids(&APPOINTMENT_STORAGE, |appointment| {
+            appointment.doctor_id == doctor_id
+        })
+    }
+}
+
+pub fn appointment_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&APPOINTMENTS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&APPOINTMENT_STORAGE, |appointment| {
+            appointment.patient_id == patient_id
+        })
+    }
+}
+
+pub fn index_availability(availability: &Availability) {
+    link(
+        &AVAILABILITY_BY_DOCTOR,
+        availability.doctor_id,
+        availability.id,
+    );
+}
+
+pub fn unindex_availability(availability: &Availability) {
+    unlink(
+        &AVAILABILITY_BY_DOCTOR,
+        availability.doctor_id,
+        availability.id,
+    );
+}
+
+pub fn availability_ids_by_doctor(doctor_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&AVAILABILITY_BY_DOCTOR, doctor_id)
+    } else {
+        scan_ids(&AVAILABILITY_STORAGE, |availability| {
+            availability.doctor_id == doctor_id
+        })
+    }
+}
+
+pub fn index_availability_rule(rule: &AvailabilityRule) {
+    link(&AVAILABILITY_RULES_BY_DOCTOR, rule.doctor_id, rule.id);
+}
+
+pub fn unindex_availability_rule(rule: &AvailabilityRule) {
+    unlink(&AVAILABILITY_RULES_BY_DOCTOR, rule.doctor_id, rule.id);
+}
+
+pub fn availability_rule_ids_by_doctor(doctor_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&AVAILABILITY_RULES_BY_DOCTOR, doctor_id)
+    } else {
+        scan_ids(&AVAILABILITY_RULE_STORAGE, |rule| {
+            rule.doctor_id == doctor_id
+        })
+    }
+}
+
+pub fn index_patient(patient: &Patient) {
+    PATIENT_BY_USERNAME.with(|index| {
+        index
+            .borrow_mut()
+            .insert(name_key(&patient.username), patient.id)
+    });
+    PATIENT_BY_IDENTITY.with(|index| index.borrow_mut().insert(patient.identity_id, patient.id));
+}
+
+pub fn unindex_patient(patient: &Patient) {
+    unname(&PATIENT_BY_USERNAME, &patient.username, patient.id);
+    PATIENT_BY_IDENTITY.with(|index| {
+        let mut index = index.borrow_mut();
+        if index.get(&patient.identity_id) == Some(patient.id) {
+            index.remove(&patient.identity_id);
+        }
+    });
+}
+
+pub fn patient_id_by_username(username: &str) -> Option<u64> {
+    if indexes_complete() {
+        PATIENT_BY_USERNAME.with(|index| index.borrow().get(&name_key(username)))
+    } else {
+        scan_id(&PATIENT_STORAGE, |patient| patient.username == username)
+    }
+}
+
+pub fn patient_id_by_identity(identity_id: u64) -> Option<u64> {
+    if indexes_complete() {
+        PATIENT_BY_IDENTITY.with(|index| index.borrow().get(&identity_id))
+    } else {
+        scan_id(&PATIENT_STORAGE, |patient| {
+            patient.identity_id == identity_id
+        })
+    }
+}
+
+pub fn index_identity(identity: &Identity) {
+    IDENTITY_BY_PRINCIPAL.with(|index| {
+        index
+            .borrow_mut()
+            .insert(name_key(&identity.principal), identity.id)
+    });
+}
+
+pub fn unindex_identity(identity: &Identity) {
+    unname(&IDENTITY_BY_PRINCIPAL, &identity.principal, identity.id);
+}
+
+pub fn identity_id_by_principal(principal: &str) -> Option<u64> {
+    if indexes_complete() {
+        IDENTITY_BY_PRINCIPAL.with(|index| index.borrow().get(&name_key(principal)))
+    } else {
+        scan_id(&IDENTITY_STORAGE, |identity| {
+            identity.principal == principal
+        })
+    }
+}
+
+pub fn index_doctor(doctor: &Doctor) {
+    DOCTOR_BY_PRINCIPAL.with(|index| {
+        index
+            .borrow_mut()
+            .insert(name_key(&doctor.principal_str), doctor.id)
+    });
+}
+
+pub fn unindex_doctor(doctor: &Doctor) {
+    unname(&DOCTOR_BY_PRINCIPAL, &doctor.principal_str, doctor.id);
+}
+
+pub fn doctor_id_by_principal(principal: &str) -> Option<u64> {
+    if indexes_complete() {
+        DOCTOR_BY_PRINCIPAL.with(|index| index.borrow().get(&name_key(principal)))
+    } else {
+        scan_id(&DOCTOR_STORAGE, |doctor| doctor.principal_str == principal)
+    }
+}
+
+pub fn index_consent(grant: &ConsentGrant) {
+    link(&CONSENTS_BY_PATIENT, grant.patient_id, grant.id);
+}
+
+pub fn unindex_consent(grant: &ConsentGrant) {
+    unlink(&CONSENTS_BY_PATIENT, grant.patient_id, grant.id);
+}
+
+pub fn consent_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&CONSENTS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&CONSENT_STORAGE, |record| record.patient_id == patient_id)
+    }
+}
+
+pub fn index_prescription(prescription: &Prescription) {
+    link(
+        &PRESCRIPTIONS_BY_PATIENT,
+        prescription.patient_id,
+        prescription.id,
+    );
+}
+
+pub fn unindex_prescription(prescription: &Prescription) {
+    unlink(
+        &PRESCRIPTIONS_BY_PATIENT,
+        prescription.patient_id,
+        prescription.id,
+    );
+}
+
+pub fn prescription_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&PRESCRIPTIONS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&PRESCRIPTION_STORAGE, |record| {
+            record.patient_id == patient_id
+        })
+    }
+}
+
+pub fn index_allergy(allergy: &Allergy) {
+    link(&ALLERGIES_BY_PATIENT, allergy.patient_id, allergy.id);
+}
+
+pub fn unindex_allergy(allergy: &Allergy) {
+    unlink(&ALLERGIES_BY_PATIENT, allergy.patient_id, allergy.id);
+}
+
+pub fn allergy_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&ALLERGIES_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&ALLERGY_STORAGE, |record| record.patient_id == patient_id)
+    }
+}
+
+pub fn index_immunization(immunization: &Immunization) {
+    link(
+        &IMMUNIZATIONS_BY_PATIENT,
+        immunization.patient_id,
+        immunization.id,
+    );
+}
+
+pub fn unindex_immunization(immunization: &Immunization) {
+    unlink(
+        &IMMUNIZATIONS_BY_PATIENT,
+        immunization.patient_id,
+        immunization.id,
+    );
+}
+
+pub fn immunization_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&IMMUNIZATIONS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&IMMUNIZATION_STORAGE, |record| {
+            record.patient_id == patient_id
+        })
+    }
+}
+
+pub fn index_problem(problem: &Problem) {
+    link(&PROBLEMS_BY_PATIENT, problem.patient_id, problem.id);
+}
+
+pub fn unindex_problem(problem: &Problem) {
+    unlink(&PROBLEMS_BY_PATIENT, problem.patient_id, problem.id);
+}
+
+pub fn problem_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&PROBLEMS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&PROBLEM_STORAGE, |record| record.patient_id == patient_id)
+    }
+}
+
+pub fn index_lab_result(result: &LabResult) {
+    link(&LAB_RESULTS_BY_PATIENT, result.patient_id, result.id);
+}
+
+pub fn unindex_lab_result(result: &LabResult) {
+    unlink(&LAB_RESULTS_BY_PATIENT, result.patient_id, result.id);
+}
+
+pub fn lab_result_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&LAB_RESULTS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&LAB_RESULT_STORAGE, |record| {
+            record.patient_id == patient_id
+        })
+    }
+}
+
+pub fn index_medical_record(record: &MedicalRecord) {
+    link(&MEDICAL_RECORDS_BY_PATIENT, record.patient_id, record.id);
+}
+
+pub fn unindex_medical_record(record: &MedicalRecord) {
+    unlink(&MEDICAL_RECORDS_BY_PATIENT, record.patient_id, record.id);
+}
+
+pub fn medical_record_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&MEDICAL_RECORDS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&MEDICAL_RECORD_STORAGE, |record| {
+            record.patient_id == patient_id
+        })
+    }
+}
+
+// Legacy reports name no doctor or visit and are linked to their patient only
+pub fn index_report(report: &Report) {
+    link(&REPORTS_BY_PATIENT, report.patient_id, report.id);
+    if let Some(doctor_id) = report.doctor_id {
+        link(&REPORTS_BY_DOCTOR, doctor_id, report.id);
+    }
+    if let Some(appointment_id) = report.appointment_id {
+        link(&REPORTS_BY_APPOINTMENT, appointment_id, report.id);
+    }
+}
+
+pub fn unindex_report(report: &Report) {
+    unlink(&REPORTS_BY_PATIENT, report.patient_id, report.id);
+    if let Some(doctor_id) = report.doctor_id {
+        unlink(&REPORTS_BY_DOCTOR, doctor_id, report.id);
+    }
+    if let Some(appointment_id) = report.appointment_id {
+        unlink(&REPORTS_BY_APPOINTMENT, appointment_id, report.id);
+    }
+}
+
+pub fn report_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&REPORTS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&REPORT_STORAGE, |record| record.patient_id == patient_id)
+    }
+}
+
+pub fn report_ids_by_doctor(doctor_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&REPORTS_BY_DOCTOR, doctor_id)
+    } else {
+        scan_ids(&REPORT_STORAGE, |report| {
+            report.doctor_id == Some(doctor_id)
+        })
+    }
+}
+
+pub fn report_ids_by_appointment(appointment_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&REPORTS_BY_APPOINTMENT, appointment_id)
+    } else {
+        scan_ids(&REPORT_STORAGE, |report| {
+            report.appointment_id == Some(appointment_id)
+        })
+    }
+}
+
+pub fn index_vital(vital: &Vital) {
+    link(&VITALS_BY_PATIENT, vital.patient_id, vital.id);
+}
+
+pub fn unindex_vital(vital: &Vital) {
+    unlink(&VITALS_BY_PATIENT, vital.patient_id, vital.id);
+}
+
+pub fn vital_ids_by_patient(patient_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&VITALS_BY_PATIENT, patient_id)
+    } else {
+        scan_ids(&VITAL_STORAGE, |record| record.patient_id == patient_id)
+    }
+}
+
+// Drug-drug interactions are found from either drug
+pub fn index_interaction(interaction: &Interaction) {
+    link(
+        &INTERACTIONS_BY_DRUG,
+        drug_key(&interaction.drug),
+        interaction.id,
+    );
+    if let InteractionTarget::Drug(other) = &interaction.target {
+        link(&INTERACTIONS_BY_DRUG, drug_key(other), interaction.id);
+    }
+}
+
+pub fn unindex_interaction(interaction: &Interaction) {
+    unlink(
+        &INTERACTIONS_BY_DRUG,
+        drug_key(&interaction.drug),
+        interaction.id,
+    );
+    if let InteractionTarget::Drug(other) = &interaction.target {
+        unlink(&INTERACTIONS_BY_DRUG, drug_key(other), interaction.id);
+    }
+}
+
+/// Candidate interactions of the drug; hash collisions are possible, so
+/// callers compare the names.
+pub fn interaction_ids_by_drug(drug: &str) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&INTERACTIONS_BY_DRUG, drug_key(drug))
+    } else {
+        let key = drug_key(drug);
+        scan_ids(&INTERACTION_STORAGE, |interaction| {
+            let other = match &interaction.target {
+                InteractionTarget::Drug(other) => Some(drug_key(other)),
+                _ => None,
+            };
+            drug_key(&interaction.drug) == key || other == Some(key)
+        })
+    }
+}
+
+pub fn index_data(data: &Data) {
+    link(&DATA_BY_PATIENT, hash_key(&data.patient_username), data.id);
+}
+
+pub fn unindex_data(data: &Data) {
+    unlink(&DATA_BY_PATIENT, hash_key(&data.patient_username), data.id);
+}
+
+/// Candidate data records of the patient; hash collisions are possible, so
+/// callers compare the usernames.
+pub fn data_ids_by_patient(username: &str) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&DATA_BY_PATIENT, hash_key(username))
+    } else {
+        scan_ids(&DATA_STORAGE, |data| data.patient_username == username)
+    }
+}
+
+// Messages are found from either party
+pub fn index_message(message: &Message) {
+    link(&MESSAGES_BY_PARTY, message.sender_id, message.id);
+    link(&MESSAGES_BY_PARTY, message.receiver_id, message.id);
+}
+
+pub fn unindex_message(message: &Message) {
+    unlink(&MESSAGES_BY_PARTY, message.sender_id, message.id);
+    unlink(&MESSAGES_BY_PARTY, message.receiver_id, message.id);
+}
+
+pub fn message_ids_by_party(party_id: u64) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&MESSAGES_BY_PARTY, party_id)
+    } else {
+        scan_ids(&MESSAGE_STORAGE, |message| {
+            message.sender_id == party_id || message.receiver_id == party_id
+        })
+    }
+}
+
+pub fn index_blob(blob: &Blob) {
+    link(&BLOBS_BY_OWNER, hash_key(&blob.owner), blob.id);
+}
+
+pub fn unindex_blob(blob: &Blob) {
+    unlink(&BLOBS_BY_OWNER, hash_key(&blob.owner), blob.id);
+}
+
+/// Candidate blobs uploaded by the principal; hash collisions are possible,
+/// so callers compare the owners.
+pub fn blob_ids_by_owner(principal: &str) -> Vec<u64> {
+    if indexes_complete() {
+        linked_ids(&BLOBS_BY_OWNER, hash_key(principal))
+    } else {
+        scan_ids(&BLOB_STORAGE, |blob| blob.owner == principal)
+    }
+}
+
+fn link(index: Index<IndexKey, ()>, owner: u64, id: u64) {
+    index.with(|index| index.borrow_mut().insert(IndexKey { owner, id }, ()));
+}
+
+fn unlink(index: Index<IndexKey, ()>, owner: u64, id: u64) {
+    index.with(|index| index.borrow_mut().remove(&IndexKey { owner, id }));
+}
+
+fn linked_ids(index: Index<IndexKey, ()>, owner: u64) -> Vec<u64> {
+    let range = IndexKey { owner, id: 0 }..=IndexKey {
+        owner,
+        id: u64::MAX,
+    };
+    index.with(|index| index.borrow().range(range).map(|(key, _)| key.id).collect())
+}
+
+// Only drops the entry if it still points at `id`
+fn unname(index: Index<NameKey, u64>, name: &str, id: u64) {
+    let key = name_key(name);
+    index.with(|index| {
+        let mut index = index.borrow_mut();
+        if index.get(&key) == Some(id) {
+            index.remove(&key);
+        }
+    });
+}
+
+fn name_key(name: &str) -> NameKey {
+    NameKey(Sha256::digest(name.as_bytes()).into())
+}
+
+// Case-insensitive
+fn drug_key(drug: &str) -> u64 {
+    hash_key(&drug.trim().to_lowercase())
+}
+
+// The first eight bytes of the hashed name
+fn hash_key(name: &str) -> u64 {
+    let digest = Sha256::digest(name.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+fn link_indexes() -> [Index<IndexKey, ()>; 19] {
+    [
+        &APPOINTMENTS_BY_DOCTOR,
+        &APPOINTMENTS_BY_PATIENT,
+        &AVAILABILITY_BY_DOCTOR,
+        &AVAILABILITY_RULES_BY_DOCTOR,
+        &CONSENTS_BY_PATIENT,
+        &PRESCRIPTIONS_BY_PATIENT,
+        &ALLERGIES_BY_PATIENT,
+        &INTERACTIONS_BY_DRUG,
+        &IMMUNIZATIONS_BY_PATIENT,
+        &PROBLEMS_BY_PATIENT,
+        &LAB_RESULTS_BY_PATIENT,
+        &VITALS_BY_PATIENT,
+        &MEDICAL_RECORDS_BY_PATIENT,
+        &REPORTS_BY_PATIENT,
+        &REPORTS_BY_DOCTOR,
+        &REPORTS_BY_APPOINTMENT,
+        &DATA_BY_PATIENT,
+        &MESSAGES_BY_PARTY,
+        &BLOBS_BY_OWNER,
+    ]
+}
+
+fn name_indexes() -> [Index<NameKey, u64>; 3] {
+    [
+        &PATIENT_BY_USERNAME,
+        &IDENTITY_BY_PRINCIPAL,
+        &DOCTOR_BY_PRINCIPAL,
+    ]
+}
+
+fn clear<K: BoundedStorable + Ord + Clone, V: BoundedStorable>(
+    index: Index<K, V>,
+    limit: u64,
+) -> u64 {
+    index.with(|index| {
+        let mut index = index.borrow_mut();
+        let keys: Vec<K> = index
+            .iter()
+            .take(limit as usize)
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            index.remove(key);
+        }
+        keys.len() as u64
+    })
+}
+
+// Lookups made while the indexes are incomplete
+fn scan_ids<V: BoundedStorable>(table: Index<u64, V>, matches: impl Fn(&V) -> bool) -> Vec<u64> {
+    table.with(|table| {
+        table
+            .borrow()
+            .iter()
+            .filter(|(_, record)| matches(record))
+            .map(|(id, _)| id)
+            .collect()
+    })
+}
+
+fn scan_id<V: BoundedStorable>(table: Index<u64, V>, matches: impl Fn(&V) -> bool) -> Option<u64> {
+    table.with(|table| {
+        table
+            .borrow()
+            .iter()
+            .find(|(_, record)| matches(record))
+            .map(|(id, _)| id)
+    })
+}
\ No newline at end of file
@@ -0,0 +1,290 @@
+//! Drug-drug and drug-allergy interaction checks
+//!
+//! Admins upload the interaction table; prescribing screens every medication
+//! line against the other lines, the patient's active prescriptions and their
+//! recorded allergies. Interactions up to `Major` come back as warnings,
+//! `Contraindicated` ones block the prescription. A medication that is itself
+//! a recorded allergen is flagged without needing a table row.
+
+use crate::allergy::get_allergies_by_patient;
+use crate::audit::audited_read;
+use crate::auth::caller_context;
+use crate::error::Error;
+use crate::index::{
+    index_interaction, interaction_ids_by_drug, prescription_ids_by_patient, unindex_interaction,
+};
+use crate::models::{
+    AllergySeverity, AuditEntity, Interaction, InteractionSeverity, InteractionTarget,
+    InteractionWarning, MedicationLine, Page, PageRequest, PrescriptionStatus,
+};
+use crate::pagination::paginate;
+use crate::prescription::{get_prescription_by_id, MAX_MEDICATION_LEN};
+use crate::storage::INTERACTION_STORAGE;
+use crate::utils::generate_id;
+
+/// Largest number of rows accepted by one `upload_interactions` call.
+pub const MAX_INTERACTION_BATCH: usize = 500;
+
+/// Drug and target names share the medication limit of prescriptions; with
+/// the description limit a row stays within its `MAX_SIZE` of 1024 bytes.
+pub const MAX_INTERACTION_DESCRIPTION_LEN: usize = 512;
+
+/// Adds rows to the interaction table. Ids are assigned here; a row for a
+/// drug pair or drug and allergen already in the table replaces it.
+#[ic_cdk::update]
+pub fn upload_interactions(interactions: Vec<Interaction>) -> Result<Vec<u64>, Error> {
+    caller_context().require_admin()?;
+
+    if interactions.is_empty() || interactions.len() > MAX_INTERACTION_BATCH {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Upload between 1 and {} interactions at a time",
+                MAX_INTERACTION_BATCH
+            ),
+        });
+    }
+    for interaction in &interactions {
+        let target = match &interaction.target {
+            InteractionTarget::Drug(name) | InteractionTarget::Allergen(name) => name,
+        };
+        if interaction.drug.trim().is_empty() || target.trim().is_empty() {
+            return Err(Error::InvalidInput {
+                msg: "Interactions need a drug and a target".to_string(),
+            });
+        }
+        if interaction.drug.len() > MAX_MEDICATION_LEN
+            || target.len() > MAX_MEDICATION_LEN
+            || interaction.description.len() > MAX_INTERACTION_DESCRIPTION_LEN
+        {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Drug and target are limited to {} bytes and the description to {}",
+                    MAX_MEDICATION_LEN, MAX_INTERACTION_DESCRIPTION_LEN
+                ),
+            });
+        }
+    }
+
+    let mut ids = Vec::with_capacity(interactions.len());
+    for mut interaction in interactions {
+        interaction.id = match find_existing(&interaction) {
+            Some(existing) => {
+                unindex_interaction(&existing);
+                existing.id
+            }
+            None => generate_id(),
+        };
+        INTERACTION_STORAGE.with(|service| {
+            service
+                .borrow_mut()
+                .insert(interaction.id, interaction.clone())
+        });
+        index_interaction(&interaction);
+        ids.push(interaction.id);
+    }
+    Ok(ids)
+}
+
+#[ic_cdk::update]
+pub fn delete_interaction(interaction_id: u64) -> Result<(), Error> {
+    caller_context().require_admin()?;
+
+    match INTERACTION_STORAGE.with(|service| service.borrow_mut().remove(&interaction_id)) {
+        Some(interaction) => {
+            unindex_interaction(&interaction);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Interaction with id={} not found", interaction_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+pub fn list_interactions(page: PageRequest) -> Page<Interaction> {
+    paginate(&INTERACTION_STORAGE, &page, |_| true)
+}
+
+/// Screens medication lines for the patient without issuing anything,
+/// including interactions that would block the prescription.
+// The warnings name active prescriptions and allergies, so this is audited
+#[ic_cdk::update]
+pub fn check_interactions(
+    patient_id: u64,
+    lines: Vec<MedicationLine>,
+) -> Result<Vec<InteractionWarning>, Error> {
+    audited_read(
+        "check_interactions",
+        AuditEntity::Patient,
+        patient_id,
+        || {
+            caller_context().require_report_writer(patient_id)?;
+            Ok(find_interactions(patient_id, &lines))
+        },
+    )
+}
+
+/// Interactions of the medication lines, failing with `InteractionConflict`
+/// when any of them blocks prescribing.
+pub fn screen_interactions(
+    patient_id: u64,
+    lines: &[MedicationLine],
+) -> Result<Vec<InteractionWarning>, Error> {
+    let warnings = find_interactions(patient_id, lines);
+    let blocking: Vec<String> = warnings
+        .iter()
+        .filter(|warning| warning.severity >= InteractionSeverity::Contraindicated)
+        .map(|warning| format!("{}: {}", warning.medication, warning.description))
+        .collect();
+
+    if blocking.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(Error::InteractionConflict {
+            msg: format!("Contraindicated: {}", blocking.join("; ")),
+        })
+    }
+}
+
+pub fn find_interactions(patient_id: u64, lines: &[MedicationLine]) -> Vec<InteractionWarning> {
+    let now = ic_cdk::api::time();
+    let active: Vec<(u64, Vec<String>)> = prescription_ids_by_patient(patient_id)
+        .iter()
+        .filter_map(get_prescription_by_id)
+        .filter(|prescription| {
+            prescription.status == PrescriptionStatus::Active && now < prescription.expires_at
+        })
+        .map(|prescription| {
+            let medications = prescription
+                .lines
+                .into_iter()
+                .map(|line| line.medication)
+                .collect();
+            (prescription.id, medications)
+        })
+        .collect();
+    let allergies = get_allergies_by_patient(patient_id);
+
+    let mut warnings: Vec<InteractionWarning> = Vec::new();
+    let mut warn = |warning: InteractionWarning| {
+        let duplicate = warnings.iter().any(|seen| {
+            seen.interaction_id == warning.interaction_id
+                && seen.prescription_id == warning.prescription_id
+                && seen.allergy_id == warning.allergy_id
+                && same_name(&seen.medication, &warning.medication)
+        });
+        if !duplicate {
+            warnings.push(warning);
+        }
+    };
+
+    for (index, line) in lines.iter().enumerate() {
+        let medication = &line.medication;
+
+        for allergy in &allergies {
+            if same_name(&allergy.substance, medication) {
+                warn(InteractionWarning {
+                    interaction_id: None,
+                    medication: medication.clone(),
+                    target: InteractionTarget::Allergen(allergy.substance.clone()),
+                    severity: allergy_severity(allergy.severity),
+                    description: format!("Patient is allergic to {}", allergy.substance),
+                    prescription_id: None,
+                    allergy_id: Some(allergy.id),
+                });
+            }
+        }
+
+        let candidates = interaction_ids_by_drug(medication)
+            .iter()
+            .filter_map(get_interaction_by_id)
+            .filter_map(|interaction| {
+                other_side(&interaction, medication).map(|target| (interaction, target))
+            })
+            .collect::<Vec<_>>();
+        for (interaction, target) in candidates {
+            let warning = |prescription_id, allergy_id| InteractionWarning {
+                interaction_id: Some(interaction.id),
+                medication: medication.clone(),
+                target: target.clone(),
+                severity: interaction.severity,
+                description: interaction.description.clone(),
+                prescription_id,
+                allergy_id,
+            };
+
+            match &target {
+                InteractionTarget::Drug(other) => {
+                    // Pairs within the prescription are reported once, from the first line
+                    if lines[index + 1..]
+                        .iter()
+                        .any(|later| same_name(&later.medication, other))
+                    {
+                        warn(warning(None, None));
+                    }
+                    for (prescription_id, medications) in &active {
+                        if medications.iter().any(|name| same_name(name, other)) {
+                            warn(warning(Some(*prescription_id), None));
+                        }
+                    }
+                }
+                InteractionTarget::Allergen(allergen) => {
+                    for allergy in &allergies {
+                        if same_name(&allergy.substance, allergen) {
+                            warn(warning(None, Some(allergy.id)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    warnings.sort_by(|a, b| b.severity.cmp(&a.severity));
+    warnings
+}
+
+pub fn get_interaction_by_id(interaction_id: &u64) -> Option<Interaction> {
+    INTERACTION_STORAGE.with(|service| service.borrow().get(interaction_id))
+}
+
+// What the medication interacts with under this row, or `None` when the
+// index matched on a hash collision
+fn other_side(interaction: &Interaction, medication: &str) -> Option<InteractionTarget> {
+    if same_name(&interaction.drug, medication) {
+        return Some(interaction.target.clone());
+    }
+    match &interaction.target {
+        InteractionTarget::Drug(other) if same_name(other, medication) => {
+            Some(InteractionTarget::Drug(interaction.drug.clone()))
+        }
+        _ => None,
+    }
+}
+
+fn find_existing(interaction: &Interaction) -> Option<Interaction> {
+    interaction_ids_by_drug(&interaction.drug)
+        .iter()
+        .filter_map(get_interaction_by_id)
+        .find(
+            |existing| match (other_side(existing, &interaction.drug), &interaction.target) {
+                (Some(InteractionTarget::Drug(a)), InteractionTarget::Drug(b))
+                | (Some(InteractionTarget::Allergen(a)), InteractionTarget::Allergen(b)) => {
+                    same_name(&a, b)
+                }
+                _ => false,
+            },
+        )
+}
+
+// Prescribing a recorded allergen is only blocked for severe allergies
+fn allergy_severity(severity: AllergySeverity) -> InteractionSeverity {
+    match severity {
+        AllergySeverity::Mild => InteractionSeverity::Moderate,
+        AllergySeverity::Moderate => InteractionSeverity::Major,
+        AllergySeverity::Severe => InteractionSeverity::Contraindicated,
+    }
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim().to_lowercase() == b.trim().to_lowercase()
+}
\ No newline at end of file
@@ -0,0 +1,349 @@
+//! Structured lab results
+//!
+//! Each result carries the reference range it was measured against, so the
+//! abnormal or critical flag is derived once, when the result is recorded, and
+//! stays correct if a lab later changes its ranges. Qualitative results carry
+//! their text and no range or flag. A patient's results for one test code,
+//! oldest first, form the time series doctors trend.
+
+use crate::audit::{audited, audited_create, audited_read, record_reads};
+use crate::auth::caller_context;
+use crate::error::Error;
+use crate::index::{index_lab_result, lab_result_ids_by_patient, unindex_lab_result};
+use crate::models::{
+    AuditAction, AuditEntity, ConsentScope, LabFlag, LabResult, LabResultFilter, LabValue,
+    ReferenceRange,
+};
+use crate::patient::get_patient_by_id;
+use crate::storage::LAB_RESULT_STORAGE;
+use crate::utils::generate_id;
+
+/// Limit of the test code, name, unit and text value. Together they keep a
+/// result within its `MAX_SIZE` of 1024 bytes.
+pub const MAX_LAB_TEXT_LEN: usize = 128;
+
+#[ic_cdk::update]
+pub fn add_lab_result(
+    patient_id: u64,
+    test_code: String,
+    test_name: String,
+    value: LabValue,
+    unit: String,
+    reference_range: Option<ReferenceRange>,
+    collected_at: u64,
+) -> Result<LabResult, Error> {
+    audited_create(
+        "add_lab_result",
+        AuditEntity::LabResult,
+        |result: &LabResult| result.id,
+        || {
+            validate_test_code(&test_code)?;
+            if test_name.trim().is_empty() {
+                return Err(Error::InvalidInput {
+                    msg: "Test name cannot be empty".to_string(),
+                });
+            }
+            if test_code.len() > MAX_LAB_TEXT_LEN
+                || test_name.len() > MAX_LAB_TEXT_LEN
+                || unit.len() > MAX_LAB_TEXT_LEN
+            {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Test code, name and unit are limited to {} bytes",
+                        MAX_LAB_TEXT_LEN
+                    ),
+                });
+            }
+            let flag = match (&value, &reference_range) {
+                (LabValue::Numeric(value), Some(range)) => {
+                    if !value.is_finite() {
+                        return Err(Error::InvalidInput {
+                            msg: "Value must be a finite number".to_string(),
+                        });
+                    }
+                    validate_range(range)?;
+                    Some(flag_for(*value, range))
+                }
+                (LabValue::Numeric(_), None) => {
+                    return Err(Error::InvalidInput {
+                        msg: "Numeric results need a reference range".to_string(),
+                    });
+                }
+                (LabValue::Text(text), None) => {
+                    if text.trim().is_empty() || text.len() > MAX_LAB_TEXT_LEN {
+                        return Err(Error::InvalidInput {
+                            msg: format!(
+                                "Text results need between 1 and {} bytes",
+                                MAX_LAB_TEXT_LEN
+                            ),
+                        });
+                    }
+                    None
+                }
+                (LabValue::Text(_), Some(_)) => {
+                    return Err(Error::InvalidInput {
+                        msg: "Text results cannot have a reference range".to_string(),
+                    });
+                }
+            };
+
+            let now = ic_cdk::api::time();
+            if collected_at > now {
+                return Err(Error::InvalidInput {
+                    msg: "Collection time cannot be in the future".to_string(),
+                });
+            }
+
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
+
+            let caller = caller_context();
+            caller.require_record_writer(patient_id)?;
+
+            let result = LabResult {
+                id: generate_id(),
+                patient_id,
+                test_code: test_code.trim().to_string(),
+                test_name,
+                value,
+                unit,
+                flag,
+                reference_range,
+                collected_at,
+                recorded_at: now,
+                recorded_by: caller.principal,
+            };
+
+            LAB_RESULT_STORAGE
+                .with(|service| service.borrow_mut().insert(result.id, result.clone()));
+            index_lab_result(&result);
+            Ok(result)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn get_lab_result(result_id: u64) -> Result<LabResult, Error> {
+    audited_read("get_lab_result", AuditEntity::LabResult, result_id, || {
+        let result = get_existing_lab_result(result_id)?;
+        caller_context().require_patient_access(result.patient_id, ConsentScope::ReadRecords)?;
+        Ok(result)
+    })
+}
+
+/// Lab results of the patient in collection order; with a test code set this
+/// is the time series of that test.
+#[ic_cdk::update]
+pub fn list_lab_results(patient_id: u64, filter: LabResultFilter) -> Result<Vec<LabResult>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadRecords)?;
+
+    let results: Vec<LabResult> = get_lab_results_by_patient(patient_id)
+        .into_iter()
+        .filter(|result| {
+            filter
+                .test_code
+                .as_ref()
+                .map_or(true, |code| code.trim() == result.test_code)
+                && filter.from.map_or(true, |from| result.collected_at >= from)
+                && filter.to.map_or(true, |to| result.collected_at < to)
+        })
+        .collect();
+
+    record_reads(
+        "list_lab_results",
+        AuditEntity::LabResult,
+        results.iter().map(|result| result.id),
+    );
+    Ok(results)
+}
+
+/// Removes a result entered in error.
+#[ic_cdk::update]
+pub fn delete_lab_result(result_id: u64) -> Result<(), Error> {
+    audited(
+        "delete_lab_result",
+        AuditEntity::LabResult,
+        result_id,
+        AuditAction::Delete,
+        || {
+            let result = get_existing_lab_result(result_id)?;
+            caller_context().require_record_writer(result.patient_id)?;
+
+            LAB_RESULT_STORAGE.with(|service| service.borrow_mut().remove(&result_id));
+            unindex_lab_result(&result);
+            Ok(())
+        },
+    )
+}
+
+pub fn get_lab_result_by_id(result_id: &u64) -> Option<LabResult> {
+    LAB_RESULT_STORAGE.with(|service| service.borrow().get(result_id))
+}
+
+/// Lab results of the patient, oldest collection first.
+pub fn get_lab_results_by_patient(patient_id: u64) -> Vec<LabResult> {
+    let mut results: Vec<LabResult> = LAB_RESULT_STORAGE.with(|service| {
+        let storage = service.borrow();
+        lab_result_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|result_id| storage.get(result_id))
+            .collect()
+    });
+    results.sort_by_key(|result| result.collected_at);
+    results
+}
+
+/// Critical bounds take precedence over the normal range.
+pub fn flag_for(value: f64, range: &ReferenceRange) -> LabFlag {
+    if range.critical_low.map_or(false, |low| value < low) {
+        LabFlag::CriticalLow
+    } else if range.critical_high.map_or(false, |high| value > high) {
+        LabFlag::CriticalHigh
+    } else if range.low.map_or(false, |low| value < low) {
+        LabFlag::Low
+    } else if range.high.map_or(false, |high| value > high) {
+        LabFlag::High
+    } else {
+        LabFlag::Normal
+    }
+}
+
+fn get_existing_lab_result(result_id: u64) -> Result<LabResult, Error> {
+    get_lab_result_by_id(&result_id).ok_or(Error::NotFound {
+        msg: format!("Lab result with id={} not found", result_id),
+    })
+}
+
+// Codes are kept as given; LOINC codes and local lab codes both pass
+fn validate_test_code(test_code: &str) -> Result<(), Error> {
+    let code = test_code.trim();
+    if code.is_empty()
+        || !code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("Invalid test code '{}'", test_code),
+        });
+    }
+    Ok(())
+}
+
+fn validate_range(range: &ReferenceRange) -> Result<(), Error> {
+    let bounds = [
+        range.low,
+        range.high,
+        range.critical_low,
+        range.critical_high,
+    ];
+    if bounds.iter().flatten().any(|bound| !bound.is_finite()) {
+        return Err(Error::InvalidInput {
+            msg: "Reference range bounds must be finite numbers".to_string(),
+        });
+    }
+    if range.low.is_none() && range.high.is_none() {
+        return Err(Error::InvalidInput {
+            msg: "Reference range needs a low or a high bound".to_string(),
+        });
+    }
+
+    let ordered = |lower: Option<f64>, upper: Option<f64>| match (lower, upper) {
+        (Some(lower), Some(upper)) => lower <= upper,
+        _ => true,
+    };
+    if !ordered(range.low, range.high)
+        || !ordered(range.critical_low, range.low)
+        || !ordered(range.high, range.critical_high)
+        || !ordered(range.critical_low, range.critical_high)
+    {
+        return Err(Error::InvalidInput {
+            msg: "Reference range bounds are out of order".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Serum potassium in mmol/L
+    fn potassium() -> ReferenceRange {
+        ReferenceRange {
+            low: Some(3.5),
+            high: Some(5.1),
+            critical_low: Some(2.5),
+            critical_high: Some(6.5),
+        }
+    }
+
+    #[test]
+    fn values_inside_the_range_are_normal() {
+        assert_eq!(flag_for(4.2, &potassium()), LabFlag::Normal);
+        // Bounds belong to the range
+        assert_eq!(flag_for(3.5, &potassium()), LabFlag::Normal);
+        assert_eq!(flag_for(5.1, &potassium()), LabFlag::Normal);
+    }
+
+    #[test]
+    fn values_outside_the_range_are_low_or_high() {
+        assert_eq!(flag_for(3.0, &potassium()), LabFlag::Low);
+        assert_eq!(flag_for(6.0, &potassium()), LabFlag::High);
+        assert_eq!(flag_for(2.5, &potassium()), LabFlag::Low);
+        assert_eq!(flag_for(6.5, &potassium()), LabFlag::High);
+    }
+
+    #[test]
+    fn critical_bounds_take_precedence() {
+        assert_eq!(flag_for(2.0, &potassium()), LabFlag::CriticalLow);
+        assert_eq!(flag_for(7.0, &potassium()), LabFlag::CriticalHigh);
+    }
+
+    #[test]
+    fn missing_bounds_never_flag() {
+        let upper_only = ReferenceRange {
+            low: None,
+            high: Some(200.0),
+            critical_low: None,
+            critical_high: None,
+        };
+        assert_eq!(flag_for(-5.0, &upper_only), LabFlag::Normal);
+        assert_eq!(flag_for(250.0, &upper_only), LabFlag::High);
+    }
+
+    #[test]
+    fn ranges_are_validated() {
+        assert!(validate_range(&potassium()).is_ok());
+
+        let inverted = ReferenceRange {
+            low: Some(5.1),
+            high: Some(3.5),
+            ..potassium()
+        };
+        assert!(validate_range(&inverted).is_err());
+
+        let critical_inside = ReferenceRange {
+            critical_low: Some(4.0),
+            ..potassium()
+        };
+        assert!(validate_range(&critical_inside).is_err());
+
+        let unbounded = ReferenceRange {
+            low: None,
+            high: None,
+            critical_low: None,
+            critical_high: None,
+        };
+        assert!(validate_range(&unbounded).is_err());
+
+        let not_a_number = ReferenceRange {
+            high: Some(f64::NAN),
+            ..potassium()
+        };
+        assert!(validate_range(&not_a_number).is_err());
+    }
+}
\ No newline at end of file
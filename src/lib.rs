@@ -5,32 +5,69 @@
 extern crate serde;
 
 // Re-export all public APIs
+pub use crate::allergy::*;
 pub use crate::appointment::*;
+pub use crate::audit::*;
 pub use crate::availability::*;
+pub use crate::blob::*;
 pub use crate::calendly::*;
+pub use crate::chart::*;
+pub use crate::consent::*;
 pub use crate::data::*;
 pub use crate::doctor::*;
+pub use crate::erasure::*;
+pub use crate::export::*;
+pub use crate::fhir::*;
 pub use crate::identity::*;
+pub use crate::immunization::*;
+pub use crate::index::*;
+pub use crate::interaction::*;
+pub use crate::lab_result::*;
 pub use crate::medical_record::*;
 pub use crate::message::*;
+pub use crate::migration::*;
 pub use crate::patient::*;
+pub use crate::prescription::*;
+pub use crate::problem::*;
 pub use crate::report::*;
+pub use crate::schedule::*;
+pub use crate::vital::*;
 
 // Internal modules
+mod allergy;
 mod appointment;
+mod audit;
+mod auth;
 mod availability;
+mod blob;
 mod calendly;
+mod chart;
+mod consent;
 mod data;
 mod doctor;
+mod erasure;
 mod error;
+mod export;
+mod fhir;
 mod identity;
+mod immunization;
+mod index;
+mod interaction;
+mod lab_result;
 mod medical_record;
 mod message;
+mod migration;
 mod models;
+mod pagination;
 mod patient;
+mod prescription;
+mod problem;
 mod report;
+mod schedule;
+mod schema;
 mod storage;
 mod utils;
+mod vital;
 
 // Export Candid interface
 ic_cdk::export_candid!();
\ No newline at end of file
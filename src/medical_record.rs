@@ -1,111 +1,532 @@
 //! Medical record management functionality
+//!
+//! Records are never edited in place: creating a record writes revision 1 and
+//! every update writes the next revision with its author and reason, so the
+//! record's full history stays readable. Deleting a record leaves a tombstone
+//! without contents in its place and keeps the history; only erasing the
+//! patient removes it.
 
+use crate::audit::{audited, audited_create, audited_read, record_reads};
+use crate::auth::caller_context;
 use crate::error::Error;
-use crate::models::MedicalRecord;
-use crate::storage::MEDICAL_RECORD_STORAGE;
+use crate::index::{index_medical_record, medical_record_ids_by_patient, unindex_medical_record};
+use crate::models::{
+    AuditAction, AuditEntity, ConsentScope, FieldChange, IndexKey, MedicalRecord,
+    MedicalRecordFilter, MedicalRecordRevision, Page, PageRequest,
+};
+use crate::pagination::{matches, paginate, paginate_ids};
+use crate::patient::get_patient_by_id;
+use crate::storage::{MEDICAL_RECORD_REVISIONS, MEDICAL_RECORD_STORAGE};
+use crate::utils::generate_id;
 
-#[ic_cdk::query]
+/// Limit of the lab results and of the treatment history. With the reason
+/// limit it keeps a record within its `MAX_SIZE` of 2048 bytes and a revision
+/// within 2560.
+pub const MAX_RECORD_TEXT_LEN: usize = 896;
+pub const MAX_REASON_LEN: usize = 256;
+
+#[ic_cdk::update]
 pub fn get_medical_record(record_id: u64) -> Result<MedicalRecord, Error> {
-    match get_medical_record_by_id(&record_id) {
-        Some(record) => Ok(record),
-        None => Err(Error::NotFound {
-            msg: format!("medical record with id={} not found", record_id),
-        }),
-    }
+    audited_read(
+        "get_medical_record",
+        AuditEntity::MedicalRecord,
+        record_id,
+        || match get_medical_record_by_id(&record_id) {
+            Some(record) => {
+                caller_context()
+                    .require_patient_access(record.patient_id, ConsentScope::ReadRecords)?;
+                Ok(record)
+            }
+            None => Err(Error::NotFound {
+                msg: format!("medical record with id={} not found", record_id),
+            }),
+        },
+    )
 }
 
 #[ic_cdk::update]
 pub fn create_medical_record(
-    record_id: u64,
     patient_id: u64,
     lab_results: String,
     treatment_history: String,
 ) -> Result<MedicalRecord, Error> {
-    // Input validation
-    if lab_results.trim().is_empty() || treatment_history.trim().is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Lab results and treatment history cannot be empty".to_string(),
-        });
-    }
+    audited_create(
+        "create_medical_record",
+        AuditEntity::MedicalRecord,
+        |record: &MedicalRecord| record.id,
+        || {
+            // Input validation
+            validate_contents(&lab_results, &treatment_history)?;
 
-    let new_record = MedicalRecord {
-        id: record_id,
-        patient_id,
-        lab_results,
-        treatment_history,
-    };
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
 
-    match MEDICAL_RECORD_STORAGE
-        .with(|service| service.borrow_mut().insert(record_id, new_record.clone()))
-    {
-        Some(_) => Err(Error::AlreadyExists {
-            msg: format!("Medical record with id={} already exists", record_id),
-        }),
-        None => Ok(new_record),
-    }
+            // Only doctors of the patient write clinical records, and only
+            // those the patient allowed to
+            let caller = caller_context();
+            caller.require_record_writer(patient_id)?;
+
+            // Records created before ids were assigned here may hold any id,
+            // so skip past the ones the counter would hand out again
+            let mut record_id = generate_id();
+            while get_stored_medical_record(&record_id).is_some() {
+                record_id = generate_id();
+            }
+
+            let new_record = MedicalRecord {
+                id: record_id,
+                patient_id,
+                lab_results,
+                treatment_history,
+                revision: 1,
+                deleted_at: None,
+            };
+
+            MEDICAL_RECORD_STORAGE
+                .with(|service| service.borrow_mut().insert(record_id, new_record.clone()));
+            index_medical_record(&new_record);
+            write_revision(&new_record, caller.principal, String::new());
+            Ok(new_record)
+        },
+    )
 }
 
+/// Writes the next revision of the record; `reason` says why it changed.
 #[ic_cdk::update]
 pub fn update_medical_record(
     record_id: u64,
     patient_id: u64,
     lab_results: String,
     treatment_history: String,
+    reason: String,
 ) -> Result<MedicalRecord, Error> {
-    // Input validation
-    if lab_results.trim().is_empty() || treatment_history.trim().is_empty() {
-        return Err(Error::InvalidInput {
-            msg: "Lab results and treatment history cannot be empty".to_string(),
-        });
-    }
+    audited(
+        "update_medical_record",
+        AuditEntity::MedicalRecord,
+        record_id,
+        AuditAction::Update,
+        || {
+            // Input validation
+            validate_contents(&lab_results, &treatment_history)?;
+            if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "A reason for the change of at most {} bytes is required",
+                        MAX_REASON_LEN
+                    ),
+                });
+            }
 
-    let updated_record = MedicalRecord {
-        id: record_id,
-        patient_id,
-        lab_results,
-        treatment_history,
-    };
+            let current = get_medical_record_by_id(&record_id).ok_or(Error::NotFound {
+                msg: format!("Medical record with id={} not found", record_id),
+            })?;
 
-    match MEDICAL_RECORD_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .insert(record_id, updated_record.clone())
-    }) {
-        Some(_) => Ok(updated_record),
-        None => Err(Error::NotFound {
-            msg: format!("Medical record with id={} not found", record_id),
-        }),
-    }
+            // Only doctors of the patient write clinical records, and only
+            // those the patient allowed to
+            let caller = caller_context();
+            caller.require_record_writer(patient_id)?;
+            caller.require_record_writer(current.patient_id)?;
+
+            // A record read before the migration reached it has no revision yet
+            store_legacy_revision(&current);
+
+            if current.patient_id == patient_id
+                && current.lab_results == lab_results
+                && current.treatment_history == treatment_history
+            {
+                return Err(Error::InvalidInput {
+                    msg: format!("Medical record with id={} is unchanged", record_id),
+                });
+            }
+
+            let updated_record = MedicalRecord {
+                id: record_id,
+                patient_id,
+                lab_results,
+                treatment_history,
+                revision: current.revision + 1,
+                deleted_at: None,
+            };
+
+            MEDICAL_RECORD_STORAGE.with(|service| {
+                service
+                    .borrow_mut()
+                    .insert(record_id, updated_record.clone())
+            });
+            if current.patient_id != patient_id {
+                unindex_medical_record(&current);
+                index_medical_record(&updated_record);
+            }
+            write_revision(&updated_record, caller.principal, reason);
+            Ok(updated_record)
+        },
+    )
 }
 
 #[ic_cdk::update]
 pub fn delete_medical_record(record_id: u64) -> Result<(), Error> {
-    // Input validation
-    if record_id == 0 {
-        return Err(Error::InvalidInput {
-            msg: "Record ID cannot be zero".to_string(),
-        });
-    }
+    audited(
+        "delete_medical_record",
+        AuditEntity::MedicalRecord,
+        record_id,
+        AuditAction::Delete,
+        || {
+            // Input validation
+            if record_id == 0 {
+                return Err(Error::InvalidInput {
+                    msg: "Record ID cannot be zero".to_string(),
+                });
+            }
 
-    match MEDICAL_RECORD_STORAGE.with(|service| service.borrow_mut().remove(&record_id)) {
-        Some(_) => Ok(()),
-        None => Err(Error::NotFound {
-            msg: format!("Medical record with id={} not found", record_id),
-        }),
-    }
+            let record = get_medical_record_by_id(&record_id).ok_or(Error::NotFound {
+                msg: format!("Medical record with id={} not found", record_id),
+            })?;
+            caller_context().require_record_writer(record.patient_id)?;
+
+            // The tombstone stays in the patient index, so erasure still
+            // finds the history it has to remove
+            store_legacy_revision(&record);
+            let tombstone = MedicalRecord {
+                lab_results: String::new(),
+                treatment_history: String::new(),
+                deleted_at: Some(ic_cdk::api::time()),
+                ..record
+            };
+            MEDICAL_RECORD_STORAGE
+                .with(|service| service.borrow_mut().insert(record_id, tombstone));
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn list_medical_records(page: PageRequest, filter: MedicalRecordFilter) -> Page<MedicalRecord> {
+    let caller = caller_context();
+    let visible = caller.patient_access(ConsentScope::ReadRecords);
+    let keep = |record: &MedicalRecord| {
+        record.deleted_at.is_none()
+            && matches(&filter.patient_id, &record.patient_id)
+            && visible(record.patient_id)
+    };
+    let page = match filter.patient_id {
+        Some(patient_id) => paginate_ids(
+            medical_record_ids_by_patient(patient_id),
+            &page,
+            get_medical_record_by_id,
+            keep,
+        ),
+        None => paginate(&MEDICAL_RECORD_STORAGE, &page, keep),
+    };
+
+    record_reads(
+        "list_medical_records",
+        AuditEntity::MedicalRecord,
+        page.items.iter().map(|record| record.id),
+    );
+    page
+}
+
+/// Every revision of the record, oldest first.
+#[ic_cdk::update]
+pub fn list_medical_record_revisions(record_id: u64) -> Result<Vec<MedicalRecordRevision>, Error> {
+    audited_read(
+        "list_medical_record_revisions",
+        AuditEntity::MedicalRecord,
+        record_id,
+        || {
+            let revisions = get_medical_record_revisions(record_id);
+            if revisions.is_empty() {
+                return Err(Error::NotFound {
+                    msg: format!("Medical record with id={} not found", record_id),
+                });
+            }
+            require_history_access(&revisions)?;
+            Ok(revisions)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn get_medical_record_revision(
+    record_id: u64,
+    revision: u32,
+) -> Result<MedicalRecordRevision, Error> {
+    audited_read(
+        "get_medical_record_revision",
+        AuditEntity::MedicalRecord,
+        record_id,
+        || {
+            let found = get_revision(record_id, revision)?;
+            require_history_access(std::slice::from_ref(&found))?;
+            Ok(found)
+        },
+    )
+}
+
+/// Fields that differ from revision `from` to revision `to`.
+#[ic_cdk::update]
+pub fn diff_medical_record_revisions(
+    record_id: u64,
+    from: u32,
+    to: u32,
+) -> Result<Vec<FieldChange>, Error> {
+    audited_read(
+        "diff_medical_record_revisions",
+        AuditEntity::MedicalRecord,
+        record_id,
+        || {
+            let old = get_revision(record_id, from)?;
+            let new = get_revision(record_id, to)?;
+            require_history_access(&[old.clone(), new.clone()])?;
+            Ok(revision_changes(old, new))
+        },
+    )
+}
+
+/// The record unless it was deleted.
+pub fn get_medical_record_by_id(record_id: &u64) -> Option<MedicalRecord> {
+    get_stored_medical_record(record_id).filter(|record| record.deleted_at.is_none())
 }
 
-#[ic_cdk::query]
-pub fn list_medical_records() -> Vec<MedicalRecord> {
+/// The record or its tombstone.
+pub fn get_stored_medical_record(record_id: &u64) -> Option<MedicalRecord> {
+    MEDICAL_RECORD_STORAGE.with(|service| service.borrow().get(record_id))
+}
+
+/// Live records of the patient, looked up through the patient index.
+pub fn get_medical_records_by_patient(patient_id: u64) -> Vec<MedicalRecord> {
     MEDICAL_RECORD_STORAGE.with(|service| {
+        let storage = service.borrow();
+        medical_record_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|record_id| storage.get(record_id))
+            .filter(|record| record.deleted_at.is_none())
+            .collect()
+    })
+}
+
+pub fn get_medical_record_revisions(record_id: u64) -> Vec<MedicalRecordRevision> {
+    MEDICAL_RECORD_REVISIONS.with(|service| {
         service
             .borrow()
-            .iter()
-            .map(|(_, record)| record.clone())
+            .range(revision_key(record_id, 0)..=revision_key(record_id, u32::MAX))
+            .map(|(_, revision)| revision)
             .collect()
     })
 }
 
-pub fn get_medical_record_by_id(record_id: &u64) -> Option<MedicalRecord> {
-    MEDICAL_RECORD_STORAGE.with(|service| service.borrow().get(record_id))
+pub fn remove_medical_record_revisions(record_id: u64) {
+    let keys: Vec<IndexKey> = MEDICAL_RECORD_REVISIONS.with(|service| {
+        service
+            .borrow()
+            .range(revision_key(record_id, 0)..=revision_key(record_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    });
+    MEDICAL_RECORD_REVISIONS.with(|service| {
+        let mut revisions = service.borrow_mut();
+        for key in keys {
+            revisions.remove(&key);
+        }
+    });
+}
+
+/// Keeps a record written before revisions existed as its first revision,
+/// with no author or time. Records that already have it are left alone.
+pub fn store_legacy_revision(record: &MedicalRecord) {
+    let key = revision_key(record.id, record.revision);
+    if MEDICAL_RECORD_REVISIONS.with(|service| service.borrow().contains_key(&key)) {
+        return;
+    }
+    insert_revision(MedicalRecordRevision {
+        record_id: record.id,
+        revision: record.revision,
+        patient_id: record.patient_id,
+        lab_results: record.lab_results.clone(),
+        treatment_history: record.treatment_history.clone(),
+        author: String::new(),
+        created_at: 0,
+        reason: String::new(),
+    });
+}
+
+fn write_revision(record: &MedicalRecord, author: String, reason: String) {
+    insert_revision(MedicalRecordRevision {
+        record_id: record.id,
+        revision: record.revision,
+        patient_id: record.patient_id,
+        lab_results: record.lab_results.clone(),
+        treatment_history: record.treatment_history.clone(),
+        author,
+        created_at: ic_cdk::api::time(),
+        reason,
+    });
+}
+
+fn insert_revision(revision: MedicalRecordRevision) {
+    let key = revision_key(revision.record_id, revision.revision);
+    MEDICAL_RECORD_REVISIONS.with(|service| service.borrow_mut().insert(key, revision));
+}
+
+fn validate_contents(lab_results: &str, treatment_history: &str) -> Result<(), Error> {
+    if lab_results.trim().is_empty() || treatment_history.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Lab results and treatment history cannot be empty".to_string(),
+        });
+    }
+    if lab_results.len() > MAX_RECORD_TEXT_LEN || treatment_history.len() > MAX_RECORD_TEXT_LEN {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Lab results and treatment history are limited to {} bytes each",
+                MAX_RECORD_TEXT_LEN
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn revision_changes(old: MedicalRecordRevision, new: MedicalRecordRevision) -> Vec<FieldChange> {
+    let fields = [
+        (
+            "patient_id",
+            old.patient_id.to_string(),
+            new.patient_id.to_string(),
+        ),
+        ("lab_results", old.lab_results, new.lab_results),
+        (
+            "treatment_history",
+            old.treatment_history,
+            new.treatment_history,
+        ),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, from, to)| from != to)
+        .map(|(field, from, to)| FieldChange {
+            field: field.to_string(),
+            from,
+            to,
+        })
+        .collect()
+}
+
+fn get_revision(record_id: u64, revision: u32) -> Result<MedicalRecordRevision, Error> {
+    MEDICAL_RECORD_REVISIONS
+        .with(|service| service.borrow().get(&revision_key(record_id, revision)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Revision {} of medical record with id={} not found",
+                revision, record_id
+            ),
+        })
+}
+
+fn revision_key(record_id: u64, revision: u32) -> IndexKey {
+    IndexKey {
+        owner: record_id,
+        id: u64::from(revision),
+    }
+}
+
+// A record moved between patients has history under each of them
+fn require_history_access(revisions: &[MedicalRecordRevision]) -> Result<(), Error> {
+    let caller = caller_context();
+    let mut checked = Vec::new();
+    for revision in revisions {
+        if !checked.contains(&revision.patient_id) {
+            caller.require_patient_access(revision.patient_id, ConsentScope::ReadRecords)?;
+            checked.push(revision.patient_id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revision(
+        revision: u32,
+        lab_results: &str,
+        treatment_history: &str,
+    ) -> MedicalRecordRevision {
+        MedicalRecordRevision {
+            record_id: 5,
+            revision,
+            patient_id: 7,
+            lab_results: lab_results.to_string(),
+            treatment_history: treatment_history.to_string(),
+            author: "aaaaa-aa".to_string(),
+            created_at: u64::from(revision),
+            reason: String::new(),
+        }
+    }
+
+    fn changed_fields(changes: &[FieldChange]) -> Vec<&str> {
+        changes.iter().map(|change| change.field.as_str()).collect()
+    }
+
+    #[test]
+    fn identical_revisions_have_no_changes() {
+        let changes = revision_changes(
+            revision(1, "HbA1c 6.1%", "metformin"),
+            revision(2, "HbA1c 6.1%", "metformin"),
+        );
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn changes_carry_both_values() {
+        let changes = revision_changes(
+            revision(1, "HbA1c 6.1%", "metformin"),
+            revision(2, "HbA1c 7.4%", "metformin"),
+        );
+
+        assert_eq!(changed_fields(&changes), vec!["lab_results"]);
+        assert_eq!(changes[0].from, "HbA1c 6.1%");
+        assert_eq!(changes[0].to, "HbA1c 7.4%");
+    }
+
+    #[test]
+    fn every_changed_field_is_listed_in_field_order() {
+        let old = revision(1, "", "metformin");
+        let new = MedicalRecordRevision {
+            patient_id: 8,
+            ..revision(3, "HbA1c 7.4%", "metformin, insulin")
+        };
+        let changes = revision_changes(old, new);
+
+        assert_eq!(
+            changed_fields(&changes),
+            vec!["patient_id", "lab_results", "treatment_history"]
+        );
+        assert_eq!(changes[0].from, "7");
+        assert_eq!(changes[0].to, "8");
+    }
+
+    #[test]
+    fn diffs_read_in_either_direction() {
+        let changes = revision_changes(
+            revision(2, "HbA1c 7.4%", "metformin"),
+            revision(1, "HbA1c 6.1%", "metformin"),
+        );
+
+        assert_eq!(changes[0].from, "HbA1c 7.4%");
+        assert_eq!(changes[0].to, "HbA1c 6.1%");
+    }
+
+    #[test]
+    fn author_and_reason_are_not_diffed() {
+        let old = revision(1, "HbA1c 6.1%", "metformin");
+        let new = MedicalRecordRevision {
+            author: "2vxsx-fae".to_string(),
+            reason: "typo".to_string(),
+            ..revision(2, "HbA1c 6.1%", "metformin")
+        };
+        assert!(revision_changes(old, new).is_empty());
+    }
 }
\ No newline at end of file
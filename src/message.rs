@@ -1,7 +1,12 @@
 //! Message management functionality
 
+use crate::auth::{caller_context, unauthorized, CallerContext};
+use crate::blob::{attach_blob, remove_blob, validate_attachment};
 use crate::error::Error;
-use crate::models::{Message, MultiMediaContent};
+use crate::index::{index_message, unindex_message};
+use crate::migration::require_schema_current;
+use crate::models::{BlobLink, Message, MessageFilter, MultiMediaContent, Page, PageRequest};
+use crate::pagination::{matches, paginate};
 use crate::patient::get_patient_by_id;
 use crate::storage::MESSAGE_STORAGE;
 use crate::utils::generate_id;
@@ -9,7 +14,10 @@ use crate::utils::generate_id;
 #[ic_cdk::query]
 pub fn get_message(message_id: u64) -> Result<Message, Error> {
     match get_message_by_id(&message_id) {
-        Some(message) => Ok(message),
+        Some(message) => {
+            require_message_party(&caller_context(), &message)?;
+            Ok(message)
+        }
         None => Err(Error::NotFound {
             msg: format!("message with id={} not found", message_id),
         }),
@@ -30,8 +38,18 @@ pub fn send_message(
         });
     }
 
+    // Callers can only send messages as themselves
+    let caller = caller_context();
+    if !caller.is_admin && !caller.is_party(sender_id) {
+        return Err(unauthorized("Caller cannot send messages as this sender"));
+    }
+
     let id = generate_id();
 
+    if let Some(attachment) = &multimedia_content {
+        validate_attachment(attachment.blob_id, BlobLink::Message(id))?;
+    }
+
     let message = Message {
         id,
         sender_id,
@@ -41,6 +59,8 @@ pub fn send_message(
     };
 
     MESSAGE_STORAGE.with(|service| service.borrow_mut().insert(id, message.clone()));
+    index_message(&message);
+    attach_message_content(&message);
     Ok(message)
 }
 
@@ -59,6 +79,23 @@ pub fn update_message(
         });
     }
 
+    require_schema_current()?;
+
+    // Only the original sender may edit a message
+    let caller = caller_context();
+    let current = get_message_by_id(&message_id);
+    if let Some(current) = &current {
+        if !caller.is_admin
+            && (!caller.is_party(current.sender_id) || current.sender_id != sender_id)
+        {
+            return Err(unauthorized("Only the sender may edit this message"));
+        }
+    }
+
+    if let Some(attachment) = &multimedia_content {
+        validate_attachment(attachment.blob_id, BlobLink::Message(message_id))?;
+    }
+
     let updated_message = Message {
         id: message_id,
         sender_id,
@@ -72,7 +109,24 @@ pub fn update_message(
             .borrow_mut()
             .insert(message_id, updated_message.clone())
     }) {
-        Some(_) => Ok(updated_message),
+        Some(previous) => {
+            unindex_message(&previous);
+            index_message(&updated_message);
+
+            // Drop an attachment the edit replaced
+            if let Some(previous) = previous.multimedia_content {
+                if updated_message
+                    .multimedia_content
+                    .as_ref()
+                    .map(|m| m.blob_id)
+                    != Some(previous.blob_id)
+                {
+                    remove_blob(previous.blob_id);
+                }
+            }
+            attach_message_content(&updated_message);
+            Ok(updated_message)
+        }
         None => Err(Error::NotFound {
             msg: format!("Message with id={} not found", message_id),
         }),
@@ -81,8 +135,18 @@ pub fn update_message(
 
 #[ic_cdk::update]
 pub fn delete_message(message_id: u64) -> Result<(), Error> {
+    if let Some(message) = get_message_by_id(&message_id) {
+        require_message_party(&caller_context(), &message)?;
+    }
+
     match MESSAGE_STORAGE.with(|service| service.borrow_mut().remove(&message_id)) {
-        Some(_) => Ok(()),
+        Some(message) => {
+            unindex_message(&message);
+            if let Some(attachment) = message.multimedia_content {
+                remove_blob(attachment.blob_id);
+            }
+            Ok(())
+        }
         None => Err(Error::NotFound {
             msg: format!("Message with id={} not found", message_id),
         }),
@@ -90,13 +154,12 @@ pub fn delete_message(message_id: u64) -> Result<(), Error> {
 }
 
 #[ic_cdk::query]
-pub fn list_messages() -> Vec<Message> {
-    MESSAGE_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, message)| message.clone())
-            .collect()
+pub fn list_messages(page: PageRequest, filter: MessageFilter) -> Page<Message> {
+    let caller = caller_context();
+    paginate(&MESSAGE_STORAGE, &page, |message| {
+        matches(&filter.sender_id, &message.sender_id)
+            && matches(&filter.receiver_id, &message.receiver_id)
+            && require_message_party(&caller, message).is_ok()
     })
 }
 
@@ -120,9 +183,16 @@ pub fn send_reminder_to_patient(
         });
     }
 
+    // Reminders come from the patient's doctors
+    caller_context().require_treating_doctor(patient_id)?;
+
     let sender_id = 0; // System ID
     let id = generate_id();
 
+    if let Some(attachment) = &multimedia_content {
+        validate_attachment(attachment.blob_id, BlobLink::Message(id))?;
+    }
+
     let message = Message {
         id,
         sender_id,
@@ -132,9 +202,29 @@ pub fn send_reminder_to_patient(
     };
 
     MESSAGE_STORAGE.with(|service| service.borrow_mut().insert(id, message.clone()));
+    index_message(&message);
+    attach_message_content(&message);
     Ok(message)
 }
 
 pub fn get_message_by_id(message_id: &u64) -> Option<Message> {
     MESSAGE_STORAGE.with(|service| service.borrow().get(message_id))
+}
+
+fn attach_message_content(message: &Message) {
+    if let Some(attachment) = &message.multimedia_content {
+        attach_blob(attachment.blob_id, BlobLink::Message(message.id));
+    }
+}
+
+pub fn require_message_party(caller: &CallerContext, message: &Message) -> Result<(), Error> {
+    if caller.is_admin || caller.is_party(message.sender_id) || caller.is_party(message.receiver_id)
+    {
+        Ok(())
+    } else {
+        Err(unauthorized(&format!(
+            "Caller is not a party to message with id={}",
+            message.id
+        )))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,369 @@
+//! Upgrade hooks and the batched migration that rewrites stable records into
+//! their current layout versions
+
+use candid::Principal;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::appointment::{store_legacy_reschedules, store_legacy_status_history};
+use crate::auth::{caller_context, party_principal};
+use crate::blob::store_legacy_payload;
+use crate::data::get_data_by_id;
+use crate::error::Error;
+use crate::identity::{add_role, bootstrap_admin};
+use crate::index::{
+    clear_indexes, index_allergy, index_appointment, index_availability, index_availability_rule,
+    index_blob, index_consent, index_data, index_doctor, index_identity, index_immunization,
+    index_interaction, index_lab_result, index_medical_record, index_message, index_patient,
+    index_prescription, index_problem, index_report, index_vital,
+};
+use crate::medical_record::store_legacy_revision;
+use crate::message::get_message_by_id;
+use crate::models::{
+    BlobLink, MedicalRecord, MigrationProgress, MigrationState, MigrationTable, Role,
+};
+use crate::patient::get_patient_by_username;
+use crate::prescription::store_legacy_prescription;
+use crate::report::get_report_by_id;
+use crate::schema::{collect_legacy_splits, fits, LegacySplit};
+use crate::storage::{
+    Memory, ALLERGY_STORAGE, APPOINTMENT_STORAGE, AVAILABILITY_RULE_STORAGE, AVAILABILITY_STORAGE,
+    BLOB_STORAGE, CALENDLY_STORAGE, CONSENT_STORAGE, DATA_STORAGE, DOCTOR_STORAGE,
+    IDENTITY_STORAGE, IMMUNIZATION_STORAGE, INTERACTION_STORAGE, LAB_RESULT_STORAGE,
+    LEGACY_DOCIDENTITY_STORAGE, MEDICAL_RECORD_STORAGE, MESSAGE_STORAGE, MIGRATION_STATE,
+    PATIENT_STORAGE, PRESCRIPTION_STORAGE, PROBLEM_STORAGE, REPORT_STORAGE, VITAL_STORAGE,
+};
+
+/// Bump whenever a model's layout `VERSION` changes or an index is added so
+/// that the next upgrade rewrites, and re-indexes, every stored record.
+pub const SCHEMA_VERSION: u32 = 17;
+
+/// Records rewritten inside `post_upgrade`; the rest is driven by
+/// `run_migration_batch` to stay within the instruction limit.
+const UPGRADE_BATCH_SIZE: u64 = 500;
+
+const MIGRATION_ORDER: [MigrationTable; 21] = [
+    MigrationTable::LegacyDocIdentities,
+    MigrationTable::Identities,
+    MigrationTable::Patients,
+    MigrationTable::Doctors,
+    MigrationTable::Appointments,
+    MigrationTable::Messages,
+    MigrationTable::MedicalRecords,
+    MigrationTable::Reports,
+    MigrationTable::Calendly,
+    MigrationTable::Data,
+    MigrationTable::Availability,
+    MigrationTable::AvailabilityRules,
+    MigrationTable::Consents,
+    MigrationTable::Prescriptions,
+    MigrationTable::Allergies,
+    MigrationTable::Interactions,
+    MigrationTable::Immunizations,
+    MigrationTable::Problems,
+    MigrationTable::LabResults,
+    MigrationTable::Vitals,
+    MigrationTable::Blobs,
+];
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    // Stable structures survive upgrades as they are; only remember which
+    // layout version the outgoing code wrote.
+    let mut state = read_state();
+    state.upgraded_from = SCHEMA_VERSION;
+    write_state(state);
+}
+
+/// Accepts the same optional admin as `init` so that a canister upgraded from
+/// a release without the role registry still ends up with an admin.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(admin: Option<Principal>) {
+    let mut state = read_state();
+    let outdated = state.schema_version < SCHEMA_VERSION;
+    if outdated {
+        // A pass interrupted by another upgrade restarts for the new target
+        state.in_progress = Some(MigrationProgress {
+            target_version: SCHEMA_VERSION,
+            table: MIGRATION_ORDER[0],
+            cursor: 0,
+            rewritten: 0,
+        });
+        write_state(state);
+    }
+
+    // Only now do identity lookups know the indexes may still be empty
+    bootstrap_admin(admin);
+
+    if outdated {
+        run_batch(UPGRADE_BATCH_SIZE);
+    }
+}
+
+#[ic_cdk::query]
+pub fn migration_status() -> Result<MigrationState, Error> {
+    caller_context().require_admin()?;
+    Ok(read_state())
+}
+
+#[ic_cdk::update]
+pub fn run_migration_batch(batch_size: u64) -> Result<MigrationState, Error> {
+    caller_context().require_admin()?;
+
+    if batch_size == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Batch size must be positive".to_string(),
+        });
+    }
+
+    Ok(run_batch(batch_size))
+}
+
+fn run_batch(batch_size: u64) -> MigrationState {
+    let mut state = read_state();
+    let mut budget = batch_size;
+
+    while budget > 0 {
+        let mut progress = match state.in_progress.take() {
+            Some(progress) => progress,
+            None => break,
+        };
+
+        let ((processed, next_cursor), splits) =
+            collect_legacy_splits(|| migrate_table(progress.table, progress.cursor, budget));
+        splits.into_iter().for_each(store_split);
+        budget -= processed;
+        progress.rewritten += processed;
+
+        match next_cursor {
+            Some(cursor) => {
+                progress.cursor = cursor;
+                state.in_progress = Some(progress);
+            }
+            None => match next_table(progress.table) {
+                Some(table) => {
+                    progress.table = table;
+                    progress.cursor = 0;
+                    state.in_progress = Some(progress);
+                }
+                None => state.schema_version = progress.target_version,
+            },
+        }
+    }
+
+    write_state(state.clone());
+    state
+}
+
+/// Restarts the migration pass at `table`; the remaining batches run through
+/// `run_migration_batch`.
+pub fn restart_migration(table: MigrationTable) -> MigrationState {
+    let mut state = read_state();
+    state.in_progress = Some(MigrationProgress {
+        target_version: SCHEMA_VERSION,
+        table,
+        cursor: 0,
+        rewritten: 0,
+    });
+    write_state(state);
+    run_batch(UPGRADE_BATCH_SIZE)
+}
+
+fn next_table(table: MigrationTable) -> Option<MigrationTable> {
+    if table == MigrationTable::Indexes {
+        return Some(MIGRATION_ORDER[0]);
+    }
+    let position = MIGRATION_ORDER.iter().position(|entry| *entry == table)?;
+    MIGRATION_ORDER.get(position + 1).copied()
+}
+
+/// Migrates up to `limit` records starting at `cursor`, returning how many were
+/// processed and where to continue, or `None` once the table is done.
+fn migrate_table(table: MigrationTable, cursor: u64, limit: u64) -> (u64, Option<u64>) {
+    match table {
+        MigrationTable::Indexes => clear_indexes(limit),
+        MigrationTable::LegacyDocIdentities => drain_legacy_docidentities(limit),
+        MigrationTable::Identities => {
+            rewrite_batch(&IDENTITY_STORAGE, cursor, limit, index_identity)
+        }
+        MigrationTable::Patients => rewrite_batch(&PATIENT_STORAGE, cursor, limit, index_patient),
+        MigrationTable::Doctors => rewrite_batch(&DOCTOR_STORAGE, cursor, limit, index_doctor),
+        MigrationTable::Appointments => {
+            rewrite_batch(&APPOINTMENT_STORAGE, cursor, limit, index_appointment)
+        }
+        MigrationTable::Messages => rewrite_batch(&MESSAGE_STORAGE, cursor, limit, index_message),
+        MigrationTable::MedicalRecords => {
+            rewrite_batch(&MEDICAL_RECORD_STORAGE, cursor, limit, with_legacy_revision)
+        }
+        MigrationTable::Reports => rewrite_batch(&REPORT_STORAGE, cursor, limit, index_report),
+        MigrationTable::Calendly => rewrite_batch(&CALENDLY_STORAGE, cursor, limit, unindexed),
+        MigrationTable::Data => rewrite_batch(&DATA_STORAGE, cursor, limit, index_data),
+        MigrationTable::Availability => {
+            rewrite_batch(&AVAILABILITY_STORAGE, cursor, limit, index_availability)
+        }
+        MigrationTable::AvailabilityRules => rewrite_batch(
+            &AVAILABILITY_RULE_STORAGE,
+            cursor,
+            limit,
+            index_availability_rule,
+        ),
+        MigrationTable::Consents => rewrite_batch(&CONSENT_STORAGE, cursor, limit, index_consent),
+        MigrationTable::Prescriptions => {
+            rewrite_batch(&PRESCRIPTION_STORAGE, cursor, limit, index_prescription)
+        }
+        MigrationTable::Allergies => rewrite_batch(&ALLERGY_STORAGE, cursor, limit, index_allergy),
+        MigrationTable::Interactions => {
+            rewrite_batch(&INTERACTION_STORAGE, cursor, limit, index_interaction)
+        }
+        MigrationTable::Immunizations => {
+            rewrite_batch(&IMMUNIZATION_STORAGE, cursor, limit, index_immunization)
+        }
+        MigrationTable::Problems => rewrite_batch(&PROBLEM_STORAGE, cursor, limit, index_problem),
+        MigrationTable::LabResults => {
+            rewrite_batch(&LAB_RESULT_STORAGE, cursor, limit, index_lab_result)
+        }
+        MigrationTable::Vitals => rewrite_batch(&VITAL_STORAGE, cursor, limit, index_vital),
+        MigrationTable::Blobs => rewrite_batch(&BLOB_STORAGE, cursor, limit, index_blob),
+    }
+}
+
+// Decoding upgrades a record to the current layout; inserting it back
+// persists it in the current envelope and `index` fills its secondary indexes.
+// A legacy record that used its whole `MAX_SIZE` has no room for the envelope
+// and stays in its old layout, which still decodes.
+fn rewrite_batch<V: BoundedStorable>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    cursor: u64,
+    limit: u64,
+    index: fn(&V),
+) -> (u64, Option<u64>) {
+    storage.with(|service| {
+        let mut map = service.borrow_mut();
+        let mut batch: Vec<(u64, V)> = map.range(cursor..).take(limit as usize + 1).collect();
+
+        let next_cursor = if batch.len() as u64 > limit {
+            batch.pop().map(|(key, _)| key)
+        } else {
+            None
+        };
+
+        let processed = batch.len() as u64;
+        for (key, value) in batch {
+            index(&value);
+            if fits(&value) {
+                map.insert(key, value);
+            }
+        }
+
+        (processed, next_cursor)
+    })
+}
+
+fn unindexed<V>(_: &V) {}
+
+// Records written before revisions were kept get their first revision when
+// they are rewritten; it is already in place for every other record
+fn with_legacy_revision(record: &MedicalRecord) {
+    index_medical_record(record);
+    store_legacy_revision(record);
+}
+
+// Stores what a rewritten legacy record carried inline in the table that
+// holds it now
+fn store_split(split: LegacySplit) {
+    match split {
+        LegacySplit::Payload {
+            record_id,
+            link,
+            content_type,
+            data,
+        } => store_legacy_payload(record_id, link, legacy_owner(link), content_type, data),
+        LegacySplit::StatusHistory {
+            appointment_id,
+            changes,
+        } => store_legacy_status_history(appointment_id, changes),
+        LegacySplit::Reschedules {
+            appointment_id,
+            reschedules,
+        } => store_legacy_reschedules(appointment_id, reschedules),
+        LegacySplit::Prescription {
+            report_id,
+            patient_id,
+            text,
+        } => store_legacy_prescription(report_id, patient_id, text),
+    }
+}
+
+// Legacy attachments belong to the sender of the message, or the patient of
+// the report or data record
+fn legacy_owner(link: BlobLink) -> String {
+    let party_id = match link {
+        BlobLink::Message(message_id) => {
+            get_message_by_id(&message_id).map(|message| message.sender_id)
+        }
+        BlobLink::Report(report_id) => get_report_by_id(&report_id).map(|report| report.patient_id),
+        BlobLink::Data(data_id) => get_data_by_id(&data_id)
+            .and_then(|data| get_patient_by_username(&data.patient_username))
+            .map(|patient| patient.id),
+    };
+    party_id.and_then(party_principal).unwrap_or_default()
+}
+
+// Principals of the retired doctor identity table become Doctor role holders
+fn drain_legacy_docidentities(limit: u64) -> (u64, Option<u64>) {
+    let batch: Vec<_> = LEGACY_DOCIDENTITY_STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .take(limit as usize)
+            .collect::<Vec<_>>()
+    });
+
+    for (key, docidentity) in &batch {
+        add_role(docidentity.principal.clone(), Role::Doctor);
+        LEGACY_DOCIDENTITY_STORAGE.with(|service| service.borrow_mut().remove(key));
+    }
+
+    let remaining = LEGACY_DOCIDENTITY_STORAGE.with(|service| !service.borrow().is_empty());
+    (batch.len() as u64, if remaining { Some(0) } else { None })
+}
+
+/// Fresh installs start out with every record in the current layout.
+pub fn mark_schema_current() {
+    let mut state = read_state();
+    state.schema_version = SCHEMA_VERSION;
+    write_state(state);
+}
+
+/// Indexes are only complete once every table has been indexed by the
+/// migration pass; until then lookups scan the tables. A release without the
+/// migration state has no indexes at all.
+pub fn indexes_complete() -> bool {
+    let state = read_state();
+    state.in_progress.is_none() && state.schema_version >= SCHEMA_VERSION
+}
+
+/// Rejects edits while a migration runs: a record not rewritten yet may still
+/// hold legacy data that only the migration moves to its new table.
+pub fn require_schema_current() -> Result<(), Error> {
+    match read_state().in_progress {
+        Some(progress) => Err(Error::InvalidInput {
+            msg: format!(
+                "Migration to schema version {} is in progress, retry later",
+                progress.target_version
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn read_state() -> MigrationState {
+    MIGRATION_STATE.with(|cell| cell.borrow().get().clone())
+}
+
+fn write_state(state: MigrationState) {
+    MIGRATION_STATE
+        .with(|cell| cell.borrow_mut().set(state))
+        .expect("cannot write migration state");
+}
\ No newline at end of file
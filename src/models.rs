@@ -1,10 +1,15 @@
 //! Data models for the medical appointment system
 
 use candid::CandidType;
+use ic_stable_structures::{BoundedStorable, Storable};
 use serde::{Deserialize, Serialize};
-use ic_stable_structures::{Storable, BoundedStorable};
 use std::borrow::Cow;
 
+use crate::schema;
+
+/// Size of every blob chunk except the last one of a blob.
+pub const BLOB_CHUNK_SIZE: u32 = 256 * 1024;
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Patient {
     pub id: u64,
@@ -25,6 +30,8 @@ pub struct Doctor {
     pub sex: String,
     pub country: String,
     pub city: String,
+    /// IANA time zone the doctor works in, e.g. "Africa/Nairobi".
+    pub time_zone: String,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -33,13 +40,57 @@ pub struct Appointment {
     pub patient_id: u64,
     pub doctor_id: u64,
     pub phone_no: String,
-    pub slot: String,
+    /// Booked window, nanoseconds since the Unix epoch (UTC).
+    pub slot_start: u64,
+    pub slot_end: u64,
     pub reason: String,
     pub symtoms: String,
-    pub status: String,
+    pub status: AppointmentStatus,
     pub appointment_type: String,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppointmentStatus {
+    Requested,
+    Confirmed,
+    CheckedIn,
+    InProgress,
+    Completed,
+    Cancelled,
+    NoShow,
+    Rescheduled,
+    /// Booked before appointments had a date; it needs a slot through
+    /// `reschedule_appointment`.
+    Unscheduled,
+}
+
+/// One entry per transition, recording when and by whom it happened.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct StatusChange {
+    pub status: AppointmentStatus,
+    pub at: u64,
+    pub by: String,
+}
+
+/// Status changes and moves of one appointment, oldest first.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AppointmentHistory {
+    pub appointment_id: u64,
+    pub status_history: Vec<StatusChange>,
+    pub reschedules: Vec<Reschedule>,
+}
+
+/// A move of an appointment from one slot to another.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Reschedule {
+    pub from_start: u64,
+    pub from_end: u64,
+    pub to_start: u64,
+    pub to_end: u64,
+    pub at: u64,
+    pub by: String,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Message {
     pub id: u64,
@@ -52,7 +103,7 @@ pub struct Message {
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct MultiMediaContent {
     pub content_type: String,
-    pub data: Vec<u8>,
+    pub blob_id: u64,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -61,42 +112,138 @@ pub struct MedicalRecord {
     pub patient_id: u64,
     pub lab_results: String,
     pub treatment_history: String,
+    /// Number of the latest revision, starting at 1.
+    pub revision: u32,
+    /// Set when the record is deleted. The deleted record keeps no contents,
+    /// but its revisions stay readable until the patient is erased.
+    pub deleted_at: Option<u64>,
+}
+
+/// Immutable snapshot of a medical record, written by its creation and by
+/// every update.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct MedicalRecordRevision {
+    pub record_id: u64,
+    pub revision: u32,
+    pub patient_id: u64,
+    pub lab_results: String,
+    pub treatment_history: String,
+    pub author: String,
+    pub created_at: u64,
+    /// Why the record was changed; empty for the first revision.
+    pub reason: String,
+}
+
+/// A field that differs between two revisions.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct FieldChange {
+    pub field: String,
+    pub from: String,
+    pub to: String,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Report {
     pub id: u64,
     pub patient_id: u64,
+    /// The authoring doctor; `None` on reports written before reports named
+    /// their author, and on addenda admins wrote before addenda came from the
+    /// visit's doctor, until a doctor claims the report.
+    pub doctor_id: Option<u64>,
+    /// The completed visit the report was written for; `None` on reports
+    /// written before reports were linked to visits.
+    pub appointment_id: Option<u64>,
     pub username: String,
     pub symptoms: String,
     pub diagnostic: String,
-    pub prescription: String,
     pub recommendations: String,
     pub multimedia_content: Option<MultiMediaContent>,
+    /// The signed report this addendum amends.
+    pub amends: Option<u64>,
+    /// Set when a doctor signs the report; a signed report never changes.
+    pub signature: Option<ReportSignature>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
-pub struct Identity {
-    pub id: u64,
+pub struct ReportSignature {
+    pub doctor_id: u64,
     pub principal: String,
+    pub signed_at: u64,
+    /// SHA-256 of the report content at signing, hex-encoded.
+    pub content_hash: String,
+}
+
+/// A signed report checked against its signature.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct SignatureVerification {
+    pub report_id: u64,
+    pub signature: ReportSignature,
+    /// Hash of the report content as stored now.
+    pub content_hash: String,
+    pub valid: bool,
+    /// Addenda amending the report, oldest first.
+    pub addendum_ids: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Doctor,
+    Patient,
+    Nurse,
+    Receptionist,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
-pub struct DocIdentity {
+pub struct Identity {
     pub id: u64,
     pub principal: String,
+    pub roles: Vec<Role>,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Availability {
     pub id: u64,
     pub doctor_id: u64,
-    pub day_of_week: u8,
-    pub start_time: String,
-    pub end_time: String,
+    /// Window bounds, nanoseconds since the Unix epoch (UTC).
+    pub start_time: u64,
+    pub end_time: u64,
     pub is_available: bool,
 }
 
+/// Weekly pattern expanded into concrete `Slot`s on demand. Times of day are
+/// minutes after midnight in the doctor's time zone.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AvailabilityRule {
+    pub id: u64,
+    pub doctor_id: u64,
+    /// Days the rule applies to, 0 = Sunday through 6 = Saturday.
+    pub weekdays: Vec<u8>,
+    pub start_minute: u32,
+    pub end_minute: u32,
+    pub slot_minutes: u32,
+    /// Gap left after every slot.
+    pub buffer_minutes: u32,
+    /// Nanoseconds since the Unix epoch; slots starting outside are skipped.
+    pub effective_from: u64,
+    pub effective_until: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlotSource {
+    Window(u64),
+    Rule(u64),
+}
+
+/// A concrete bookable occurrence, times in nanoseconds since the Unix epoch.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Slot {
+    pub doctor_id: u64,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub source: SlotSource,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Calendly {
     pub id: u64,
@@ -109,17 +256,638 @@ pub struct Data {
     pub id: u64,
     pub patient_username: String,
     pub doctor_username: String,
-    pub data: Vec<u8>,
+    pub blob_id: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobStatus {
+    Uploading,
+    Finalized,
+}
+
+/// The record a blob is attached to; readers of the record may read the blob.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobLink {
+    Message(u64),
+    Report(u64),
+    Data(u64),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Blob {
+    pub id: u64,
+    pub owner: String,
+    pub content_type: String,
+    pub declared_size: u64,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub sha256: Option<String>,
+    pub status: BlobStatus,
+    pub attached_to: Option<BlobLink>,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlobChunkKey {
+    pub blob_id: u64,
+    pub index: u32,
+}
+
+/// Secondary index entry linking a record to its owner, e.g. an appointment
+/// to its doctor. Keys sort by owner first so one owner's records are a range.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexKey {
+    pub owner: u64,
+    pub id: u64,
+}
+
+/// SHA-256 of a username or principal, keying the name indexes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NameKey(pub [u8; 32]);
+
+/// Raw chunk bytes, stored without a Candid envelope.
+#[derive(Clone)]
+pub struct BlobChunk(pub Vec<u8>);
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEntity {
+    MedicalRecord,
+    Report,
+    Data,
+    Blob,
+    Consent,
+    Prescription,
+    Allergy,
+    Immunization,
+    Problem,
+    LabResult,
+    Vital,
+    Patient,
+    Appointment,
+    Message,
+    Identity,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    Read,
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    Success,
+    Denied(String),
+    Failed(String),
+}
+
+/// Entry of the append-only audit log. `hash` commits to every other field,
+/// including `prev_hash`, the hash of the preceding entry.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub at: u64,
+    pub caller: String,
+    pub endpoint: String,
+    pub entity: AuditEntity,
+    pub entity_id: u64,
+    pub action: AuditAction,
+    pub outcome: AuditOutcome,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AuditFilter {
+    pub caller: Option<String>,
+    pub entity: Option<AuditEntity>,
+    pub entity_id: Option<u64>,
+    /// Only entries written in `[from, to)`.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AuditFailure {
+    pub seq: u64,
+    pub reason: String,
+}
+
+/// Result of checking a range of the audit chain. `head_hash` is the hash of
+/// the newest entry, for anchoring outside the canister.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AuditVerification {
+    pub checked: u64,
+    pub next_seq: Option<u64>,
+    pub head_hash: String,
+    pub failure: Option<AuditFailure>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsentScope {
+    /// Medical records and data records.
+    ReadRecords,
+    /// Writing medical records, data records and the patient chart.
+    WriteRecords,
+    ReadReports,
+    /// Writing and editing reports; only doctors can hold it.
+    WriteReports,
+}
+
+/// Who a grant is for: one doctor, or every holder of a role.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsentGrantee {
+    Doctor(u64),
+    Role(Role),
+}
+
+/// Permission from a patient to access their clinical data. Revoked grants
+/// are kept, with `revoked_at` set, as a record of past consent.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ConsentGrant {
+    pub id: u64,
+    pub patient_id: u64,
+    pub grantee: ConsentGrantee,
+    pub scopes: Vec<ConsentScope>,
+    pub granted_at: u64,
+    /// Nanoseconds since the Unix epoch; `None` lasts until revoked.
+    pub expires_at: Option<u64>,
+    pub revoked_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrescriptionStatus {
+    Active,
+    /// Handed out with no refills left.
+    Dispensed,
+    Expired,
+    Revoked,
+}
+
+/// One medication of a prescription.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct MedicationLine {
+    pub medication: String,
+    /// Amount per intake, e.g. "500 mg".
+    pub dose: String,
+    /// e.g. "oral", "topical".
+    pub route: String,
+    /// e.g. "twice daily".
+    pub frequency: String,
+    pub duration_days: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Prescription {
+    pub id: u64,
+    pub patient_id: u64,
+    pub report_id: u64,
+    /// 0 for prescriptions carried over from free-text reports.
+    pub doctor_id: u64,
+    pub lines: Vec<MedicationLine>,
+    /// Dispensings left after the current one.
+    pub refills: u32,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub status: PrescriptionStatus,
+    /// Prescription this one renews.
+    pub renewed_from: Option<u64>,
+    /// Free-text prescription of reports written before structured ones.
+    pub notes: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllergySeverity {
+    Mild,
+    Moderate,
+    Severe,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Allergy {
+    pub id: u64,
+    pub patient_id: u64,
+    /// Drug or other substance, matched against the interaction table.
+    pub substance: String,
+    pub reaction: String,
+    pub severity: AllergySeverity,
+    pub recorded_at: u64,
+    pub recorded_by: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Immunization {
+    pub id: u64,
+    pub patient_id: u64,
+    pub vaccine: String,
+    /// Position in the vaccine's series, starting at 1.
+    pub dose_number: u32,
+    pub administered_at: u64,
+    /// Manufacturer lot; empty when unknown, e.g. for doses given elsewhere.
+    pub lot_number: String,
+    pub recorded_at: u64,
+    pub recorded_by: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProblemStatus {
+    Active,
+    Inactive,
+    Resolved,
+}
+
+/// Entry of the patient's problem list.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Problem {
+    pub id: u64,
+    pub patient_id: u64,
+    pub description: String,
+    /// Diagnosis code such as ICD-10, if known.
+    pub code: Option<String>,
+    pub status: ProblemStatus,
+    pub onset_at: Option<u64>,
+    /// Set when the problem is marked `Resolved`, cleared if it is reopened.
+    pub resolved_at: Option<u64>,
+    pub recorded_at: u64,
+    pub recorded_by: String,
+}
+
+/// Bounds of a lab test, in the result's unit. Values outside `low`..`high`
+/// are abnormal, values outside the critical bounds are critical.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ReferenceRange {
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+    pub critical_low: Option<f64>,
+    pub critical_high: Option<f64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LabFlag {
+    Normal,
+    Low,
+    High,
+    CriticalLow,
+    CriticalHigh,
+}
+
+/// Measured value of a lab result.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum LabValue {
+    Numeric(f64),
+    /// Qualitative result such as `positive` or a titre like `1:80`.
+    Text(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct LabResult {
+    pub id: u64,
+    pub patient_id: u64,
+    /// LOINC-style test code, e.g. `2345-7` for serum glucose.
+    pub test_code: String,
+    pub test_name: String,
+    pub value: LabValue,
+    pub unit: String,
+    /// Set for numeric results only.
+    pub reference_range: Option<ReferenceRange>,
+    /// Derived from a numeric `value` and its `reference_range` when the
+    /// result is recorded; qualitative results are not flagged.
+    pub flag: Option<LabFlag>,
+    pub collected_at: u64,
+    pub recorded_at: u64,
+    pub recorded_by: String,
+}
+
+/// Narrows a patient's lab results to one test and a collection window.
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct LabResultFilter {
+    pub test_code: Option<String>,
+    /// Inclusive lower bound of `collected_at`.
+    pub from: Option<u64>,
+    /// Exclusive upper bound of `collected_at`.
+    pub to: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VitalType {
+    BloodPressure,
+    HeartRate,
+    Temperature,
+    SpO2,
+    Weight,
+    Glucose,
+}
+
+/// A vital sign reading in its fixed unit.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum VitalMeasurement {
+    /// mmHg
+    BloodPressure { systolic: f64, diastolic: f64 },
+    /// Beats per minute
+    HeartRate(f64),
+    /// Degrees Celsius
+    Temperature(f64),
+    /// Percent oxygen saturation
+    SpO2(f64),
+    /// Kilograms
+    Weight(f64),
+    /// mmol/L
+    Glucose(f64),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Vital {
+    pub id: u64,
+    pub patient_id: u64,
+    pub measurement: VitalMeasurement,
+    pub measured_at: u64,
+    /// Visit the reading was taken at; unset for home readings.
+    pub appointment_id: Option<u64>,
+    pub recorded_at: u64,
+    pub recorded_by: String,
+}
+
+/// Narrows a patient's vitals to one type and a measurement window.
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct VitalFilter {
+    pub vital_type: Option<VitalType>,
+    /// Inclusive lower bound of `measured_at`.
+    pub from: Option<u64>,
+    /// Exclusive upper bound of `measured_at`.
+    pub to: Option<u64>,
+}
+
+/// Summary of one vital sign component, e.g. systolic pressure, over a window.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct VitalStats {
+    pub vital_type: VitalType,
+    /// `systolic` or `diastolic` for blood pressure, `value` otherwise.
+    pub component: String,
+    pub unit: String,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub latest: f64,
+    pub latest_at: u64,
+    /// Latest reading minus the last one taken at an earlier visit.
+    pub change_since_last_visit: Option<f64>,
+}
+
+/// The patient's chart: profile, records and the clinical lists kept beside them.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PatientChart {
+    pub patient: Patient,
+    pub allergies: Vec<Allergy>,
+    pub immunizations: Vec<Immunization>,
+    /// Active problems first.
+    pub problems: Vec<Problem>,
+    pub medical_records: Vec<MedicalRecord>,
+    /// Oldest first.
+    pub lab_results: Vec<LabResult>,
+    pub vital_stats: Vec<VitalStats>,
+}
+
+/// Severities from `Contraindicated` up block prescribing; the rest warn.
+#[derive(
+    CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum InteractionSeverity {
+    Minor,
+    Moderate,
+    Major,
+    Contraindicated,
+}
+
+/// What a drug interacts with. Names are compared case-insensitively.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum InteractionTarget {
+    Drug(String),
+    Allergen(String),
+}
+
+/// Row of the uploadable interaction table.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Interaction {
+    pub id: u64,
+    pub drug: String,
+    pub target: InteractionTarget,
+    pub severity: InteractionSeverity,
+    pub description: String,
+}
+
+/// An interaction found for a medication being prescribed.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct InteractionWarning {
+    /// Row of the interaction table; `None` when the medication is itself a
+    /// recorded allergen.
+    pub interaction_id: Option<u64>,
+    pub medication: String,
+    pub target: InteractionTarget,
+    pub severity: InteractionSeverity,
+    pub description: String,
+    /// Active prescription holding the other drug, if it is not in the same prescription.
+    pub prescription_id: Option<u64>,
+    /// Allergy entry naming the allergen.
+    pub allergy_id: Option<u64>,
+}
+
+/// A prescription as issued, with the interactions that did not block it.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct IssuedPrescription {
+    pub prescription: Prescription,
+    pub warnings: Vec<InteractionWarning>,
+}
+
+/// Everything linked to a patient and what erasure does with it.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ErasurePlan {
+    pub patient_id: u64,
+    pub medical_record_ids: Vec<u64>,
+    pub report_ids: Vec<u64>,
+    pub data_ids: Vec<u64>,
+    pub message_ids: Vec<u64>,
+    pub consent_ids: Vec<u64>,
+    pub prescription_ids: Vec<u64>,
+    pub allergy_ids: Vec<u64>,
+    pub immunization_ids: Vec<u64>,
+    pub problem_ids: Vec<u64>,
+    pub lab_result_ids: Vec<u64>,
+    pub vital_ids: Vec<u64>,
+    /// Appointments that have not ended; deleting them frees their slots.
+    pub deleted_appointment_ids: Vec<u64>,
+    /// Past appointments, kept for the doctor's history without patient details.
+    pub anonymized_appointment_ids: Vec<u64>,
+    /// Attachments of the erased records and unattached uploads of the patient.
+    pub blob_ids: Vec<u64>,
+    pub identity_id: Option<u64>,
+    /// False when the identity holds other roles and only loses `Patient`.
+    pub identity_deleted: bool,
+}
+
+/// Proof of an erasure. Every erased item has an audit entry in
+/// `[first_audit_seq, last_audit_seq]`; `audit_hash` is the hash of the last.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ErasureReceipt {
+    pub plan: ErasurePlan,
+    pub erased_at: u64,
+    pub erased_by: String,
+    pub first_audit_seq: u64,
+    pub last_audit_seq: u64,
+    pub audit_hash: String,
+}
+
+/// One page of everything the canister holds about a patient. Attachment
+/// contents are not inlined; `blobs` lists them for download with `read_blob`.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PatientExport {
+    pub exported_at: u64,
+    pub patient: Patient,
+    pub appointments: Vec<Appointment>,
+    pub appointment_history: Vec<AppointmentHistory>,
+    pub medical_records: Vec<MedicalRecord>,
+    pub medical_record_revisions: Vec<MedicalRecordRevision>,
+    pub reports: Vec<Report>,
+    pub prescriptions: Vec<Prescription>,
+    pub allergies: Vec<Allergy>,
+    pub immunizations: Vec<Immunization>,
+    pub problems: Vec<Problem>,
+    pub lab_results: Vec<LabResult>,
+    pub vitals: Vec<Vital>,
+    pub messages: Vec<Message>,
+    pub data: Vec<Data>,
+    pub consents: Vec<ConsentGrant>,
+    pub blobs: Vec<Blob>,
+    /// Cursor of the next page, or `None` on the last one.
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrationTable {
+    /// Stale index entries, cleared by `rebuild_indexes` before the tables
+    /// are indexed again
+    Indexes,
+    LegacyDocIdentities,
+    Identities,
+    Patients,
+    Doctors,
+    Appointments,
+    Messages,
+    MedicalRecords,
+    Reports,
+    Calendly,
+    Data,
+    Availability,
+    AvailabilityRules,
+    Consents,
+    Prescriptions,
+    Allergies,
+    Interactions,
+    Immunizations,
+    Problems,
+    LabResults,
+    Vitals,
+    Blobs,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct MigrationProgress {
+    pub target_version: u32,
+    pub table: MigrationTable,
+    pub cursor: u64,
+    pub rewritten: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct MigrationState {
+    pub schema_version: u32,
+    pub upgraded_from: u32,
+    pub in_progress: Option<MigrationProgress>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+/// Page of a list endpoint. `cursor` is the `next_cursor` of the previous page,
+/// or `None` for the first page; a zero `limit` picks the default page size.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PageRequest {
+    pub cursor: Option<u64>,
+    pub limit: u32,
+    pub order: SortOrder,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct PatientFilter {
+    pub username_prefix: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct DoctorFilter {
+    pub specialism: Option<String>,
+    pub country: Option<String>,
+    pub city: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AppointmentFilter {
+    pub doctor_id: Option<u64>,
+    pub patient_id: Option<u64>,
+    pub status: Option<AppointmentStatus>,
+    /// Only appointments whose slot starts in `[from, to)`.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct MessageFilter {
+    pub sender_id: Option<u64>,
+    pub receiver_id: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct MedicalRecordFilter {
+    pub patient_id: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ReportFilter {
+    pub patient_id: Option<u64>,
+    /// Only the addenda of this report.
+    pub amends: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct IdentityFilter {
+    pub role: Option<Role>,
 }
 
-// Implement Storable and BoundedStorable for all types to work with stable structures
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct AvailabilityFilter {
+    pub doctor_id: Option<u64>,
+    pub is_available: Option<bool>,
+}
+
+// Implement Storable and BoundedStorable for all types to work with stable structures.
+// Records are wrapped in versioned envelopes, see `schema`.
 impl Storable for Patient {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
@@ -130,11 +898,11 @@ impl BoundedStorable for Patient {
 
 impl Storable for Doctor {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
@@ -145,26 +913,56 @@ impl BoundedStorable for Doctor {
 
 impl Storable for Appointment {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
 impl BoundedStorable for Appointment {
-    const MAX_SIZE: u32 = 1536; // Adjust based on your needs
+    const MAX_SIZE: u32 = 1536; // Fixed by the existing map; see `MAX_APPOINTMENT_TEXT_LEN`
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for StatusChange {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for StatusChange {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Reschedule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Reschedule {
+    const MAX_SIZE: u32 = 256;
     const IS_FIXED_SIZE: bool = false;
 }
 
 impl Storable for Message {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
@@ -175,11 +973,11 @@ impl BoundedStorable for Message {
 
 impl Storable for MedicalRecord {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
@@ -188,13 +986,28 @@ impl BoundedStorable for MedicalRecord {
     const IS_FIXED_SIZE: bool = false;
 }
 
+impl Storable for MedicalRecordRevision {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for MedicalRecordRevision {
+    const MAX_SIZE: u32 = 2560;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 impl Storable for Report {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
@@ -205,11 +1018,11 @@ impl BoundedStorable for Report {
 
 impl Storable for Identity {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
@@ -218,43 +1031,163 @@ impl BoundedStorable for Identity {
     const IS_FIXED_SIZE: bool = false;
 }
 
-impl Storable for DocIdentity {
+impl Storable for Availability {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
-impl BoundedStorable for DocIdentity {
-    const MAX_SIZE: u32 = 512; // Smaller for identity data
+impl BoundedStorable for Availability {
+    const MAX_SIZE: u32 = 512; // Smaller for availability data
     const IS_FIXED_SIZE: bool = false;
 }
 
-impl Storable for Availability {
+impl Storable for AvailabilityRule {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
-impl BoundedStorable for Availability {
-    const MAX_SIZE: u32 = 512; // Smaller for availability data
+impl BoundedStorable for AvailabilityRule {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ConsentGrant {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for ConsentGrant {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Prescription {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Prescription {
+    const MAX_SIZE: u32 = 4096; // Room for the medication lines
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Allergy {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Allergy {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Immunization {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Immunization {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Problem {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Problem {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for LabResult {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for LabResult {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Vital {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Vital {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Interaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Interaction {
+    const MAX_SIZE: u32 = 1024;
     const IS_FIXED_SIZE: bool = false;
 }
 
 impl Storable for Calendly {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
@@ -265,15 +1198,132 @@ impl BoundedStorable for Calendly {
 
 impl Storable for Data {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(candid::encode_one(self).unwrap())
+        Cow::Owned(schema::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+        schema::decode(&bytes)
     }
 }
 
 impl BoundedStorable for Data {
-    const MAX_SIZE: u32 = 8192; // Larger for binary data
+    const MAX_SIZE: u32 = 1024; // Payload lives in the blob store
     const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for MigrationState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl Storable for Blob {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(schema::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        schema::decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Blob {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for BlobChunkKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(12);
+        bytes.extend_from_slice(&self.blob_id.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut blob_id = [0u8; 8];
+        let mut index = [0u8; 4];
+        blob_id.copy_from_slice(&bytes[..8]);
+        index.copy_from_slice(&bytes[8..12]);
+        BlobChunkKey {
+            blob_id: u64::from_be_bytes(blob_id),
+            index: u32::from_be_bytes(index),
+        }
+    }
+}
+
+impl BoundedStorable for BlobChunkKey {
+    const MAX_SIZE: u32 = 12;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for BlobChunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        BlobChunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for BlobChunk {
+    const MAX_SIZE: u32 = BLOB_CHUNK_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for IndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.owner.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut owner = [0u8; 8];
+        let mut id = [0u8; 8];
+        owner.copy_from_slice(&bytes[..8]);
+        id.copy_from_slice(&bytes[8..16]);
+        IndexKey {
+            owner: u64::from_be_bytes(owner),
+            id: u64::from_be_bytes(id),
+        }
+    }
+}
+
+impl BoundedStorable for IndexKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for NameKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&bytes[..32]);
+        NameKey(hash)
+    }
+}
+
+impl BoundedStorable for NameKey {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
 }
\ No newline at end of file
@@ -0,0 +1,231 @@
+//! Cursor-based pagination shared by the list endpoints
+//!
+//! Cursors are record keys: a page starts at its cursor, inclusive, and
+//! `next_cursor` is the key the following page starts at. Filters and the
+//! caller's visibility are applied before records count towards the page
+//! limit. A call examines at most `MAX_PAGE_SCAN` records, so a page may hold
+//! fewer records than its limit, or none, while `next_cursor` is still set.
+
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+use crate::models::{Page, PageRequest, SortOrder};
+use crate::storage::Memory;
+
+/// Page size used when a request leaves `limit` at zero.
+pub const DEFAULT_PAGE_LIMIT: u32 = 50;
+
+/// Largest page returned, keeping replies below the message size limit.
+pub const MAX_PAGE_LIMIT: u32 = 200;
+
+/// Records a single call examines before it returns what it has found.
+pub const MAX_PAGE_SCAN: usize = 1000;
+
+// Width of the first key range read backwards by a descending page
+const DESCENDING_SPAN: u64 = 64;
+
+type Table<V> = &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>;
+
+pub fn paginate<V: BoundedStorable>(
+    table: Table<V>,
+    page: &PageRequest,
+    keep: impl Fn(&V) -> bool,
+) -> Page<V> {
+    let limit = page_limit(page.limit);
+
+    table.with(|table| {
+        let table = table.borrow();
+        match page.order {
+            SortOrder::Ascending => {
+                collect_page(table.range(page.cursor.unwrap_or(0)..), limit, keep)
+            }
+            SortOrder::Descending => collect_page(
+                descending(&table, page.cursor.unwrap_or(u64::MAX), limit + 1),
+                limit,
+                keep,
+            ),
+        }
+    })
+}
+
+/// Pages through the records of `ids`, in ascending order as returned by the
+/// index lookups, so that a filtered listing only loads the matching records.
+pub fn paginate_ids<V>(
+    ids: Vec<u64>,
+    page: &PageRequest,
+    get: impl Fn(&u64) -> Option<V>,
+    keep: impl Fn(&V) -> bool,
+) -> Page<V> {
+    let limit = page_limit(page.limit);
+    let load = |id: u64| get(&id).map(|value| (id, value));
+
+    match page.order {
+        SortOrder::Ascending => {
+            let cursor = page.cursor.unwrap_or(0);
+            let entries = ids.into_iter().filter(|id| *id >= cursor);
+            collect_page(entries.filter_map(load), limit, keep)
+        }
+        SortOrder::Descending => {
+            let cursor = page.cursor.unwrap_or(u64::MAX);
+            let entries = ids.into_iter().rev().filter(|id| *id <= cursor);
+            collect_page(entries.filter_map(load), limit, keep)
+        }
+    }
+}
+
+fn collect_page<V>(
+    entries: impl Iterator<Item = (u64, V)>,
+    limit: usize,
+    keep: impl Fn(&V) -> bool,
+) -> Page<V> {
+    let mut items = Vec::with_capacity(limit);
+    let mut next_cursor = None;
+    let mut scanned = 0;
+    for (key, value) in entries {
+        if items.len() == limit || scanned == MAX_PAGE_SCAN {
+            next_cursor = Some(key);
+            break;
+        }
+        scanned += 1;
+        if keep(&value) {
+            items.push(value);
+        }
+    }
+    Page { items, next_cursor }
+}
+
+// Stable maps only iterate forwards, so walk backwards from the cursor in
+// key ranges that double in width. A range holding more than `step` entries
+// is read again at half the width, so no step loads more than `step` + 1
+fn descending<V: BoundedStorable>(
+    table: &StableBTreeMap<u64, V, Memory>,
+    cursor: u64,
+    step: usize,
+) -> impl Iterator<Item = (u64, V)> + '_ {
+    let step = step.max(1);
+    let mut upper = Some(cursor);
+    let mut span = DESCENDING_SPAN;
+    let mut chunk: Vec<(u64, V)> = Vec::new();
+
+    std::iter::from_fn(move || loop {
+        if let Some(entry) = chunk.pop() {
+            return Some(entry);
+        }
+        let high = upper?;
+        let low = high.saturating_sub(span);
+        chunk = table.range(low..=high).take(step + 1).collect();
+        if chunk.len() > step {
+            // A range one key wide holds a single entry, so this ends
+            chunk.clear();
+            span /= 2;
+            continue;
+        }
+        upper = low.checked_sub(1);
+        span = span.saturating_mul(2).max(1);
+    })
+}
+
+/// Page size for a requested `limit`, applying the default and the maximum.
+pub fn page_limit(limit: u32) -> usize {
+    let limit = match limit {
+        0 => DEFAULT_PAGE_LIMIT,
+        limit => limit.min(MAX_PAGE_LIMIT),
+    };
+    limit as usize
+}
+
+/// True when the filter is unset or equals `value`.
+pub fn matches<T: PartialEq>(filter: &Option<T>, value: &T) -> bool {
+    filter.as_ref().map_or(true, |expected| expected == value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    fn table(keys: impl IntoIterator<Item = u64>) -> StableBTreeMap<u64, u64, Memory> {
+        let memory = MemoryManager::init(DefaultMemoryImpl::default()).get(MemoryId::new(0));
+        let mut table = StableBTreeMap::init(memory);
+        for key in keys {
+            table.insert(key, key);
+        }
+        table
+    }
+
+    fn keys_from(table: &StableBTreeMap<u64, u64, Memory>, cursor: u64, step: usize) -> Vec<u64> {
+        descending(table, cursor, step)
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn descending_walks_back_from_the_cursor() {
+        let table = table([1, 5, 64, 65, 200, 1000]);
+
+        assert_eq!(
+            keys_from(&table, u64::MAX, 10),
+            vec![1000, 200, 65, 64, 5, 1]
+        );
+        // The cursor itself is included
+        assert_eq!(keys_from(&table, 200, 10), vec![200, 65, 64, 5, 1]);
+        assert_eq!(keys_from(&table, 199, 10), vec![65, 64, 5, 1]);
+        assert!(keys_from(&table, 0, 10).is_empty());
+    }
+
+    #[test]
+    fn descending_reaches_both_ends_of_the_key_space() {
+        let table = table([0, 3, u64::MAX - 1, u64::MAX]);
+
+        assert_eq!(
+            keys_from(&table, u64::MAX, 10),
+            vec![u64::MAX, u64::MAX - 1, 3, 0]
+        );
+    }
+
+    #[test]
+    fn descending_narrows_ranges_denser_than_a_step() {
+        let table = table(0..500);
+        let expected: Vec<u64> = (0..500).rev().collect();
+
+        assert_eq!(keys_from(&table, u64::MAX, 1), expected);
+        assert_eq!(keys_from(&table, u64::MAX, 3), expected);
+        assert_eq!(keys_from(&table, 499, 200), expected);
+    }
+
+    #[test]
+    fn pages_end_at_the_limit_with_a_cursor() {
+        let page = collect_page((0..10).map(|key| (key, key)), 3, |_| true);
+        assert_eq!(page.items, vec![0, 1, 2]);
+        assert_eq!(page.next_cursor, Some(3));
+
+        let last = collect_page((7..10).map(|key| (key, key)), 3, |_| true);
+        assert_eq!(last.items, vec![7, 8, 9]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn filtered_records_do_not_count_towards_the_limit() {
+        let page = collect_page((0..10).map(|key| (key, key)), 2, |key| key % 3 == 0);
+        assert_eq!(page.items, vec![0, 3]);
+        assert_eq!(page.next_cursor, Some(4));
+    }
+
+    #[test]
+    fn pages_stop_scanning_with_a_cursor_to_resume_from() {
+        let total = MAX_PAGE_SCAN as u64 + 10;
+        let page = collect_page((0..total).map(|key| (key, key)), 5, |key| *key == total - 1);
+
+        assert!(page.items.is_empty());
+        assert_eq!(page.next_cursor, Some(MAX_PAGE_SCAN as u64));
+    }
+
+    #[test]
+    fn page_limits_default_and_cap() {
+        assert_eq!(page_limit(0), DEFAULT_PAGE_LIMIT as usize);
+        assert_eq!(page_limit(7), 7);
+        assert_eq!(page_limit(MAX_PAGE_LIMIT + 1), MAX_PAGE_LIMIT as usize);
+    }
+}
\ No newline at end of file
@@ -1,13 +1,24 @@
 //! Patient management functionality
 
+use crate::auth::{caller_context, unauthorized};
+use crate::erasure::erase_patient;
 use crate::error::Error;
-use crate::identity::get_identity_by_id;
-use crate::models::Patient;
-use crate::storage::{IDENTITY_STORAGE, PATIENT_STORAGE};
+use crate::identity::{add_role, get_identity_by_id};
+use crate::index::{index_patient, patient_id_by_identity, patient_id_by_username};
+use crate::models::{Page, PageRequest, Patient, PatientFilter, Role};
+use crate::pagination::paginate;
+use crate::storage::PATIENT_STORAGE;
 use crate::utils::generate_id;
 
 #[ic_cdk::query]
 pub fn get_patient(patient_id: u64) -> Result<Patient, Error> {
+    if !caller_context().can_view_patient_profile(patient_id) {
+        return Err(unauthorized(&format!(
+            "Caller may not view patient with id={}",
+            patient_id
+        )));
+    }
+
     match get_patient_by_id(&patient_id) {
         Some(patient) => Ok(patient),
         None => Err(Error::NotFound {
@@ -26,26 +37,32 @@ pub fn register_patient(username: String, identity_id: u64) -> Result<Patient, E
     }
 
     // Check if the username already exists
-    let username_exists = PATIENT_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .any(|(_, patient)| patient.username == username)
-    });
-
-    if username_exists {
+    if patient_id_by_username(&username).is_some() {
         return Err(Error::AlreadyExists {
             msg: "Username already exists".to_string(),
         });
     }
 
     // Check if the identity_id exists
-    let identity_exists =
-        IDENTITY_STORAGE.with(|service| service.borrow().contains_key(&identity_id));
+    let identity = match get_identity_by_id(&identity_id) {
+        Some(identity) => identity,
+        None => {
+            return Err(Error::NotFound {
+                msg: "Identity ID does not exist".to_string(),
+            })
+        }
+    };
+
+    // Only the owner of the identity may register it as a patient
+    let caller = caller_context();
+    if !caller.is_admin && caller.principal != identity.principal {
+        return Err(unauthorized("Caller does not own this identity"));
+    }
 
-    if !identity_exists {
-        return Err(Error::NotFound {
-            msg: "Identity ID does not exist".to_string(),
+    // An identity can back at most one patient
+    if patient_id_by_identity(identity_id).is_some() {
+        return Err(Error::AlreadyExists {
+            msg: "Identity is already registered as a patient".to_string(),
         });
     }
 
@@ -58,30 +75,37 @@ pub fn register_patient(username: String, identity_id: u64) -> Result<Patient, E
     };
 
     PATIENT_STORAGE.with(|service| service.borrow_mut().insert(id, patient.clone()));
+    index_patient(&patient);
+    add_role(identity.principal, Role::Patient);
     Ok(patient)
 }
 
+/// Erases the patient with everything linked to them; see `erase_patient`
+/// for the receipt.
 #[ic_cdk::update]
 pub fn delete_patient(patient_id: u64) -> Result<(), Error> {
-    match PATIENT_STORAGE.with(|service| service.borrow_mut().remove(&patient_id)) {
-        Some(_) => Ok(()),
-        None => Err(Error::NotFound {
-            msg: format!("Patient with id={} not found", patient_id),
-        }),
-    }
+    erase_patient(patient_id).map(|_| ())
 }
 
 #[ic_cdk::query]
-pub fn list_patients() -> Vec<Patient> {
-    PATIENT_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, patient)| patient.clone())
-            .collect()
+pub fn list_patients(page: PageRequest, filter: PatientFilter) -> Page<Patient> {
+    let caller = caller_context();
+    let visible = caller.profile_access();
+    paginate(&PATIENT_STORAGE, &page, |patient| {
+        filter
+            .username_prefix
+            .as_ref()
+            .map_or(true, |prefix| patient.username.starts_with(prefix.as_str()))
+            && visible(patient.id)
     })
 }
 
 pub fn get_patient_by_id(patient_id: &u64) -> Option<Patient> {
     PATIENT_STORAGE.with(|service| service.borrow().get(patient_id))
+}
+
+pub fn get_patient_by_username(username: &str) -> Option<Patient> {
+    patient_id_by_username(username)
+        .and_then(|patient_id| get_patient_by_id(&patient_id))
+        .filter(|patient| patient.username == username)
 }
\ No newline at end of file
@@ -0,0 +1,366 @@
+//! Structured prescriptions issued against reports
+//!
+//! A prescription is `Active` until its last refill is dispensed, it passes
+//! `expires_at` or its doctor revokes it. Expiry is applied when a
+//! prescription is read, so stored records may still say `Active`. Issuing
+//! and renewing screen the medication lines for interactions first.
+
+use crate::audit::{audited, audited_create, audited_read, record_reads};
+use crate::auth::{caller_context, unauthorized, CallerContext};
+use crate::error::Error;
+use crate::index::{index_prescription, prescription_ids_by_patient};
+use crate::interaction::screen_interactions;
+use crate::models::{
+    AuditAction, AuditEntity, ConsentScope, IssuedPrescription, MedicationLine, Prescription,
+    PrescriptionStatus, Role,
+};
+use crate::report::get_report_by_id;
+use crate::storage::PRESCRIPTION_STORAGE;
+use crate::utils::generate_id;
+
+/// Medication lines per prescription. Together with the field limits below
+/// it keeps a prescription within its `MAX_SIZE` of 4096 bytes.
+pub const MAX_MEDICATION_LINES: usize = 10;
+pub const MAX_REFILLS: u32 = 12;
+pub const MAX_VALIDITY_DAYS: u32 = 365;
+
+pub const MAX_MEDICATION_LEN: usize = 128;
+pub const MAX_LINE_FIELD_LEN: usize = 64;
+
+// Room left for the notes of a legacy prescription, which has no lines
+const MAX_LEGACY_NOTES_LEN: usize = 3584;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[ic_cdk::update]
+pub fn issue_prescription(
+    report_id: u64,
+    lines: Vec<MedicationLine>,
+    refills: u32,
+    valid_days: u32,
+) -> Result<IssuedPrescription, Error> {
+    audited_create(
+        "issue_prescription",
+        AuditEntity::Prescription,
+        |issued: &IssuedPrescription| issued.prescription.id,
+        || {
+            validate_terms(&lines, refills, valid_days)?;
+
+            let report = get_report_by_id(&report_id).ok_or(Error::NotFound {
+                msg: format!("Report with id={} not found", report_id),
+            })?;
+
+            let caller = caller_context();
+            let doctor_id = require_prescriber(&caller, report.patient_id)?;
+            let warnings = screen_interactions(report.patient_id, &lines)?;
+
+            let now = ic_cdk::api::time();
+            let prescription = Prescription {
+                id: generate_id(),
+                patient_id: report.patient_id,
+                report_id,
+                doctor_id,
+                lines,
+                refills,
+                issued_at: now,
+                expires_at: now + u64::from(valid_days) * NANOS_PER_DAY,
+                status: PrescriptionStatus::Active,
+                renewed_from: None,
+                notes: String::new(),
+            };
+
+            insert_prescription(&prescription);
+            index_prescription(&prescription);
+            Ok(IssuedPrescription {
+                prescription,
+                warnings,
+            })
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn get_prescription(prescription_id: u64) -> Result<Prescription, Error> {
+    audited_read(
+        "get_prescription",
+        AuditEntity::Prescription,
+        prescription_id,
+        || {
+            let prescription = get_current_prescription(prescription_id)?;
+            caller_context()
+                .require_patient_access(prescription.patient_id, ConsentScope::ReadReports)?;
+            Ok(prescription)
+        },
+    )
+}
+
+/// Prescriptions of the patient, optionally only those issued against one report.
+#[ic_cdk::update]
+pub fn list_prescriptions(
+    patient_id: u64,
+    report_id: Option<u64>,
+) -> Result<Vec<Prescription>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadReports)?;
+
+    let now = ic_cdk::api::time();
+    let prescriptions: Vec<Prescription> = PRESCRIPTION_STORAGE.with(|service| {
+        let storage = service.borrow();
+        prescription_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|prescription_id| storage.get(prescription_id))
+            .filter(|prescription| report_id.map_or(true, |id| prescription.report_id == id))
+            .map(|prescription| with_expiry(prescription, now))
+            .collect()
+    });
+
+    record_reads(
+        "list_prescriptions",
+        AuditEntity::Prescription,
+        prescriptions.iter().map(|prescription| prescription.id),
+    );
+    Ok(prescriptions)
+}
+
+/// Issues a copy of a dispensed or expired prescription with fresh refills
+/// and validity.
+#[ic_cdk::update]
+pub fn renew_prescription(
+    prescription_id: u64,
+    refills: u32,
+    valid_days: u32,
+) -> Result<IssuedPrescription, Error> {
+    audited_create(
+        "renew_prescription",
+        AuditEntity::Prescription,
+        |issued: &IssuedPrescription| issued.prescription.id,
+        || {
+            let previous = get_current_prescription(prescription_id)?;
+
+            let caller = caller_context();
+            let doctor_id = require_prescriber(&caller, previous.patient_id)?;
+
+            match previous.status {
+                PrescriptionStatus::Dispensed | PrescriptionStatus::Expired => {}
+                status => {
+                    return Err(Error::InvalidTransition {
+                        msg: format!("Cannot renew a prescription that is {:?}", status),
+                    })
+                }
+            }
+            // Carried-over free-text prescriptions have nothing to renew
+            if previous.lines.is_empty() {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Prescription with id={} has no medication lines; issue a new one",
+                        prescription_id
+                    ),
+                });
+            }
+            validate_terms(&previous.lines, refills, valid_days)?;
+            let warnings = screen_interactions(previous.patient_id, &previous.lines)?;
+
+            let now = ic_cdk::api::time();
+            let prescription = Prescription {
+                id: generate_id(),
+                patient_id: previous.patient_id,
+                report_id: previous.report_id,
+                doctor_id,
+                lines: previous.lines,
+                refills,
+                issued_at: now,
+                expires_at: now + u64::from(valid_days) * NANOS_PER_DAY,
+                status: PrescriptionStatus::Active,
+                renewed_from: Some(prescription_id),
+                notes: String::new(),
+            };
+
+            insert_prescription(&prescription);
+            index_prescription(&prescription);
+            Ok(IssuedPrescription {
+                prescription,
+                warnings,
+            })
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn revoke_prescription(prescription_id: u64) -> Result<Prescription, Error> {
+    audited(
+        "revoke_prescription",
+        AuditEntity::Prescription,
+        prescription_id,
+        AuditAction::Update,
+        || {
+            let mut prescription = get_current_prescription(prescription_id)?;
+
+            // The prescribing doctor revokes, or an admin
+            caller_context().require_doctor(prescription.doctor_id)?;
+
+            require_active(&prescription)?;
+            prescription.status = PrescriptionStatus::Revoked;
+            insert_prescription(&prescription);
+            Ok(prescription)
+        },
+    )
+}
+
+/// Records one dispensing, using up a refill or, with none left, the prescription.
+#[ic_cdk::update]
+pub fn dispense_prescription(prescription_id: u64) -> Result<Prescription, Error> {
+    audited(
+        "dispense_prescription",
+        AuditEntity::Prescription,
+        prescription_id,
+        AuditAction::Update,
+        || {
+            let mut prescription = get_current_prescription(prescription_id)?;
+
+            // Nurses hand out medication to patients who shared their reports
+            let caller = caller_context();
+            if !caller.is_admin {
+                caller.require_role(Role::Nurse)?;
+            }
+            caller.require_patient_access(prescription.patient_id, ConsentScope::ReadReports)?;
+
+            require_active(&prescription)?;
+            match prescription.refills {
+                0 => prescription.status = PrescriptionStatus::Dispensed,
+                refills => prescription.refills = refills - 1,
+            }
+            insert_prescription(&prescription);
+            Ok(prescription)
+        },
+    )
+}
+
+pub fn get_prescription_by_id(prescription_id: &u64) -> Option<Prescription> {
+    PRESCRIPTION_STORAGE.with(|service| service.borrow().get(prescription_id))
+}
+
+/// Keeps the free-text prescription of a report written before structured
+/// prescriptions as an expired prescription with the text as its notes. It
+/// reuses the report id, so repeated migration batches are idempotent. Text
+/// beyond what a prescription can hold is cut off.
+pub fn store_legacy_prescription(report_id: u64, patient_id: u64, mut text: String) {
+    if get_prescription_by_id(&report_id).is_some() {
+        return;
+    }
+    if text.len() > MAX_LEGACY_NOTES_LEN {
+        let mut end = MAX_LEGACY_NOTES_LEN;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+
+    let prescription = Prescription {
+        id: report_id,
+        patient_id,
+        report_id,
+        doctor_id: 0,
+        lines: Vec::new(),
+        refills: 0,
+        issued_at: 0,
+        expires_at: 0,
+        status: PrescriptionStatus::Expired,
+        renewed_from: None,
+        notes: text,
+    };
+
+    insert_prescription(&prescription);
+    index_prescription(&prescription);
+}
+
+fn get_current_prescription(prescription_id: u64) -> Result<Prescription, Error> {
+    match get_prescription_by_id(&prescription_id) {
+        Some(prescription) => Ok(with_expiry(prescription, ic_cdk::api::time())),
+        None => Err(Error::NotFound {
+            msg: format!("Prescription with id={} not found", prescription_id),
+        }),
+    }
+}
+
+fn with_expiry(mut prescription: Prescription, now: u64) -> Prescription {
+    if prescription.status == PrescriptionStatus::Active && now >= prescription.expires_at {
+        prescription.status = PrescriptionStatus::Expired;
+    }
+    prescription
+}
+
+fn insert_prescription(prescription: &Prescription) {
+    PRESCRIPTION_STORAGE.with(|service| {
+        service
+            .borrow_mut()
+            .insert(prescription.id, prescription.clone())
+    });
+}
+
+// Returns the prescribing doctor's id
+fn require_prescriber(caller: &CallerContext, patient_id: u64) -> Result<u64, Error> {
+    let doctor_id = caller
+        .doctor_id
+        .ok_or_else(|| unauthorized("Only doctors issue prescriptions"))?;
+    caller.require_report_writer(patient_id)?;
+    Ok(doctor_id)
+}
+
+fn require_active(prescription: &Prescription) -> Result<(), Error> {
+    if prescription.status == PrescriptionStatus::Active {
+        Ok(())
+    } else {
+        Err(Error::InvalidTransition {
+            msg: format!(
+                "Prescription with id={} is {:?}",
+                prescription.id, prescription.status
+            ),
+        })
+    }
+}
+
+fn validate_terms(lines: &[MedicationLine], refills: u32, valid_days: u32) -> Result<(), Error> {
+    if lines.is_empty() || lines.len() > MAX_MEDICATION_LINES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A prescription needs between 1 and {} medication lines",
+                MAX_MEDICATION_LINES
+            ),
+        });
+    }
+    for line in lines {
+        if line.medication.trim().is_empty()
+            || line.dose.trim().is_empty()
+            || line.route.trim().is_empty()
+            || line.frequency.trim().is_empty()
+            || line.duration_days == 0
+        {
+            return Err(Error::InvalidInput {
+                msg: "Medication lines need a medication, dose, route, frequency and duration"
+                    .to_string(),
+            });
+        }
+        if line.medication.len() > MAX_MEDICATION_LEN
+            || line.dose.len() > MAX_LINE_FIELD_LEN
+            || line.route.len() > MAX_LINE_FIELD_LEN
+            || line.frequency.len() > MAX_LINE_FIELD_LEN
+        {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Medication is limited to {} bytes and dose, route and frequency to {}",
+                    MAX_MEDICATION_LEN, MAX_LINE_FIELD_LEN
+                ),
+            });
+        }
+    }
+    if refills > MAX_REFILLS {
+        return Err(Error::InvalidInput {
+            msg: format!("At most {} refills may be prescribed", MAX_REFILLS),
+        });
+    }
+    if valid_days == 0 || valid_days > MAX_VALIDITY_DAYS {
+        return Err(Error::InvalidInput {
+            msg: format!("Validity must be between 1 and {} days", MAX_VALIDITY_DAYS),
+        });
+    }
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,180 @@
+//! The patient's problem list
+//!
+//! Problems are diagnoses kept by the patient's doctors. Resolving one keeps
+//! it on the list with `resolved_at` set; `list_problems` can narrow the list
+//! to the active ones.
+
+use crate::audit::{audited, audited_create, audited_read, record_reads};
+use crate::auth::caller_context;
+use crate::error::Error;
+use crate::index::{index_problem, problem_ids_by_patient, unindex_problem};
+use crate::models::{AuditAction, AuditEntity, ConsentScope, Problem, ProblemStatus};
+use crate::pagination::matches;
+use crate::patient::get_patient_by_id;
+use crate::storage::PROBLEM_STORAGE;
+use crate::utils::generate_id;
+
+#[ic_cdk::update]
+pub fn add_problem(
+    patient_id: u64,
+    description: String,
+    code: Option<String>,
+    onset_at: Option<u64>,
+) -> Result<Problem, Error> {
+    audited_create(
+        "add_problem",
+        AuditEntity::Problem,
+        |problem: &Problem| problem.id,
+        || {
+            validate_problem(&description, &code)?;
+
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
+
+            // Diagnoses are kept by the patient's doctors only
+            let caller = caller_context();
+            caller.require_record_writer(patient_id)?;
+
+            let problem = Problem {
+                id: generate_id(),
+                patient_id,
+                description,
+                code,
+                status: ProblemStatus::Active,
+                onset_at,
+                resolved_at: None,
+                recorded_at: ic_cdk::api::time(),
+                recorded_by: caller.principal,
+            };
+
+            insert_problem(&problem);
+            index_problem(&problem);
+            Ok(problem)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn get_problem(problem_id: u64) -> Result<Problem, Error> {
+    audited_read("get_problem", AuditEntity::Problem, problem_id, || {
+        let problem = get_existing_problem(problem_id)?;
+        caller_context().require_patient_access(problem.patient_id, ConsentScope::ReadRecords)?;
+        Ok(problem)
+    })
+}
+
+/// Problems of the patient, optionally only those with the given status.
+#[ic_cdk::update]
+pub fn list_problems(
+    patient_id: u64,
+    status: Option<ProblemStatus>,
+) -> Result<Vec<Problem>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadRecords)?;
+
+    let problems: Vec<Problem> = get_problems_by_patient(patient_id)
+        .into_iter()
+        .filter(|problem| matches(&status, &problem.status))
+        .collect();
+    record_reads(
+        "list_problems",
+        AuditEntity::Problem,
+        problems.iter().map(|problem| problem.id),
+    );
+    Ok(problems)
+}
+
+#[ic_cdk::update]
+pub fn update_problem(
+    problem_id: u64,
+    description: String,
+    code: Option<String>,
+    status: ProblemStatus,
+    onset_at: Option<u64>,
+) -> Result<Problem, Error> {
+    audited(
+        "update_problem",
+        AuditEntity::Problem,
+        problem_id,
+        AuditAction::Update,
+        || {
+            validate_problem(&description, &code)?;
+
+            let mut problem = get_existing_problem(problem_id)?;
+            caller_context().require_record_writer(problem.patient_id)?;
+
+            problem.resolved_at = match status {
+                ProblemStatus::Resolved => problem.resolved_at.or(Some(ic_cdk::api::time())),
+                ProblemStatus::Active | ProblemStatus::Inactive => None,
+            };
+            problem.description = description;
+            problem.code = code;
+            problem.status = status;
+            problem.onset_at = onset_at;
+            insert_problem(&problem);
+            Ok(problem)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn delete_problem(problem_id: u64) -> Result<(), Error> {
+    audited(
+        "delete_problem",
+        AuditEntity::Problem,
+        problem_id,
+        AuditAction::Delete,
+        || {
+            let problem = get_existing_problem(problem_id)?;
+            caller_context().require_record_writer(problem.patient_id)?;
+
+            PROBLEM_STORAGE.with(|service| service.borrow_mut().remove(&problem_id));
+            unindex_problem(&problem);
+            Ok(())
+        },
+    )
+}
+
+pub fn get_problem_by_id(problem_id: &u64) -> Option<Problem> {
+    PROBLEM_STORAGE.with(|service| service.borrow().get(problem_id))
+}
+
+/// Problems of the patient, active ones first.
+pub fn get_problems_by_patient(patient_id: u64) -> Vec<Problem> {
+    let mut problems: Vec<Problem> = PROBLEM_STORAGE.with(|service| {
+        let storage = service.borrow();
+        problem_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|problem_id| storage.get(problem_id))
+            .collect()
+    });
+    problems.sort_by_key(|problem| problem.status != ProblemStatus::Active);
+    problems
+}
+
+fn get_existing_problem(problem_id: u64) -> Result<Problem, Error> {
+    get_problem_by_id(&problem_id).ok_or(Error::NotFound {
+        msg: format!("Problem with id={} not found", problem_id),
+    })
+}
+
+fn insert_problem(problem: &Problem) {
+    PROBLEM_STORAGE.with(|service| service.borrow_mut().insert(problem.id, problem.clone()));
+}
+
+fn validate_problem(description: &str, code: &Option<String>) -> Result<(), Error> {
+    if description.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Description cannot be empty".to_string(),
+        });
+    }
+    if code.as_ref().map_or(false, |code| code.trim().is_empty()) {
+        return Err(Error::InvalidInput {
+            msg: "Leave the code unset rather than empty".to_string(),
+        });
+    }
+    Ok(())
+}
\ No newline at end of file
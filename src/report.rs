@@ -1,65 +1,163 @@
 //! Report management functionality
+//!
+//! A report stays editable by its author until they sign it, and only an
+//! admin deletes an unsigned one. Signing stores a hash of the report's
+//! content, attachment bytes included, and locks the report: later
+//! corrections are addenda, reports of their own that reference the signed
+//! one through `amends`. `verify_report_signature` recomputes the hash to show
+//! the report is still what was signed. A report naming no author is claimed
+//! by a doctor through `claim_report` before it can be signed.
+//!
+//! Every new report belongs to a completed appointment and is authored by
+//! that appointment's doctor; reports written before this link carry neither
+//! and sort before the rest when ordered by visit time.
 
+use sha2::{Digest, Sha256};
+
+use crate::appointment::get_appointment_by_id;
+use crate::audit::{audited, audited_create, audited_read, record_reads};
+use crate::auth::{caller_context, unauthorized};
+use crate::blob::{attach_blob, get_blob_by_id, remove_blob, validate_attachment};
 use crate::error::Error;
-use crate::models::{MultiMediaContent, Report};
+use crate::index::{
+    index_report, report_ids_by_appointment, report_ids_by_doctor, report_ids_by_patient,
+    unindex_report,
+};
+use crate::migration::require_schema_current;
+use crate::models::{
+    AppointmentStatus, AuditAction, AuditEntity, BlobLink, ConsentScope, MultiMediaContent, Page,
+    PageRequest, Report, ReportFilter, ReportSignature, SignatureVerification,
+};
+use crate::pagination::{matches, paginate, paginate_ids};
 use crate::patient::get_patient_by_id;
+use crate::schema::fits;
 use crate::storage::REPORT_STORAGE;
-use crate::utils::generate_id;
+use crate::utils::{generate_id, to_hex};
+
+/// Limit of the symptoms, the diagnostic and the recommendations. With the
+/// limits below it keeps a report within its `MAX_SIZE` of 4096 bytes once
+/// the signature is added.
+pub const MAX_REPORT_TEXT_LEN: usize = 1024;
+pub const MAX_REPORT_USERNAME_LEN: usize = 64;
+pub const MAX_CONTENT_TYPE_LEN: usize = 128;
 
 #[ic_cdk::update]
 pub fn add_report(
     patient_id: u64,
+    appointment_id: u64,
     username: String,
     symptoms: String,
     diagnostic: String,
-    prescription: String,
     recommendations: String,
     multimedia_content: Option<MultiMediaContent>,
 ) -> Result<Report, Error> {
-    // Validate input data
-    if username.is_empty()
-        || symptoms.is_empty()
-        || diagnostic.is_empty()
-        || prescription.is_empty()
-        || recommendations.is_empty()
-    {
-        return Err(Error::InvalidInput {
-            msg: "All fields must be provided".to_string(),
-        });
-    }
+    audited_create(
+        "add_report",
+        AuditEntity::Report,
+        |report: &Report| report.id,
+        || {
+            // Validate input data
+            if username.is_empty()
+                || symptoms.is_empty()
+                || diagnostic.is_empty()
+                || recommendations.is_empty()
+            {
+                return Err(Error::InvalidInput {
+                    msg: "All fields must be provided".to_string(),
+                });
+            }
+            validate_username(&username)?;
+            validate_lengths(
+                &symptoms,
+                &diagnostic,
+                &recommendations,
+                &multimedia_content,
+            )?;
 
-    // Check if the patient exists
-    if get_patient_by_id(&patient_id).is_none() {
-        return Err(Error::NotFound {
-            msg: format!("Patient with id={} not found", patient_id),
-        });
-    }
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
 
-    let id = generate_id();
-
-    let report = Report {
-        id,
-        patient_id,
-        username,
-        symptoms,
-        diagnostic,
-        prescription,
-        recommendations,
-        multimedia_content,
-    };
+            let appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
+                msg: format!("Appointment with id={} not found", appointment_id),
+            })?;
+            if appointment.patient_id != patient_id {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Appointment with id={} is not one of the patient's",
+                        appointment_id
+                    ),
+                });
+            }
+
+            // Only doctors the patient allowed to write reports, about their
+            // own visits; an admin cannot author one in a doctor's name
+            let caller = caller_context();
+            caller.require_report_writer(patient_id)?;
+            if caller.doctor_id != Some(appointment.doctor_id) {
+                return Err(unauthorized(&format!(
+                    "Only the doctor of appointment with id={} writes its report",
+                    appointment_id
+                )));
+            }
+
+            if appointment.status != AppointmentStatus::Completed {
+                return Err(Error::InvalidTransition {
+                    msg: format!(
+                        "Appointment with id={} is {:?}; reports follow completed visits",
+                        appointment_id, appointment.status
+                    ),
+                });
+            }
+
+            let id = generate_id();
+
+            if let Some(attachment) = &multimedia_content {
+                validate_attachment(attachment.blob_id, BlobLink::Report(id))?;
+            }
+
+            let report = Report {
+                id,
+                patient_id,
+                doctor_id: Some(appointment.doctor_id),
+                appointment_id: Some(appointment_id),
+                username,
+                symptoms,
+                diagnostic,
+                recommendations,
+                multimedia_content,
+                amends: None,
+                signature: None,
+            };
 
-    REPORT_STORAGE.with(|service| service.borrow_mut().insert(id, report.clone()));
-    Ok(report)
+            REPORT_STORAGE.with(|service| service.borrow_mut().insert(id, report.clone()));
+            index_report(&report);
+            attach_report_content(&report);
+            Ok(report)
+        },
+    )
 }
 
-#[ic_cdk::query]
+#[ic_cdk::update]
 pub fn get_report(report_id: u64) -> Result<Report, Error> {
-    match get_report_by_id(&report_id) {
-        Some(report) => Ok(report),
-        None => Err(Error::NotFound {
-            msg: format!("Report with id={} not found", report_id),
-        }),
-    }
+    audited_read(
+        "get_report",
+        AuditEntity::Report,
+        report_id,
+        || match get_report_by_id(&report_id) {
+            Some(report) => {
+                caller_context()
+                    .require_patient_access(report.patient_id, ConsentScope::ReadReports)?;
+                Ok(report)
+            }
+            None => Err(Error::NotFound {
+                msg: format!("Report with id={} not found", report_id),
+            }),
+        },
+    )
 }
 
 #[ic_cdk::update]
@@ -69,66 +167,531 @@ pub fn update_report(
     username: String,
     symptoms: String,
     diagnostic: String,
-    prescription: String,
     recommendations: String,
     multimedia_content: Option<MultiMediaContent>,
 ) -> Result<Report, Error> {
-    // Validate input data
-    if username.is_empty()
-        || symptoms.is_empty()
-        || diagnostic.is_empty()
-        || prescription.is_empty()
-        || recommendations.is_empty()
-    {
+    audited(
+        "update_report",
+        AuditEntity::Report,
+        report_id,
+        AuditAction::Update,
+        || {
+            // Validate input data
+            if username.is_empty()
+                || symptoms.is_empty()
+                || diagnostic.is_empty()
+                || recommendations.is_empty()
+            {
+                return Err(Error::InvalidInput {
+                    msg: "All fields must be provided".to_string(),
+                });
+            }
+            validate_username(&username)?;
+            validate_lengths(
+                &symptoms,
+                &diagnostic,
+                &recommendations,
+                &multimedia_content,
+            )?;
+
+            require_schema_current()?;
+
+            // Only doctors the patient allowed to write reports edit them, and
+            // a report naming its author only by that author
+            let current = get_existing_report(report_id)?;
+            let caller = caller_context();
+            caller.require_report_writer(patient_id)?;
+            caller.require_report_writer(current.patient_id)?;
+            if current.doctor_id.is_some() && caller.doctor_id != current.doctor_id {
+                return Err(unauthorized(
+                    "Only the doctor who wrote the report edits it",
+                ));
+            }
+
+            require_unsigned(&current)?;
+            let linked = current.appointment_id.is_some() || current.amends.is_some();
+            if linked && patient_id != current.patient_id {
+                return Err(Error::InvalidInput {
+                    msg: "A report stays with the patient of its visit".to_string(),
+                });
+            }
+
+            if let Some(attachment) = &multimedia_content {
+                validate_attachment(attachment.blob_id, BlobLink::Report(report_id))?;
+            }
+
+            let updated_report = Report {
+                id: report_id,
+                patient_id,
+                doctor_id: current.doctor_id,
+                appointment_id: current.appointment_id,
+                username,
+                symptoms,
+                diagnostic,
+                recommendations,
+                multimedia_content,
+                amends: current.amends,
+                signature: None,
+            };
+
+            REPORT_STORAGE.with(|service| {
+                service
+                    .borrow_mut()
+                    .insert(report_id, updated_report.clone())
+            });
+            if current.patient_id != patient_id {
+                unindex_report(&current);
+                index_report(&updated_report);
+            }
+
+            // Drop an attachment the edit replaced
+            if let Some(previous) = current.multimedia_content {
+                if updated_report
+                    .multimedia_content
+                    .as_ref()
+                    .map(|m| m.blob_id)
+                    != Some(previous.blob_id)
+                {
+                    remove_blob(previous.blob_id);
+                }
+            }
+            attach_report_content(&updated_report);
+            Ok(updated_report)
+        },
+    )
+}
+
+#[ic_cdk::update]
+pub fn delete_report(report_id: u64) -> Result<(), Error> {
+    audited(
+        "delete_report",
+        AuditEntity::Report,
+        report_id,
+        AuditAction::Delete,
+        || {
+            let report = get_existing_report(report_id)?;
+            caller_context().require_admin()?;
+            require_unsigned(&report)?;
+
+            REPORT_STORAGE.with(|service| service.borrow_mut().remove(&report_id));
+            unindex_report(&report);
+            if let Some(attachment) = report.multimedia_content {
+                remove_blob(attachment.blob_id);
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Signs the report as the doctor who wrote it, after which it is locked.
+#[ic_cdk::update]
+pub fn sign_report(report_id: u64) -> Result<Report, Error> {
+    audited(
+        "sign_report",
+        AuditEntity::Report,
+        report_id,
+        AuditAction::Update,
+        || {
+            require_schema_current()?;
+            let mut report = get_existing_report(report_id)?;
+
+            // Admins may edit reports but only a doctor vouches for one
+            let caller = caller_context();
+            let doctor_id = caller
+                .doctor_id
+                .ok_or_else(|| unauthorized("Only doctors sign reports"))?;
+            caller.require_report_writer(report.patient_id)?;
+            if report.doctor_id.is_none() {
+                return Err(Error::InvalidTransition {
+                    msg: format!(
+                        "Report with id={} names no author; claim it before signing",
+                        report_id
+                    ),
+                });
+            }
+            if report.doctor_id != Some(doctor_id) {
+                return Err(unauthorized(
+                    "Only the doctor who wrote the report signs it",
+                ));
+            }
+            require_unsigned(&report)?;
+
+            report.signature = Some(ReportSignature {
+                doctor_id,
+                principal: caller.principal,
+                signed_at: ic_cdk::api::time(),
+                content_hash: content_hash(&report),
+            });
+            REPORT_STORAGE.with(|service| service.borrow_mut().insert(report_id, report.clone()));
+            Ok(report)
+        },
+    )
+}
+
+/// Makes the calling doctor the author of an unsigned report that names none,
+/// so they can edit and sign it. A report of a visit is only claimed by the
+/// visit's doctor.
+#[ic_cdk::update]
+pub fn claim_report(report_id: u64) -> Result<Report, Error> {
+    audited(
+        "claim_report",
+        AuditEntity::Report,
+        report_id,
+        AuditAction::Update,
+        || {
+            require_schema_current()?;
+            let current = get_existing_report(report_id)?;
+
+            let caller = caller_context();
+            let doctor_id = caller
+                .doctor_id
+                .ok_or_else(|| unauthorized("Only doctors claim reports"))?;
+            caller.require_report_writer(current.patient_id)?;
+            require_unsigned(&current)?;
+            if current.doctor_id.is_some() {
+                return Err(Error::InvalidTransition {
+                    msg: format!("Report with id={} already names its author", report_id),
+                });
+            }
+            let visit = current
+                .appointment_id
+                .and_then(|appointment_id| get_appointment_by_id(&appointment_id));
+            if visit.map_or(false, |appointment| appointment.doctor_id != doctor_id) {
+                return Err(unauthorized(
+                    "Only the doctor of the report's visit claims it",
+                ));
+            }
+
+            let report = Report {
+                doctor_id: Some(doctor_id),
+                ..current.clone()
+            };
+            REPORT_STORAGE.with(|service| service.borrow_mut().insert(report_id, report.clone()));
+            unindex_report(&current);
+            index_report(&report);
+            Ok(report)
+        },
+    )
+}
+
+/// Amends a signed report. The addendum is a new, unsigned report of the same
+/// patient and visit that references the original.
+#[ic_cdk::update]
+pub fn add_report_addendum(
+    report_id: u64,
+    symptoms: String,
+    diagnostic: String,
+    recommendations: String,
+    multimedia_content: Option<MultiMediaContent>,
+) -> Result<Report, Error> {
+    audited_create(
+        "add_report_addendum",
+        AuditEntity::Report,
+        |report: &Report| report.id,
+        || {
+            // Validate input data
+            if symptoms.is_empty() || diagnostic.is_empty() || recommendations.is_empty() {
+                return Err(Error::InvalidInput {
+                    msg: "All fields must be provided".to_string(),
+                });
+            }
+
+            validate_lengths(
+                &symptoms,
+                &diagnostic,
+                &recommendations,
+                &multimedia_content,
+            )?;
+
+            let original = get_existing_report(report_id)?;
+            let caller = caller_context();
+            caller.require_report_writer(original.patient_id)?;
+            let signature = original
+                .signature
+                .as_ref()
+                .ok_or(Error::InvalidTransition {
+                    msg: format!(
+                        "Report with id={} is not signed; edit it instead",
+                        report_id
+                    ),
+                })?;
+
+            // Addenda come from the doctor of the visit, as reports do; a
+            // report written before reports were linked to visits is amended
+            // by the doctor who signed it
+            let author = match original.appointment_id {
+                Some(appointment_id) => {
+                    get_appointment_by_id(&appointment_id)
+                        .ok_or(Error::NotFound {
+                            msg: format!("Appointment with id={} not found", appointment_id),
+                        })?
+                        .doctor_id
+                }
+                None => signature.doctor_id,
+            };
+            if caller.doctor_id != Some(author) {
+                return Err(unauthorized(&format!(
+                    "Only the doctor of the visit amends report with id={}",
+                    report_id
+                )));
+            }
+
+            let id = generate_id();
+
+            if let Some(attachment) = &multimedia_content {
+                validate_attachment(attachment.blob_id, BlobLink::Report(id))?;
+            }
+
+            let addendum = Report {
+                id,
+                patient_id: original.patient_id,
+                doctor_id: Some(author),
+                appointment_id: original.appointment_id,
+                username: original.username,
+                symptoms,
+                diagnostic,
+                recommendations,
+                multimedia_content,
+                amends: Some(report_id),
+                signature: None,
+            };
+
+            // A report written before the field limits may carry a username
+            // too long to share with full-length texts
+            if !fits(&addendum) {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Report with id={} leaves no room for an addendum this long",
+                        report_id
+                    ),
+                });
+            }
+
+            REPORT_STORAGE.with(|service| service.borrow_mut().insert(id, addendum.clone()));
+            index_report(&addendum);
+            attach_report_content(&addendum);
+            Ok(addendum)
+        },
+    )
+}
+
+/// Checks a signed report against the hash taken when it was signed. Only
+/// hashes and ids are returned, so this needs no audit entry.
+#[ic_cdk::query]
+pub fn verify_report_signature(report_id: u64) -> Result<SignatureVerification, Error> {
+    let report = get_existing_report(report_id)?;
+    caller_context().require_patient_access(report.patient_id, ConsentScope::ReadReports)?;
+
+    let signature = report.signature.clone().ok_or(Error::InvalidInput {
+        msg: format!("Report with id={} is not signed", report_id),
+    })?;
+    let content_hash = content_hash(&report);
+    Ok(SignatureVerification {
+        report_id,
+        valid: content_hash == signature.content_hash,
+        signature,
+        content_hash,
+        addendum_ids: get_addendum_ids(&report),
+    })
+}
+
+/// Reports the doctor wrote, in visit order.
+#[ic_cdk::update]
+pub fn list_reports_by_doctor(doctor_id: u64) -> Result<Vec<Report>, Error> {
+    let caller = caller_context();
+    caller.require_doctor(doctor_id)?;
+
+    // A patient who withdrew consent hides their reports from the author too
+    let visible = caller.patient_access(ConsentScope::ReadReports);
+    let reports: Vec<Report> = get_reports_in_visit_order(report_ids_by_doctor(doctor_id))
+        .into_iter()
+        .filter(|report| visible(report.patient_id))
+        .collect();
+    record_reads(
+        "list_reports_by_doctor",
+        AuditEntity::Report,
+        reports.iter().map(|report| report.id),
+    );
+    Ok(reports)
+}
+
+/// Reports written for the visit, each addendum after what it amends.
+#[ic_cdk::update]
+pub fn list_reports_for_appointment(appointment_id: u64) -> Result<Vec<Report>, Error> {
+    let appointment = get_appointment_by_id(&appointment_id).ok_or(Error::NotFound {
+        msg: format!("Appointment with id={} not found", appointment_id),
+    })?;
+    caller_context().require_patient_access(appointment.patient_id, ConsentScope::ReadReports)?;
+
+    let reports = get_reports_in_visit_order(report_ids_by_appointment(appointment_id));
+    record_reads(
+        "list_reports_for_appointment",
+        AuditEntity::Report,
+        reports.iter().map(|report| report.id),
+    );
+    Ok(reports)
+}
+
+/// Reports of the patient in visit order.
+#[ic_cdk::update]
+pub fn list_reports_for_patient(patient_id: u64) -> Result<Vec<Report>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadReports)?;
+
+    let reports = get_reports_in_visit_order(report_ids_by_patient(patient_id));
+    record_reads(
+        "list_reports_for_patient",
+        AuditEntity::Report,
+        reports.iter().map(|report| report.id),
+    );
+    Ok(reports)
+}
+
+#[ic_cdk::update]
+pub fn list_reports(page: PageRequest, filter: ReportFilter) -> Page<Report> {
+    let caller = caller_context();
+    let visible = caller.patient_access(ConsentScope::ReadReports);
+    let keep = |report: &Report| {
+        matches(&filter.patient_id, &report.patient_id)
+            && filter
+                .amends
+                .map_or(true, |amends| report.amends == Some(amends))
+            && visible(report.patient_id)
+    };
+    let page = match filter.patient_id {
+        Some(patient_id) => paginate_ids(
+            report_ids_by_patient(patient_id),
+            &page,
+            get_report_by_id,
+            keep,
+        ),
+        None => paginate(&REPORT_STORAGE, &page, keep),
+    };
+
+    record_reads(
+        "list_reports",
+        AuditEntity::Report,
+        page.items.iter().map(|report| report.id),
+    );
+    page
+}
+
+pub fn get_report_by_id(report_id: &u64) -> Option<Report> {
+    REPORT_STORAGE.with(|service| service.borrow().get(report_id))
+}
+
+fn attach_report_content(report: &Report) {
+    if let Some(attachment) = &report.multimedia_content {
+        attach_blob(attachment.blob_id, BlobLink::Report(report.id));
+    }
+}
+
+fn get_existing_report(report_id: u64) -> Result<Report, Error> {
+    get_report_by_id(&report_id).ok_or(Error::NotFound {
+        msg: format!("Report with id={} not found", report_id),
+    })
+}
+
+fn validate_username(username: &str) -> Result<(), Error> {
+    if username.len() > MAX_REPORT_USERNAME_LEN {
         return Err(Error::InvalidInput {
-            msg: "All fields must be provided".to_string(),
+            msg: format!("Username is limited to {} bytes", MAX_REPORT_USERNAME_LEN),
         });
     }
+    Ok(())
+}
 
-    let updated_report = Report {
-        id: report_id,
-        patient_id,
-        username,
-        symptoms,
-        diagnostic,
-        prescription,
-        recommendations,
-        multimedia_content,
-    };
-
-    match REPORT_STORAGE.with(|service| {
-        service
-            .borrow_mut()
-            .insert(report_id, updated_report.clone())
-    }) {
-        Some(_) => Ok(updated_report),
-        None => Err(Error::NotFound {
-            msg: format!("Report with id={} not found", report_id),
-        }),
+// An addendum copies the username of the report it amends, so only its own
+// fields are checked here
+fn validate_lengths(
+    symptoms: &str,
+    diagnostic: &str,
+    recommendations: &str,
+    multimedia_content: &Option<MultiMediaContent>,
+) -> Result<(), Error> {
+    let content_type_len = multimedia_content
+        .as_ref()
+        .map_or(0, |content| content.content_type.len());
+    if symptoms.len() > MAX_REPORT_TEXT_LEN
+        || diagnostic.len() > MAX_REPORT_TEXT_LEN
+        || recommendations.len() > MAX_REPORT_TEXT_LEN
+        || content_type_len > MAX_CONTENT_TYPE_LEN
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Report texts and attachment type are limited to {} and {} bytes",
+                MAX_REPORT_TEXT_LEN, MAX_CONTENT_TYPE_LEN
+            ),
+        });
     }
+    Ok(())
 }
 
-#[ic_cdk::update]
-pub fn delete_report(report_id: u64) -> Result<(), Error> {
-    match REPORT_STORAGE.with(|service| service.borrow_mut().remove(&report_id)) {
-        Some(_) => Ok(()),
-        None => Err(Error::NotFound {
-            msg: format!("Report with id={} not found", report_id),
-        }),
+fn require_unsigned(report: &Report) -> Result<(), Error> {
+    if report.signature.is_some() {
+        return Err(Error::InvalidTransition {
+            msg: format!(
+                "Report with id={} is signed; amend it with an addendum",
+                report.id
+            ),
+        });
     }
+    Ok(())
 }
 
-#[ic_cdk::query]
-pub fn list_reports() -> Vec<Report> {
+// Addenda stay with the patient of the report they amend, and ids grow with
+// time, so the patient's reports hold them oldest first
+fn get_addendum_ids(report: &Report) -> Vec<u64> {
     REPORT_STORAGE.with(|service| {
-        service
-            .borrow()
-            .iter()
-            .map(|(_, report)| report.clone())
+        let storage = service.borrow();
+        report_ids_by_patient(report.patient_id)
+            .into_iter()
+            .filter(|report_id| {
+                storage
+                    .get(report_id)
+                    .map_or(false, |addendum| addendum.amends == Some(report.id))
+            })
             .collect()
     })
 }
 
-pub fn get_report_by_id(report_id: &u64) -> Option<Report> {
-    REPORT_STORAGE.with(|service| service.borrow().get(report_id))
+// Ordered by the start of the visit, then by id so addenda follow the report
+// they amend; reports without a visit count as the oldest
+fn get_reports_in_visit_order(report_ids: Vec<u64>) -> Vec<Report> {
+    let mut reports: Vec<(u64, Report)> = report_ids
+        .iter()
+        .filter_map(get_report_by_id)
+        .map(|report| {
+            let visit_at = report
+                .appointment_id
+                .and_then(|appointment_id| get_appointment_by_id(&appointment_id))
+                .map_or(0, |appointment| appointment.slot_start);
+            (visit_at, report)
+        })
+        .collect();
+    reports.sort_by_key(|(visit_at, report)| (*visit_at, report.id));
+    reports.into_iter().map(|(_, report)| report).collect()
+}
+
+// Covers every field but the signature; an attachment is covered by the hash
+// of its bytes, which the blob store keeps once the upload completes
+fn content_hash(report: &Report) -> String {
+    let attachment = report.multimedia_content.as_ref().map(|content| {
+        let blob_hash = get_blob_by_id(&content.blob_id).and_then(|blob| blob.sha256);
+        (&content.content_type, content.blob_id, blob_hash)
+    });
+    let fields = candid::encode_args((
+        report.id,
+        report.patient_id,
+        report.doctor_id,
+        report.appointment_id,
+        &report.username,
+        &report.symptoms,
+        &report.diagnostic,
+        &report.recommendations,
+        attachment,
+        report.amends,
+    ))
+    .expect("cannot encode report");
+
+    to_hex(&Sha256::digest(&fields))
 }
\ No newline at end of file
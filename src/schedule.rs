@@ -0,0 +1,519 @@
+//! Recurring availability rules and their expansion into bookable slots
+//!
+//! Rules describe weekly patterns in the doctor's own time zone and are never
+//! consumed by bookings: `list_available_slots` expands one-off windows and
+//! rules for a time range and drops the occurrences an appointment holds.
+
+use chrono::{Datelike, Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::appointment::{get_appointments_by_ids, holds_slot};
+use crate::auth::caller_context;
+use crate::availability::{
+    filter_availability_by_doctor_id, filter_available_slots_by_doctor_id, find_availability_at,
+};
+use crate::doctor::get_doctor_by_id;
+use crate::error::Error;
+use crate::index::{
+    appointment_ids_by_doctor, availability_rule_ids_by_doctor, index_availability_rule,
+    unindex_availability_rule,
+};
+use crate::models::{AvailabilityRule, Slot, SlotSource};
+use crate::storage::AVAILABILITY_RULE_STORAGE;
+use crate::utils::generate_id;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * NANOS_PER_SECOND;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Longest range `list_available_slots` expands in a single call.
+pub const MAX_SLOT_RANGE: u64 = 31 * NANOS_PER_DAY;
+
+#[ic_cdk::update]
+pub fn add_availability_rule(
+    doctor_id: u64,
+    weekdays: Vec<u8>,
+    start_minute: u32,
+    end_minute: u32,
+    slot_minutes: u32,
+    buffer_minutes: u32,
+    effective_from: u64,
+    effective_until: Option<u64>,
+) -> Result<AvailabilityRule, Error> {
+    let mut rule = AvailabilityRule {
+        id: 0,
+        doctor_id,
+        weekdays,
+        start_minute,
+        end_minute,
+        slot_minutes,
+        buffer_minutes,
+        effective_from,
+        effective_until,
+    };
+
+    // Validate input data
+    validate_rule(&rule)?;
+
+    // Doctors manage their own availability
+    caller_context().require_doctor(doctor_id)?;
+
+    // Check if the doctor exists
+    if get_doctor_by_id(&doctor_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Doctor with id={} not found", doctor_id),
+        });
+    }
+
+    check_rule_overlap(&rule)?;
+
+    rule.id = generate_id();
+
+    AVAILABILITY_RULE_STORAGE.with(|service| service.borrow_mut().insert(rule.id, rule.clone()));
+    index_availability_rule(&rule);
+    Ok(rule)
+}
+
+/// Replaces the pattern of a rule, validated like a new rule. Appointments
+/// already booked keep their slots.
+#[ic_cdk::update]
+pub fn update_availability_rule(
+    rule_id: u64,
+    weekdays: Vec<u8>,
+    start_minute: u32,
+    end_minute: u32,
+    slot_minutes: u32,
+    buffer_minutes: u32,
+    effective_from: u64,
+    effective_until: Option<u64>,
+) -> Result<AvailabilityRule, Error> {
+    let current = get_availability_rule_by_id(&rule_id).ok_or(Error::NotFound {
+        msg: format!("Availability rule with id={} not found", rule_id),
+    })?;
+
+    let rule = AvailabilityRule {
+        id: rule_id,
+        doctor_id: current.doctor_id,
+        weekdays,
+        start_minute,
+        end_minute,
+        slot_minutes,
+        buffer_minutes,
+        effective_from,
+        effective_until,
+    };
+
+    // Validate input data
+    validate_rule(&rule)?;
+
+    // Doctors manage their own availability
+    caller_context().require_doctor(rule.doctor_id)?;
+
+    check_rule_overlap(&rule)?;
+
+    AVAILABILITY_RULE_STORAGE.with(|service| service.borrow_mut().insert(rule_id, rule.clone()));
+    Ok(rule)
+}
+
+#[ic_cdk::query]
+pub fn get_availability_rule(rule_id: u64) -> Result<AvailabilityRule, Error> {
+    match get_availability_rule_by_id(&rule_id) {
+        Some(rule) => Ok(rule),
+        None => Err(Error::NotFound {
+            msg: format!("Availability rule with id={} not found", rule_id),
+        }),
+    }
+}
+
+#[ic_cdk::update]
+pub fn delete_availability_rule(rule_id: u64) -> Result<(), Error> {
+    if let Some(rule) = get_availability_rule_by_id(&rule_id) {
+        caller_context().require_doctor(rule.doctor_id)?;
+    }
+
+    match AVAILABILITY_RULE_STORAGE.with(|service| service.borrow_mut().remove(&rule_id)) {
+        Some(rule) => {
+            unindex_availability_rule(&rule);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Availability rule with id={} not found", rule_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+pub fn filter_availability_rules_by_doctor_id(doctor_id: u64) -> Vec<AvailabilityRule> {
+    AVAILABILITY_RULE_STORAGE.with(|service| {
+        let storage = service.borrow();
+        availability_rule_ids_by_doctor(doctor_id)
+            .iter()
+            .filter_map(|rule_id| storage.get(rule_id))
+            .collect()
+    })
+}
+
+/// Unbooked slots of the doctor starting in `[from, to)`, ordered by start time.
+#[ic_cdk::query]
+pub fn list_available_slots(doctor_id: u64, from: u64, to: u64) -> Result<Vec<Slot>, Error> {
+    if to <= from || to - from > MAX_SLOT_RANGE {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Range must be non-empty and span at most {} days",
+                MAX_SLOT_RANGE / NANOS_PER_DAY
+            ),
+        });
+    }
+
+    if get_doctor_by_id(&doctor_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Doctor with id={} not found", doctor_id),
+        });
+    }
+
+    // The doctor's bookings are loaded once rather than for every slot
+    let booked: Vec<(u64, u64)> = get_appointments_by_ids(&appointment_ids_by_doctor(doctor_id))
+        .into_iter()
+        .filter(|appointment| holds_slot(appointment.status))
+        .map(|appointment| (appointment.slot_start, appointment.slot_end))
+        .collect();
+
+    Ok(expand_slots(doctor_id, from, to)
+        .into_iter()
+        .filter(|slot| {
+            !booked
+                .iter()
+                .any(|(start, end)| *start < slot.end_time && slot.start_time < *end)
+        })
+        .collect())
+}
+
+pub fn get_availability_rule_by_id(rule_id: &u64) -> Option<AvailabilityRule> {
+    AVAILABILITY_RULE_STORAGE.with(|service| service.borrow().get(rule_id))
+}
+
+/// Finds the doctor's slot containing the instant `at`, booked or not.
+pub fn find_slot(doctor_id: u64, at: u64) -> Option<Slot> {
+    if let Some(availability) = find_availability_at(doctor_id, at) {
+        return Some(Slot {
+            doctor_id,
+            start_time: availability.start_time,
+            end_time: availability.end_time,
+            source: SlotSource::Window(availability.id),
+        });
+    }
+
+    // Rule slots never exceed a day, so one containing `at` starts within the last day
+    let time_zone = doctor_time_zone(doctor_id);
+    filter_availability_rules_by_doctor_id(doctor_id)
+        .iter()
+        .flat_map(|rule| rule_slots(rule, time_zone, at.saturating_sub(NANOS_PER_DAY), at + 1))
+        .find(|slot| slot.start_time <= at && at < slot.end_time)
+}
+
+/// Rejects a window overlapping another window or a rule slot of the doctor;
+/// `except` is the window being updated.
+pub fn check_window_overlap(
+    doctor_id: u64,
+    start: u64,
+    end: u64,
+    except: Option<u64>,
+) -> Result<(), Error> {
+    let window = filter_availability_by_doctor_id(doctor_id)
+        .into_iter()
+        .find(|availability| {
+            Some(availability.id) != except
+                && availability.start_time < end
+                && start < availability.end_time
+        });
+    if let Some(availability) = window {
+        return Err(overlap_error(&Slot {
+            doctor_id,
+            start_time: availability.start_time,
+            end_time: availability.end_time,
+            source: SlotSource::Window(availability.id),
+        }));
+    }
+
+    let time_zone = doctor_time_zone(doctor_id);
+    for rule in filter_availability_rules_by_doctor_id(doctor_id) {
+        if let Some(slot) = rule_slots(&rule, time_zone, start.saturating_sub(NANOS_PER_DAY), end)
+            .into_iter()
+            .find(|slot| slot.start_time < end && start < slot.end_time)
+        {
+            return Err(overlap_error(&slot));
+        }
+    }
+
+    Ok(())
+}
+
+// A rule may not produce slots overlapping another rule or a window; an
+// updated rule is not compared with its previous version
+fn check_rule_overlap(rule: &AvailabilityRule) -> Result<(), Error> {
+    for other in filter_availability_rules_by_doctor_id(rule.doctor_id) {
+        if other.id != rule.id && rules_overlap(rule, &other) {
+            return Err(Error::AppointmentConflict {
+                msg: format!(
+                    "Overlaps availability rule id={} of doctor_id={}",
+                    other.id, other.doctor_id
+                ),
+            });
+        }
+    }
+
+    let time_zone = doctor_time_zone(rule.doctor_id);
+    for window in filter_availability_by_doctor_id(rule.doctor_id) {
+        let from = window.start_time.saturating_sub(NANOS_PER_DAY);
+        if rule_slots(rule, time_zone, from, window.end_time)
+            .iter()
+            .any(|slot| slot.start_time < window.end_time && window.start_time < slot.end_time)
+        {
+            return Err(overlap_error(&Slot {
+                doctor_id: window.doctor_id,
+                start_time: window.start_time,
+                end_time: window.end_time,
+                source: SlotSource::Window(window.id),
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+fn rules_overlap(a: &AvailabilityRule, b: &AvailabilityRule) -> bool {
+    let shares_day = a.weekdays.iter().any(|day| b.weekdays.contains(day));
+    let shares_hours = a.start_minute < b.end_minute && b.start_minute < a.end_minute;
+    let shares_range = a
+        .effective_until
+        .map_or(true, |until| b.effective_from < until)
+        && b.effective_until
+            .map_or(true, |until| a.effective_from < until);
+    shares_day && shares_hours && shares_range
+}
+
+fn overlap_error(slot: &Slot) -> Error {
+    let source = match slot.source {
+        SlotSource::Window(availability_id) => {
+            format!("availability window id={}", availability_id)
+        }
+        SlotSource::Rule(rule_id) => format!("availability rule id={}", rule_id),
+    };
+    Error::AppointmentConflict {
+        msg: format!(
+            "Overlaps {} of doctor_id={} from {} to {}",
+            source, slot.doctor_id, slot.start_time, slot.end_time
+        ),
+    }
+}
+
+// Every slot, booked or not, starting in `[from, to)`
+fn expand_slots(doctor_id: u64, from: u64, to: u64) -> Vec<Slot> {
+    let mut slots: Vec<Slot> = filter_available_slots_by_doctor_id(doctor_id)
+        .into_iter()
+        .filter(|availability| availability.start_time >= from && availability.start_time < to)
+        .map(|availability| Slot {
+            doctor_id,
+            start_time: availability.start_time,
+            end_time: availability.end_time,
+            source: SlotSource::Window(availability.id),
+        })
+        .collect();
+
+    let time_zone = doctor_time_zone(doctor_id);
+    for rule in filter_availability_rules_by_doctor_id(doctor_id) {
+        slots.extend(rule_slots(&rule, time_zone, from, to));
+    }
+
+    slots.sort_by_key(|slot| slot.start_time);
+    slots
+}
+
+fn rule_slots(rule: &AvailabilityRule, time_zone: Tz, from: u64, to: u64) -> Vec<Slot> {
+    let mut slots = Vec::new();
+    let step = rule.slot_minutes + rule.buffer_minutes;
+
+    let mut day = local_date(time_zone, from);
+    let last_day = local_date(time_zone, to);
+    while day <= last_day {
+        let weekday = day.weekday().num_days_from_sunday() as u8;
+        if rule.weekdays.contains(&weekday) {
+            let mut minute = rule.start_minute;
+            while minute + rule.slot_minutes <= rule.end_minute {
+                let start = local_to_utc(time_zone, day, minute);
+                let end = local_to_utc(time_zone, day, minute + rule.slot_minutes);
+                // Local times skipped by a DST change yield no slot
+                if let (Some(start), Some(end)) = (start, end) {
+                    if start >= from && start < to && end > start && rule_in_effect(rule, start) {
+                        slots.push(Slot {
+                            doctor_id: rule.doctor_id,
+                            start_time: start,
+                            end_time: end,
+                            source: SlotSource::Rule(rule.id),
+                        });
+                    }
+                }
+                minute += step;
+            }
+        }
+
+        day = match day.succ_opt() {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    slots
+}
+
+fn rule_in_effect(rule: &AvailabilityRule, at: u64) -> bool {
+    at >= rule.effective_from && rule.effective_until.map_or(true, |until| at < until)
+}
+
+// Profiles created before time zones were recorded fall back to UTC
+fn doctor_time_zone(doctor_id: u64) -> Tz {
+    get_doctor_by_id(&doctor_id)
+        .and_then(|doctor| doctor.time_zone.parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+fn local_date(time_zone: Tz, at: u64) -> NaiveDate {
+    let nanos = at.min(i64::MAX as u64) as i64;
+    Utc.timestamp_nanos(nanos)
+        .with_timezone(&time_zone)
+        .date_naive()
+}
+
+fn local_to_utc(time_zone: Tz, day: NaiveDate, minute: u32) -> Option<u64> {
+    let local = day.and_hms_opt(0, 0, 0)? + Duration::minutes(i64::from(minute));
+    let instant = time_zone.from_local_datetime(&local).earliest()?;
+    u64::try_from(instant.timestamp())
+        .ok()
+        .map(|seconds| seconds * NANOS_PER_SECOND)
+}
+
+fn validate_rule(rule: &AvailabilityRule) -> Result<(), Error> {
+    if rule.weekdays.is_empty() || rule.weekdays.iter().any(|day| *day > 6) {
+        return Err(Error::InvalidInput {
+            msg: "Weekdays must be between 0 (Sunday) and 6 (Saturday)".to_string(),
+        });
+    }
+    if rule.end_minute <= rule.start_minute || rule.end_minute > MINUTES_PER_DAY {
+        return Err(Error::InvalidInput {
+            msg: "End minute must be after start minute and within the day".to_string(),
+        });
+    }
+    let slot_end = rule.start_minute.checked_add(rule.slot_minutes);
+    if rule.slot_minutes == 0 || slot_end.map_or(true, |slot_end| slot_end > rule.end_minute) {
+        return Err(Error::InvalidInput {
+            msg: "Slot duration must be positive and fit between start and end".to_string(),
+        });
+    }
+    // Keeps the step between slots from overflowing
+    if rule.buffer_minutes > MINUTES_PER_DAY {
+        return Err(Error::InvalidInput {
+            msg: format!("Buffer must be at most {} minutes", MINUTES_PER_DAY),
+        });
+    }
+    if let Some(until) = rule.effective_until {
+        if until <= rule.effective_from {
+            return Err(Error::InvalidInput {
+                msg: "Effective range must end after it starts".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32) -> u64 {
+        let instant = Utc.with_ymd_and_hms(year, month, day, hour, 0, 0).unwrap();
+        instant.timestamp() as u64 * NANOS_PER_SECOND
+    }
+
+    // Hourly slots on Sundays between the given local hours
+    fn sunday_rule(start_hour: u32, end_hour: u32) -> AvailabilityRule {
+        AvailabilityRule {
+            id: 1,
+            doctor_id: 2,
+            weekdays: vec![0],
+            start_minute: start_hour * 60,
+            end_minute: end_hour * 60,
+            slot_minutes: 60,
+            buffer_minutes: 0,
+            effective_from: 0,
+            effective_until: None,
+        }
+    }
+
+    fn starts(slots: &[Slot]) -> Vec<u64> {
+        slots.iter().map(|slot| slot.start_time).collect()
+    }
+
+    #[test]
+    fn slots_follow_the_doctors_time_zone() {
+        let rule = sunday_rule(9, 11);
+        let slots = rule_slots(
+            &rule,
+            Tz::Europe__Berlin,
+            utc(2024, 1, 7, 0),
+            utc(2024, 1, 8, 0),
+        );
+
+        // Berlin is an hour ahead of UTC in winter
+        assert_eq!(starts(&slots), vec![utc(2024, 1, 7, 8), utc(2024, 1, 7, 9)]);
+        assert_eq!(slots[0].end_time, utc(2024, 1, 7, 9));
+        assert_eq!(slots[0].source, SlotSource::Rule(1));
+    }
+
+    #[test]
+    fn local_times_skipped_in_spring_yield_no_slot() {
+        // On 2024-03-31 Berlin clocks jump from 02:00 to 03:00
+        let rule = sunday_rule(2, 4);
+        let slots = rule_slots(
+            &rule,
+            Tz::Europe__Berlin,
+            utc(2024, 3, 30, 0),
+            utc(2024, 4, 1, 0),
+        );
+
+        assert_eq!(starts(&slots), vec![utc(2024, 3, 31, 1)]);
+        assert_eq!(slots[0].end_time, utc(2024, 3, 31, 2));
+    }
+
+    #[test]
+    fn local_times_repeated_in_autumn_yield_one_slot() {
+        // On 2024-10-27 Berlin clocks fall back from 03:00 to 02:00
+        let rule = sunday_rule(1, 4);
+        let slots = rule_slots(
+            &rule,
+            Tz::Europe__Berlin,
+            utc(2024, 10, 26, 0),
+            utc(2024, 10, 28, 0),
+        );
+
+        // The repeated hour is offered once, at its earlier occurrence
+        assert_eq!(
+            starts(&slots),
+            vec![
+                utc(2024, 10, 26, 23),
+                utc(2024, 10, 27, 0),
+                utc(2024, 10, 27, 2)
+            ]
+        );
+        for pair in slots.windows(2) {
+            assert!(pair[0].end_time <= pair[1].start_time);
+        }
+    }
+
+    #[test]
+    fn slots_stay_within_the_requested_range() {
+        let rule = sunday_rule(9, 12);
+        let slots = rule_slots(&rule, Tz::UTC, utc(2024, 1, 7, 10), utc(2024, 1, 7, 11));
+
+        assert_eq!(starts(&slots), vec![utc(2024, 1, 7, 10)]);
+    }
+}
\ No newline at end of file
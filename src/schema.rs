@@ -0,0 +1,1282 @@
+//! Versioned record envelopes for everything kept in stable memory
+//!
+//! Every record is written as `b"HV"`, a little-endian `u16` layout version and
+//! the Candid payload. Records written before envelopes existed are plain
+//! Candid (starting with `DIDL`) and are treated as version 0. Decoding an
+//! older version goes through `Versioned::upgrade`, so adding a field to a
+//! model means bumping its `VERSION`, keeping the previous layout below and
+//! mapping it forward.
+//!
+//! Decoding never writes. Data that an older layout kept inside a record and
+//! the current one keeps in a table of its own is handed to the migration as a
+//! `LegacySplit`, which stores it when it rewrites the record.
+
+use candid::CandidType;
+use ic_stable_structures::{BoundedStorable, Storable};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::models::{
+    Allergy, Appointment, AppointmentStatus, AuditEntry, Availability, AvailabilityRule, Blob,
+    BlobLink, Calendly, ConsentGrant, Data, Doctor, Identity, Immunization, Interaction, LabResult,
+    MedicalRecord, MedicalRecordRevision, Message, MigrationState, MultiMediaContent, Patient,
+    Prescription, Problem, Report, ReportSignature, Reschedule, StatusChange, Vital,
+};
+
+const ENVELOPE_MAGIC: [u8; 2] = *b"HV";
+
+/// Bytes `encode` writes ahead of the payload. A model's `MAX_SIZE` covers
+/// them, so the payload itself has `MAX_SIZE - ENVELOPE_SIZE` bytes to fill.
+pub const ENVELOPE_SIZE: usize = 4;
+
+/// A stable record whose Candid layout is tagged with a version.
+pub trait Versioned: CandidType + DeserializeOwned {
+    /// Layout version written by `encode`.
+    const VERSION: u16;
+
+    /// Decodes a payload written with an older layout.
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String>;
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let payload = candid::encode_one(value).expect("cannot encode record");
+    let mut bytes = Vec::with_capacity(payload.len() + ENVELOPE_SIZE);
+    bytes.extend_from_slice(&ENVELOPE_MAGIC);
+    bytes.extend_from_slice(&T::VERSION.to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+pub fn decode<T: Versioned>(bytes: &[u8]) -> T {
+    let (version, payload) = split_envelope(bytes);
+
+    let decoded = if version == T::VERSION {
+        decode_payload(payload)
+    } else if version > T::VERSION {
+        Err(format!(
+            "layout version {} is newer than supported version {}",
+            version,
+            T::VERSION
+        ))
+    } else {
+        T::upgrade(version, payload)
+    };
+
+    decoded.unwrap_or_else(|err| {
+        ic_cdk::trap(&format!(
+            "cannot decode {}: {}",
+            std::any::type_name::<T>(),
+            err
+        ))
+    })
+}
+
+pub fn decode_payload<T: CandidType + DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    candid::decode_one(payload).map_err(|err| err.to_string())
+}
+
+/// Whether a record, envelope included, fits its map. Records written before
+/// envelopes existed may have used the whole `MAX_SIZE` for their payload.
+pub fn fits<T: BoundedStorable>(value: &T) -> bool {
+    value.to_bytes().len() <= T::MAX_SIZE as usize
+}
+
+fn split_envelope(bytes: &[u8]) -> (u16, &[u8]) {
+    if bytes.len() >= ENVELOPE_SIZE && bytes[..2] == ENVELOPE_MAGIC {
+        (
+            u16::from_le_bytes([bytes[2], bytes[3]]),
+            &bytes[ENVELOPE_SIZE..],
+        )
+    } else {
+        (0, bytes)
+    }
+}
+
+/// Part of a legacy record that moved out of its layout.
+pub enum LegacySplit {
+    /// Inline attachment bytes, stored as the blob with the record's id.
+    Payload {
+        record_id: u64,
+        link: BlobLink,
+        content_type: String,
+        data: Vec<u8>,
+    },
+    /// Status changes of an appointment, stored under its id.
+    StatusHistory {
+        appointment_id: u64,
+        changes: Vec<StatusChange>,
+    },
+    /// Moves of an appointment between slots, stored under its id.
+    Reschedules {
+        appointment_id: u64,
+        reschedules: Vec<Reschedule>,
+    },
+    /// Free-text prescription of a report, stored as a prescription of the
+    /// report's patient.
+    Prescription {
+        report_id: u64,
+        patient_id: u64,
+        text: String,
+    },
+}
+
+thread_local! {
+    static LEGACY_SPLITS: RefCell<Option<Vec<LegacySplit>>> = RefCell::new(None);
+}
+
+/// Runs `f` and returns the splits of every legacy record it decoded.
+pub fn collect_legacy_splits<R>(f: impl FnOnce() -> R) -> (R, Vec<LegacySplit>) {
+    LEGACY_SPLITS.with(|splits| *splits.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let splits = LEGACY_SPLITS.with(|splits| splits.borrow_mut().take());
+    (result, splits.unwrap_or_default())
+}
+
+// Outside `collect_legacy_splits` the split is dropped, so plain reads of a
+// legacy record have no side effects
+fn defer_split(split: LegacySplit) {
+    LEGACY_SPLITS.with(|splits| {
+        if let Some(splits) = splits.borrow_mut().as_mut() {
+            splits.push(split);
+        }
+    });
+}
+
+fn unknown_version(version: u16) -> String {
+    format!("unknown layout version {}", version)
+}
+
+// Models still in their original layout; version 0 is that layout without an envelope
+macro_rules! original_layout {
+    ($($model:ty),*) => {
+        $(
+            impl Versioned for $model {
+                const VERSION: u16 = 1;
+
+                fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+                    match version {
+                        0 => decode_payload(payload),
+                        _ => Err(unknown_version(version)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+original_layout!(
+    Patient,
+    Calendly,
+    MigrationState,
+    Blob,
+    AvailabilityRule,
+    AuditEntry,
+    ConsentGrant,
+    Prescription,
+    Allergy,
+    Interaction,
+    Immunization,
+    Problem,
+    LabResult,
+    Vital,
+    MedicalRecordRevision,
+    StatusChange,
+    Reschedule
+);
+
+/// `Doctor` before time zones were recorded.
+#[derive(CandidType, Deserialize)]
+struct DoctorV1 {
+    id: u64,
+    principal_str: String,
+    fname: String,
+    lname: String,
+    dob: String,
+    specialism: String,
+    licence_no: u64,
+    id_no: u64,
+    sex: String,
+    country: String,
+    city: String,
+}
+
+impl Versioned for Doctor {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let legacy: DoctorV1 = decode_payload(payload)?;
+                Ok(Doctor {
+                    id: legacy.id,
+                    principal_str: legacy.principal_str,
+                    fname: legacy.fname,
+                    lname: legacy.lname,
+                    dob: legacy.dob,
+                    specialism: legacy.specialism,
+                    licence_no: legacy.licence_no,
+                    id_no: legacy.id_no,
+                    sex: legacy.sex,
+                    country: legacy.country,
+                    city: legacy.city,
+                    time_zone: "UTC".to_string(),
+                })
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// `Availability` as a weekday with free-form "HH:MM" times.
+#[derive(CandidType, Deserialize)]
+struct AvailabilityV1 {
+    id: u64,
+    doctor_id: u64,
+    day_of_week: u8,
+    start_time: String,
+    end_time: String,
+    #[allow(dead_code)]
+    is_available: bool,
+}
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+// Sunday 1970-01-04, the first Sunday after the epoch, in minutes
+const EPOCH_SUNDAY_MINUTES: u64 = 3 * 24 * 60;
+
+impl AvailabilityV1 {
+    // Weekly windows carry no date, so they are pinned to the first week after
+    // the epoch and withdrawn from booking until the doctor republishes them.
+    fn into_window(self) -> Availability {
+        let day_start = EPOCH_SUNDAY_MINUTES + u64::from(self.day_of_week % 7) * 24 * 60;
+        let start = legacy_minute_of_day(&self.start_time);
+        let end = legacy_minute_of_day(&self.end_time);
+        let (start_time, end_time) = match (start, end) {
+            (Some(start), Some(end)) if end > start => (
+                (day_start + start) * NANOS_PER_MINUTE,
+                (day_start + end) * NANOS_PER_MINUTE,
+            ),
+            _ => (0, 0),
+        };
+
+        Availability {
+            id: self.id,
+            doctor_id: self.doctor_id,
+            start_time,
+            end_time,
+            is_available: false,
+        }
+    }
+}
+
+// Parses "H:MM" or "HH:MM" into minutes since midnight
+fn legacy_minute_of_day(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    if minutes > 59 || hours * 60 + minutes > 24 * 60 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+impl Versioned for Availability {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let legacy: AvailabilityV1 = decode_payload(payload)?;
+                Ok(legacy.into_window())
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// `Identity` before roles were introduced.
+#[derive(CandidType, Deserialize)]
+struct IdentityV0 {
+    id: u64,
+    principal: String,
+}
+
+impl Versioned for Identity {
+    const VERSION: u16 = 1;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 => {
+                let legacy: IdentityV0 = decode_payload(payload)?;
+                Ok(Identity {
+                    id: legacy.id,
+                    principal: legacy.principal,
+                    roles: Vec::new(),
+                })
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// `Appointment` with a free-form status string.
+#[derive(CandidType, Deserialize)]
+struct AppointmentV1 {
+    id: u64,
+    patient_id: u64,
+    doctor_id: u64,
+    phone_no: String,
+    slot: String,
+    reason: String,
+    symtoms: String,
+    status: String,
+    appointment_type: String,
+}
+
+impl AppointmentV1 {
+    fn upgrade(self) -> AppointmentV2 {
+        // `complete_appointment` used to write "confirmed"
+        let status = match self.status.as_str() {
+            "cancelled" => AppointmentStatus::Cancelled,
+            "confirmed" => AppointmentStatus::Completed,
+            _ => AppointmentStatus::Requested,
+        };
+        AppointmentV2 {
+            id: self.id,
+            patient_id: self.patient_id,
+            doctor_id: self.doctor_id,
+            phone_no: self.phone_no,
+            slot: self.slot,
+            reason: self.reason,
+            symtoms: self.symtoms,
+            status,
+            status_history: Vec::new(),
+            appointment_type: self.appointment_type,
+        }
+    }
+}
+
+/// `Appointment` with the slot as the start time string of an availability.
+#[derive(CandidType, Deserialize)]
+struct AppointmentV2 {
+    id: u64,
+    patient_id: u64,
+    doctor_id: u64,
+    phone_no: String,
+    #[allow(dead_code)]
+    slot: String,
+    reason: String,
+    symtoms: String,
+    status: AppointmentStatus,
+    status_history: Vec<StatusChange>,
+    appointment_type: String,
+}
+
+impl AppointmentV2 {
+    // A bare "HH:MM" string names no date, so the booked window is unknown and
+    // appointments still ahead are flagged until they are rescheduled
+    fn upgrade(self) -> AppointmentV3 {
+        let status = match self.status {
+            AppointmentStatus::Requested
+            | AppointmentStatus::Confirmed
+            | AppointmentStatus::Rescheduled => AppointmentStatus::Unscheduled,
+            status => status,
+        };
+        AppointmentV3 {
+            id: self.id,
+            patient_id: self.patient_id,
+            doctor_id: self.doctor_id,
+            phone_no: self.phone_no,
+            slot_start: 0,
+            slot_end: 0,
+            reason: self.reason,
+            symtoms: self.symtoms,
+            status,
+            status_history: self.status_history,
+            appointment_type: self.appointment_type,
+        }
+    }
+}
+
+/// `Appointment` before reschedules were recorded.
+#[derive(CandidType, Deserialize)]
+struct AppointmentV3 {
+    id: u64,
+    patient_id: u64,
+    doctor_id: u64,
+    phone_no: String,
+    slot_start: u64,
+    slot_end: u64,
+    reason: String,
+    symtoms: String,
+    status: AppointmentStatus,
+    status_history: Vec<StatusChange>,
+    appointment_type: String,
+}
+
+impl AppointmentV3 {
+    fn upgrade(self) -> AppointmentV4 {
+        AppointmentV4 {
+            id: self.id,
+            patient_id: self.patient_id,
+            doctor_id: self.doctor_id,
+            phone_no: self.phone_no,
+            slot_start: self.slot_start,
+            slot_end: self.slot_end,
+            reason: self.reason,
+            symtoms: self.symtoms,
+            status: self.status,
+            status_history: self.status_history,
+            reschedules: Vec::new(),
+            appointment_type: self.appointment_type,
+        }
+    }
+}
+
+/// `Appointment` with its status history inline.
+#[derive(CandidType, Deserialize)]
+struct AppointmentV4 {
+    id: u64,
+    patient_id: u64,
+    doctor_id: u64,
+    phone_no: String,
+    slot_start: u64,
+    slot_end: u64,
+    reason: String,
+    symtoms: String,
+    status: AppointmentStatus,
+    status_history: Vec<StatusChange>,
+    reschedules: Vec<Reschedule>,
+    appointment_type: String,
+}
+
+impl AppointmentV4 {
+    fn upgrade(self) -> AppointmentV5 {
+        if !self.status_history.is_empty() {
+            defer_split(LegacySplit::StatusHistory {
+                appointment_id: self.id,
+                changes: self.status_history,
+            });
+        }
+        AppointmentV5 {
+            id: self.id,
+            patient_id: self.patient_id,
+            doctor_id: self.doctor_id,
+            phone_no: self.phone_no,
+            slot_start: self.slot_start,
+            slot_end: self.slot_end,
+            reason: self.reason,
+            symtoms: self.symtoms,
+            status: self.status,
+            reschedules: self.reschedules,
+            appointment_type: self.appointment_type,
+        }
+    }
+}
+
+/// `Appointment` with its reschedules inline.
+#[derive(CandidType, Deserialize)]
+struct AppointmentV5 {
+    id: u64,
+    patient_id: u64,
+    doctor_id: u64,
+    phone_no: String,
+    slot_start: u64,
+    slot_end: u64,
+    reason: String,
+    symtoms: String,
+    status: AppointmentStatus,
+    reschedules: Vec<Reschedule>,
+    appointment_type: String,
+}
+
+impl AppointmentV5 {
+    fn upgrade(self) -> Appointment {
+        if !self.reschedules.is_empty() {
+            defer_split(LegacySplit::Reschedules {
+                appointment_id: self.id,
+                reschedules: self.reschedules,
+            });
+        }
+        Appointment {
+            id: self.id,
+            patient_id: self.patient_id,
+            doctor_id: self.doctor_id,
+            phone_no: self.phone_no,
+            slot_start: self.slot_start,
+            slot_end: self.slot_end,
+            reason: self.reason,
+            symtoms: self.symtoms,
+            status: self.status,
+            appointment_type: self.appointment_type,
+        }
+    }
+}
+
+impl Versioned for Appointment {
+    const VERSION: u16 = 6;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let legacy: AppointmentV1 = decode_payload(payload)?;
+                Ok(legacy.upgrade().upgrade().upgrade().upgrade().upgrade())
+            }
+            2 => {
+                let legacy: AppointmentV2 = decode_payload(payload)?;
+                Ok(legacy.upgrade().upgrade().upgrade().upgrade())
+            }
+            3 => {
+                let legacy: AppointmentV3 = decode_payload(payload)?;
+                Ok(legacy.upgrade().upgrade().upgrade())
+            }
+            4 => {
+                let legacy: AppointmentV4 = decode_payload(payload)?;
+                Ok(legacy.upgrade().upgrade())
+            }
+            5 => {
+                let legacy: AppointmentV5 = decode_payload(payload)?;
+                Ok(legacy.upgrade())
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// `MultiMediaContent` before attachments moved to the blob store.
+#[derive(CandidType, Deserialize)]
+struct InlineMultiMediaContentV1 {
+    content_type: String,
+    data: Vec<u8>,
+}
+
+impl InlineMultiMediaContentV1 {
+    // The blob takes the record's id, so the reference is valid as soon as the
+    // migration has stored the payload
+    fn into_blob(self, link: BlobLink, record_id: u64) -> MultiMediaContent {
+        defer_split(LegacySplit::Payload {
+            record_id,
+            link,
+            content_type: self.content_type.clone(),
+            data: self.data,
+        });
+        MultiMediaContent {
+            content_type: self.content_type,
+            blob_id: record_id,
+        }
+    }
+}
+
+/// `Message` with inline attachment bytes.
+#[derive(CandidType, Deserialize)]
+struct MessageV1 {
+    id: u64,
+    sender_id: u64,
+    receiver_id: u64,
+    content: String,
+    multimedia_content: Option<InlineMultiMediaContentV1>,
+}
+
+impl Versioned for Message {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let legacy: MessageV1 = decode_payload(payload)?;
+                Ok(Message {
+                    id: legacy.id,
+                    sender_id: legacy.sender_id,
+                    receiver_id: legacy.receiver_id,
+                    content: legacy.content,
+                    multimedia_content: legacy
+                        .multimedia_content
+                        .map(|content| content.into_blob(BlobLink::Message(legacy.id), legacy.id)),
+                })
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// `Report` with inline attachment bytes.
+#[derive(CandidType, Deserialize)]
+struct ReportV1 {
+    id: u64,
+    patient_id: u64,
+    username: String,
+    symptoms: String,
+    diagnostic: String,
+    prescription: String,
+    recommendations: String,
+    multimedia_content: Option<InlineMultiMediaContentV1>,
+}
+
+impl ReportV1 {
+    fn upgrade(self) -> ReportV2 {
+        ReportV2 {
+            id: self.id,
+            patient_id: self.patient_id,
+            username: self.username,
+            symptoms: self.symptoms,
+            diagnostic: self.diagnostic,
+            prescription: self.prescription,
+            recommendations: self.recommendations,
+            multimedia_content: self
+                .multimedia_content
+                .map(|content| content.into_blob(BlobLink::Report(self.id), self.id)),
+        }
+    }
+}
+
+/// `Report` with a free-text prescription.
+#[derive(CandidType, Deserialize)]
+struct ReportV2 {
+    id: u64,
+    patient_id: u64,
+    username: String,
+    symptoms: String,
+    diagnostic: String,
+    prescription: String,
+    recommendations: String,
+    multimedia_content: Option<MultiMediaContent>,
+}
+
+impl ReportV2 {
+    fn upgrade(self) -> ReportV3 {
+        if !self.prescription.trim().is_empty() {
+            defer_split(LegacySplit::Prescription {
+                report_id: self.id,
+                patient_id: self.patient_id,
+                text: self.prescription,
+            });
+        }
+        ReportV3 {
+            id: self.id,
+            patient_id: self.patient_id,
+            username: self.username,
+            symptoms: self.symptoms,
+            diagnostic: self.diagnostic,
+            recommendations: self.recommendations,
+            multimedia_content: self.multimedia_content,
+        }
+    }
+}
+
+/// `Report` before reports were signed.
+#[derive(CandidType, Deserialize)]
+struct ReportV3 {
+    id: u64,
+    patient_id: u64,
+    username: String,
+    symptoms: String,
+    diagnostic: String,
+    recommendations: String,
+    multimedia_content: Option<MultiMediaContent>,
+}
+
+impl ReportV3 {
+    fn upgrade(self) -> ReportV4 {
+        ReportV4 {
+            id: self.id,
+            patient_id: self.patient_id,
+            username: self.username,
+            symptoms: self.symptoms,
+            diagnostic: self.diagnostic,
+            recommendations: self.recommendations,
+            multimedia_content: self.multimedia_content,
+            amends: None,
+            signature: None,
+        }
+    }
+}
+
+/// `Report` before reports were linked to a visit and its doctor.
+#[derive(CandidType, Deserialize)]
+struct ReportV4 {
+    id: u64,
+    patient_id: u64,
+    username: String,
+    symptoms: String,
+    diagnostic: String,
+    recommendations: String,
+    multimedia_content: Option<MultiMediaContent>,
+    amends: Option<u64>,
+    signature: Option<ReportSignature>,
+}
+
+impl ReportV4 {
+    fn upgrade(self) -> Report {
+        Report {
+            id: self.id,
+            patient_id: self.patient_id,
+            doctor_id: None,
+            appointment_id: None,
+            username: self.username,
+            symptoms: self.symptoms,
+            diagnostic: self.diagnostic,
+            recommendations: self.recommendations,
+            multimedia_content: self.multimedia_content,
+            amends: self.amends,
+            signature: self.signature,
+        }
+    }
+}
+
+impl Versioned for Report {
+    const VERSION: u16 = 5;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let legacy: ReportV1 = decode_payload(payload)?;
+                Ok(legacy.upgrade().upgrade().upgrade().upgrade())
+            }
+            2 => {
+                let legacy: ReportV2 = decode_payload(payload)?;
+                Ok(legacy.upgrade().upgrade().upgrade())
+            }
+            3 => {
+                let legacy: ReportV3 = decode_payload(payload)?;
+                Ok(legacy.upgrade().upgrade())
+            }
+            4 => {
+                let legacy: ReportV4 = decode_payload(payload)?;
+                Ok(legacy.upgrade())
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// `Data` with the payload inline.
+#[derive(CandidType, Deserialize)]
+struct DataV1 {
+    id: u64,
+    patient_username: String,
+    doctor_username: String,
+    data: Vec<u8>,
+}
+
+impl Versioned for Data {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let legacy: DataV1 = decode_payload(payload)?;
+                defer_split(LegacySplit::Payload {
+                    record_id: legacy.id,
+                    link: BlobLink::Data(legacy.id),
+                    content_type: "application/octet-stream".to_string(),
+                    data: legacy.data,
+                });
+                Ok(Data {
+                    id: legacy.id,
+                    patient_username: legacy.patient_username,
+                    doctor_username: legacy.doctor_username,
+                    blob_id: legacy.id,
+                })
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// `MedicalRecord` before revisions were kept.
+#[derive(CandidType, Deserialize)]
+struct MedicalRecordV1 {
+    id: u64,
+    patient_id: u64,
+    lab_results: String,
+    treatment_history: String,
+}
+
+impl Versioned for MedicalRecord {
+    const VERSION: u16 = 2;
+
+    fn upgrade(version: u16, payload: &[u8]) -> Result<Self, String> {
+        match version {
+            0 | 1 => {
+                let legacy: MedicalRecordV1 = decode_payload(payload)?;
+                Ok(MedicalRecord {
+                    id: legacy.id,
+                    patient_id: legacy.patient_id,
+                    lab_results: legacy.lab_results,
+                    treatment_history: legacy.treatment_history,
+                    revision: 1,
+                    deleted_at: None,
+                })
+            }
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// Row of the retired doctor identity table (MemoryId 8), read once by the
+/// migration to grant the Doctor role to its principals.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct LegacyDocIdentity {
+    pub id: u64,
+    pub principal: String,
+}
+
+impl Storable for LegacyDocIdentity {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+impl BoundedStorable for LegacyDocIdentity {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `_V0` fixtures are the Candid bytes the baseline release stored, i.e.
+    // `candid::encode_one` of the structs described above each one. Later
+    // fixtures carry the envelope of the layout version in their name.
+
+    // Patient { id: 7, username: "amina", identity_id: 3 }
+    const PATIENT_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x03, 0xdb, 0xb7, 0x01, 0x78, 0x96, 0x8c, 0xae, 0x87,
+        0x02, 0x71, 0xfc, 0x8e, 0xb6, 0x99, 0x0b, 0x78, 0x01, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x05, 0x61, 0x6d, 0x69, 0x6e, 0x61, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+
+    // Doctor { id: 9, principal_str: "2vxsx-fae", fname: "Jo", lname: "Otieno", .. }
+    const DOCTOR_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x0b, 0xdb, 0xb7, 0x01, 0x78, 0xf7, 0x84, 0xb1, 0x02,
+        0x71, 0xa6, 0xb7, 0xde, 0x02, 0x71, 0xb1, 0xbb, 0xfa, 0x1e, 0x71, 0xd6, 0xf4, 0xe6, 0xea,
+        0x01, 0x71, 0xab, 0xe3, 0x80, 0x8e, 0x04, 0x71, 0xef, 0x82, 0x83, 0xcf, 0x06, 0x78, 0xaa,
+        0x81, 0xc0, 0xee, 0x06, 0x71, 0xb7, 0xb1, 0x98, 0xc2, 0x07, 0x71, 0xe5, 0xea, 0xd2, 0xbb,
+        0x0b, 0x78, 0xe0, 0xd6, 0x9c, 0xc1, 0x0b, 0x71, 0x01, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x0a, 0x31, 0x39, 0x38, 0x30, 0x2d, 0x30, 0x32, 0x2d, 0x30, 0x31, 0x01,
+        0x46, 0x02, 0x4a, 0x6f, 0x05, 0x4b, 0x65, 0x6e, 0x79, 0x61, 0x07, 0x4e, 0x61, 0x69, 0x72,
+        0x6f, 0x62, 0x69, 0xd2, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x63, 0x61, 0x72,
+        0x64, 0x69, 0x6f, 0x6c, 0x6f, 0x67, 0x79, 0x06, 0x4f, 0x74, 0x69, 0x65, 0x6e, 0x6f, 0x2e,
+        0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x32, 0x76, 0x78, 0x73, 0x78, 0x2d, 0x66,
+        0x61, 0x65,
+    ];
+
+    // Appointment { id: 11, patient_id: 7, doctor_id: 9, slot: "09:30", status: "confirmed", .. }
+    const APPOINTMENT_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x09, 0xdb, 0xb7, 0x01, 0x78, 0xb2, 0xce, 0xef, 0x2f,
+        0x71, 0x95, 0xb2, 0xac, 0x62, 0x78, 0xa8, 0xac, 0x86, 0xf8, 0x01, 0x71, 0x9a, 0xce, 0xf9,
+        0xde, 0x02, 0x71, 0xfe, 0xbb, 0xd7, 0xe2, 0x04, 0x71, 0xbb, 0xe0, 0xf1, 0xf3, 0x0d, 0x78,
+        0xb2, 0x97, 0x98, 0xac, 0x0e, 0x71, 0xc4, 0x9f, 0xf4, 0xe4, 0x0f, 0x71, 0x01, 0x00, 0x0b,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x63, 0x6f, 0x6e, 0x66, 0x69, 0x72, 0x6d,
+        0x65, 0x64, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x6e, 0x6f, 0x6e, 0x65,
+        0x05, 0x76, 0x69, 0x73, 0x69, 0x74, 0x05, 0x30, 0x39, 0x3a, 0x33, 0x30, 0x09, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x30, 0x37, 0x30, 0x30, 0x07, 0x63, 0x68, 0x65, 0x63,
+        0x6b, 0x75, 0x70,
+    ];
+
+    // Identity { id: 3, principal: "2vxsx-fae" }
+    const IDENTITY_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x02, 0xdb, 0xb7, 0x01, 0x78, 0xae, 0x9d, 0xb1, 0x90,
+        0x01, 0x71, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x32, 0x76,
+        0x78, 0x73, 0x78, 0x2d, 0x66, 0x61, 0x65,
+    ];
+
+    // DocIdentity { id: 4, principal: "aaaaa-aa" }
+    const DOCIDENTITY_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x02, 0xdb, 0xb7, 0x01, 0x78, 0xae, 0x9d, 0xb1, 0x90,
+        0x01, 0x71, 0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x61, 0x61,
+        0x61, 0x61, 0x61, 0x2d, 0x61, 0x61,
+    ];
+
+    // Availability { id: 12, doctor_id: 9, day_of_week: 1, start_time: "9:00", .. }
+    const AVAILABILITY_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x06, 0xdb, 0xb7, 0x01, 0x78, 0xb1, 0x94, 0xf8, 0xd6,
+        0x06, 0x71, 0xca, 0xa0, 0xdd, 0xc7, 0x08, 0x71, 0xf4, 0xf3, 0xcf, 0xf4, 0x0a, 0x7e, 0xbb,
+        0xe0, 0xf1, 0xf3, 0x0d, 0x78, 0xb9, 0xa6, 0xa0, 0x99, 0x0f, 0x7b, 0x01, 0x00, 0x0c, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x31, 0x30, 0x3a, 0x33, 0x30, 0x04, 0x39, 0x3a,
+        0x30, 0x30, 0x01, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    // Calendly { id: 13, principle_id: "2vxsx-fae", calendly: "https://calendly.com/jo" }
+    const CALENDLY_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x03, 0xdb, 0xb7, 0x01, 0x78, 0xbe, 0xce, 0xaf, 0x81,
+        0x01, 0x71, 0xda, 0xd0, 0xa5, 0xf9, 0x07, 0x71, 0x01, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x09, 0x32, 0x76, 0x78, 0x73, 0x78, 0x2d, 0x66, 0x61, 0x65, 0x17, 0x68,
+        0x74, 0x74, 0x70, 0x73, 0x3a, 0x2f, 0x2f, 0x63, 0x61, 0x6c, 0x65, 0x6e, 0x64, 0x6c, 0x79,
+        0x2e, 0x63, 0x6f, 0x6d, 0x2f, 0x6a, 0x6f,
+    ];
+
+    // Message { id: 14, sender_id: 7, content: "hello", attachment bytes [1, 2, 3, 4], .. }
+    const MESSAGE_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x04, 0x6d, 0x7b, 0x6c, 0x02, 0xaa, 0xac, 0x8d, 0x93, 0x04, 0x00,
+        0xc0, 0xe5, 0xeb, 0x9b, 0x05, 0x71, 0x6e, 0x01, 0x6c, 0x05, 0xdb, 0xb7, 0x01, 0x78, 0xab,
+        0xa8, 0xd5, 0xa1, 0x01, 0x78, 0xb9, 0x9a, 0xde, 0xcb, 0x01, 0x71, 0x85, 0x9b, 0xe8, 0xd7,
+        0x02, 0x02, 0xe5, 0xd2, 0xf6, 0x82, 0x0a, 0x78, 0x01, 0x03, 0x0e, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x68, 0x65, 0x6c,
+        0x6c, 0x6f, 0x01, 0x04, 0x01, 0x02, 0x03, 0x04, 0x09, 0x69, 0x6d, 0x61, 0x67, 0x65, 0x2f,
+        0x70, 0x6e, 0x67, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    // Data { id: 15, patient_username: "amina", doctor_username: "jo", data: [9, 8, 7] }
+    const DATA_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x02, 0x6d, 0x7b, 0x6c, 0x04, 0xdb, 0xb7, 0x01, 0x78, 0xaa, 0xac,
+        0x8d, 0x93, 0x04, 0x00, 0xd0, 0xd5, 0xfb, 0xa9, 0x04, 0x71, 0xf6, 0xc4, 0xec, 0xcd, 0x04,
+        0x71, 0x01, 0x01, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x09, 0x08, 0x07,
+        0x05, 0x61, 0x6d, 0x69, 0x6e, 0x61, 0x02, 0x6a, 0x6f,
+    ];
+
+    // Appointment { id: 16, status: Confirmed, status_history: [Requested, Confirmed], .. }
+    const APPOINTMENT_V4: &[u8] = &[
+        0x48, 0x56, 0x04, 0x00, 0x44, 0x49, 0x44, 0x4c, 0x06, 0x6b, 0x08, 0xfa, 0xcf, 0xc7, 0xf0,
+        0x02, 0x7f, 0xcc, 0xd5, 0xa0, 0xef, 0x04, 0x7f, 0xfe, 0x93, 0xf2, 0x83, 0x05, 0x7f, 0xbf,
+        0xaa, 0xb8, 0xcb, 0x06, 0x7f, 0xce, 0x92, 0xa7, 0xc6, 0x0b, 0x7f, 0xf1, 0xcc, 0x9d, 0xa0,
+        0x0c, 0x7f, 0xd2, 0x81, 0xd0, 0xc6, 0x0d, 0x7f, 0xeb, 0x82, 0xae, 0x88, 0x0f, 0x7f, 0x6c,
+        0x06, 0xf3, 0xa9, 0x01, 0x78, 0xd7, 0xab, 0x01, 0x71, 0xde, 0xde, 0xf3, 0x94, 0x07, 0x78,
+        0xd7, 0x8f, 0xe2, 0xcf, 0x08, 0x78, 0x8d, 0x99, 0x89, 0xf5, 0x08, 0x78, 0xc6, 0xd6, 0xab,
+        0x90, 0x0b, 0x78, 0x6d, 0x01, 0x6c, 0x03, 0xf3, 0xa9, 0x01, 0x78, 0xd7, 0xab, 0x01, 0x71,
+        0xb2, 0xce, 0xef, 0x2f, 0x00, 0x6d, 0x03, 0x6c, 0x0c, 0xdb, 0xb7, 0x01, 0x78, 0xb2, 0xce,
+        0xef, 0x2f, 0x00, 0x95, 0xb2, 0xac, 0x62, 0x78, 0xa8, 0xac, 0x86, 0xf8, 0x01, 0x71, 0x9a,
+        0xce, 0xf9, 0xde, 0x02, 0x71, 0xa9, 0xa0, 0xfd, 0xce, 0x04, 0x02, 0xa1, 0xd8, 0xe0, 0x9e,
+        0x08, 0x78, 0x87, 0xf0, 0x83, 0xff, 0x09, 0x04, 0xda, 0xfb, 0x9f, 0xbf, 0x0a, 0x78, 0xbb,
+        0xe0, 0xf1, 0xf3, 0x0d, 0x78, 0xb2, 0x97, 0x98, 0xac, 0x0e, 0x71, 0xc4, 0x9f, 0xf4, 0xe4,
+        0x0f, 0x71, 0x01, 0x05, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x07, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x6e, 0x6f, 0x6e, 0x65, 0x05, 0x76, 0x69, 0x73,
+        0x69, 0x74, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x32, 0x76, 0x78, 0x73, 0x78, 0x2d, 0x66, 0x61, 0x65,
+        0x04, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x61, 0x61, 0x61, 0x61, 0x61,
+        0x2d, 0x61, 0x61, 0x03, 0xc8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x30, 0x37, 0x30, 0x30, 0x07, 0x63, 0x68, 0x65, 0x63,
+        0x6b, 0x75, 0x70,
+    ];
+
+    // Appointment { id: 18, slot_start: 300, reschedules: [100..200 -> 300..400], .. }
+    const APPOINTMENT_V5: &[u8] = &[
+        0x48, 0x56, 0x05, 0x00, 0x44, 0x49, 0x44, 0x4c, 0x04, 0x6b, 0x09, 0xfa, 0xcf, 0xc7, 0xf0,
+        0x02, 0x7f, 0xcc, 0xd5, 0xa0, 0xef, 0x04, 0x7f, 0xfe, 0x93, 0xf2, 0x83, 0x05, 0x7f, 0xbf,
+        0xaa, 0xb8, 0xcb, 0x06, 0x7f, 0x94, 0xc1, 0xad, 0x9a, 0x07, 0x7f, 0xce, 0x92, 0xa7, 0xc6,
+        0x0b, 0x7f, 0xf1, 0xcc, 0x9d, 0xa0, 0x0c, 0x7f, 0xd2, 0x81, 0xd0, 0xc6, 0x0d, 0x7f, 0xeb,
+        0x82, 0xae, 0x88, 0x0f, 0x7f, 0x6c, 0x06, 0xf3, 0xa9, 0x01, 0x78, 0xd7, 0xab, 0x01, 0x71,
+        0xde, 0xde, 0xf3, 0x94, 0x07, 0x78, 0xd7, 0x8f, 0xe2, 0xcf, 0x08, 0x78, 0x8d, 0x99, 0x89,
+        0xf5, 0x08, 0x78, 0xc6, 0xd6, 0xab, 0x90, 0x0b, 0x78, 0x6d, 0x01, 0x6c, 0x0b, 0xdb, 0xb7,
+        0x01, 0x78, 0xb2, 0xce, 0xef, 0x2f, 0x00, 0x95, 0xb2, 0xac, 0x62, 0x78, 0xa8, 0xac, 0x86,
+        0xf8, 0x01, 0x71, 0x9a, 0xce, 0xf9, 0xde, 0x02, 0x71, 0xa9, 0xa0, 0xfd, 0xce, 0x04, 0x02,
+        0xa1, 0xd8, 0xe0, 0x9e, 0x08, 0x78, 0xda, 0xfb, 0x9f, 0xbf, 0x0a, 0x78, 0xbb, 0xe0, 0xf1,
+        0xf3, 0x0d, 0x78, 0xb2, 0x97, 0x98, 0xac, 0x0e, 0x71, 0xc4, 0x9f, 0xf4, 0xe4, 0x0f, 0x71,
+        0x01, 0x03, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x07, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x04, 0x6e, 0x6f, 0x6e, 0x65, 0x05, 0x76, 0x69, 0x73, 0x69, 0x74,
+        0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x61, 0x61, 0x61, 0x61, 0x61,
+        0x2d, 0x61, 0x61, 0x2c, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x90, 0x01, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc8, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x90, 0x01,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04,
+        0x30, 0x37, 0x30, 0x30, 0x07, 0x63, 0x68, 0x65, 0x63, 0x6b, 0x75, 0x70,
+    ];
+
+    // Report { id: 18, prescription: "rest and fluids", .. }
+    const REPORT_V2: &[u8] = &[
+        0x48, 0x56, 0x02, 0x00, 0x44, 0x49, 0x44, 0x4c, 0x03, 0x6c, 0x02, 0xbd, 0xd9, 0xb0, 0xa2,
+        0x04, 0x78, 0xc0, 0xe5, 0xeb, 0x9b, 0x05, 0x71, 0x6e, 0x00, 0x6c, 0x08, 0xdb, 0xb7, 0x01,
+        0x78, 0x95, 0xb2, 0xac, 0x62, 0x78, 0x96, 0x8c, 0xae, 0x87, 0x02, 0x71, 0x85, 0x9b, 0xe8,
+        0xd7, 0x02, 0x01, 0x9a, 0xb8, 0x9b, 0xf8, 0x02, 0x71, 0xe7, 0xd2, 0xcb, 0xd3, 0x03, 0x71,
+        0x9a, 0xe8, 0xaf, 0xa3, 0x04, 0x71, 0xaa, 0x97, 0xdc, 0xc0, 0x0b, 0x71, 0x01, 0x02, 0x12,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x05, 0x61, 0x6d, 0x69, 0x6e, 0x61, 0x00, 0x0f, 0x72, 0x65, 0x73, 0x74, 0x20, 0x61, 0x6e,
+        0x64, 0x20, 0x66, 0x6c, 0x75, 0x69, 0x64, 0x73, 0x04, 0x63, 0x6f, 0x6c, 0x64, 0x05, 0x73,
+        0x6c, 0x65, 0x65, 0x70, 0x05, 0x63, 0x6f, 0x75, 0x67, 0x68,
+    ];
+
+    // Appointment { id: 17, slot: "14:00", status: "pending", .. }
+    const OPEN_APPOINTMENT_V0: &[u8] = &[
+        0x44, 0x49, 0x44, 0x4c, 0x01, 0x6c, 0x09, 0xdb, 0xb7, 0x01, 0x78, 0xb2, 0xce, 0xef, 0x2f,
+        0x71, 0x95, 0xb2, 0xac, 0x62, 0x78, 0xa8, 0xac, 0x86, 0xf8, 0x01, 0x71, 0x9a, 0xce, 0xf9,
+        0xde, 0x02, 0x71, 0xfe, 0xbb, 0xd7, 0xe2, 0x04, 0x71, 0xbb, 0xe0, 0xf1, 0xf3, 0x0d, 0x78,
+        0xb2, 0x97, 0x98, 0xac, 0x0e, 0x71, 0xc4, 0x9f, 0xf4, 0xe4, 0x0f, 0x71, 0x01, 0x00, 0x11,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x70, 0x65, 0x6e, 0x64, 0x69, 0x6e, 0x67,
+        0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x6e, 0x6f, 0x6e, 0x65, 0x05, 0x76,
+        0x69, 0x73, 0x69, 0x74, 0x05, 0x31, 0x34, 0x3a, 0x30, 0x30, 0x09, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x04, 0x30, 0x37, 0x30, 0x30, 0x09, 0x66, 0x6f, 0x6c, 0x6c, 0x6f, 0x77,
+        0x2d, 0x75, 0x70,
+    ];
+
+    fn from_fixture<T: Storable>(bytes: &[u8]) -> T {
+        T::from_bytes(Cow::Borrowed(bytes))
+    }
+
+    // Writes the record back in the current envelope and reads it again
+    fn round_trip<T: Versioned + BoundedStorable>(value: &T) -> T {
+        let bytes = value.to_bytes().into_owned();
+        assert_eq!(bytes[..2], ENVELOPE_MAGIC);
+        assert_eq!(u16::from_le_bytes([bytes[2], bytes[3]]), T::VERSION);
+        assert!(fits(value));
+        T::from_bytes(Cow::Owned(bytes))
+    }
+
+    fn assert_patient(patient: &Patient) {
+        assert_eq!(patient.id, 7);
+        assert_eq!(patient.username, "amina");
+        assert_eq!(patient.identity_id, 3);
+    }
+
+    #[test]
+    fn patient_from_baseline_layout() {
+        let patient: Patient = from_fixture(PATIENT_V0);
+        assert_patient(&patient);
+        assert_patient(&round_trip(&patient));
+    }
+
+    fn assert_doctor(doctor: &Doctor) {
+        assert_eq!(doctor.id, 9);
+        assert_eq!(doctor.principal_str, "2vxsx-fae");
+        assert_eq!(doctor.lname, "Otieno");
+        assert_eq!(doctor.licence_no, 1234);
+        assert_eq!(doctor.city, "Nairobi");
+        assert_eq!(doctor.time_zone, "UTC");
+    }
+
+    #[test]
+    fn doctor_from_baseline_layout() {
+        let doctor: Doctor = from_fixture(DOCTOR_V0);
+        assert_doctor(&doctor);
+        assert_doctor(&round_trip(&doctor));
+    }
+
+    fn assert_appointment(appointment: &Appointment) {
+        assert_eq!(appointment.id, 11);
+        assert_eq!(appointment.patient_id, 7);
+        assert_eq!(appointment.doctor_id, 9);
+        assert_eq!(appointment.reason, "checkup");
+        assert_eq!(appointment.status, AppointmentStatus::Completed);
+        assert_eq!(appointment.appointment_type, "visit");
+    }
+
+    #[test]
+    fn appointment_from_baseline_layout() {
+        let appointment: Appointment = from_fixture(APPOINTMENT_V0);
+        assert_appointment(&appointment);
+        assert_appointment(&round_trip(&appointment));
+    }
+
+    fn assert_identity(identity: &Identity) {
+        assert_eq!(identity.id, 3);
+        assert_eq!(identity.principal, "2vxsx-fae");
+        assert!(identity.roles.is_empty());
+    }
+
+    #[test]
+    fn identity_from_baseline_layout() {
+        let identity: Identity = from_fixture(IDENTITY_V0);
+        assert_identity(&identity);
+        assert_identity(&round_trip(&identity));
+    }
+
+    #[test]
+    fn docidentity_from_baseline_layout() {
+        let docidentity: LegacyDocIdentity = from_fixture(DOCIDENTITY_V0);
+        assert_eq!(docidentity.id, 4);
+        assert_eq!(docidentity.principal, "aaaaa-aa");
+    }
+
+    fn assert_availability(availability: &Availability) {
+        // Monday 1970-01-05, 09:00 to 10:30
+        let monday = EPOCH_SUNDAY_MINUTES + 24 * 60;
+        assert_eq!(availability.id, 12);
+        assert_eq!(availability.doctor_id, 9);
+        assert_eq!(
+            availability.start_time,
+            (monday + 9 * 60) * NANOS_PER_MINUTE
+        );
+        assert_eq!(
+            availability.end_time,
+            (monday + 10 * 60 + 30) * NANOS_PER_MINUTE
+        );
+        assert!(!availability.is_available);
+    }
+
+    #[test]
+    fn availability_from_baseline_layout() {
+        let availability: Availability = from_fixture(AVAILABILITY_V0);
+        assert_availability(&availability);
+        assert_availability(&round_trip(&availability));
+    }
+
+    fn assert_calendly(calendly: &Calendly) {
+        assert_eq!(calendly.id, 13);
+        assert_eq!(calendly.principle_id, "2vxsx-fae");
+        assert_eq!(calendly.calendly, "https://calendly.com/jo");
+    }
+
+    #[test]
+    fn calendly_from_baseline_layout() {
+        let calendly: Calendly = from_fixture(CALENDLY_V0);
+        assert_calendly(&calendly);
+        assert_calendly(&round_trip(&calendly));
+    }
+
+    // Decodes a fixture as the migration does, expecting one payload split
+    fn with_payload_split<T: Storable>(bytes: &[u8]) -> (T, u64, BlobLink, String, Vec<u8>) {
+        let (value, mut splits) = collect_legacy_splits(|| from_fixture::<T>(bytes));
+        assert_eq!(splits.len(), 1);
+        match splits.remove(0) {
+            LegacySplit::Payload {
+                record_id,
+                link,
+                content_type,
+                data,
+            } => (value, record_id, link, content_type, data),
+            LegacySplit::StatusHistory { .. }
+            | LegacySplit::Reschedules { .. }
+            | LegacySplit::Prescription { .. } => panic!("expected a payload"),
+        }
+    }
+
+    fn assert_message(message: &Message) {
+        assert_eq!(message.id, 14);
+        assert_eq!(message.sender_id, 7);
+        assert_eq!(message.receiver_id, 9);
+        assert_eq!(message.content, "hello");
+        let attachment = message.multimedia_content.as_ref().unwrap();
+        assert_eq!(attachment.content_type, "image/png");
+        assert_eq!(attachment.blob_id, 14);
+    }
+
+    #[test]
+    fn message_from_baseline_layout() {
+        let (message, record_id, link, content_type, data) =
+            with_payload_split::<Message>(MESSAGE_V0);
+        assert_eq!(record_id, 14);
+        assert_eq!(link, BlobLink::Message(14));
+        assert_eq!(content_type, "image/png");
+        assert_eq!(data, vec![1, 2, 3, 4]);
+        assert_message(&message);
+        assert_message(&round_trip(&message));
+    }
+
+    #[test]
+    fn plain_decode_collects_no_splits() {
+        let message: Message = from_fixture(MESSAGE_V0);
+        assert_message(&message);
+        let (_, splits) = collect_legacy_splits(|| ());
+        assert!(splits.is_empty());
+    }
+
+    fn assert_data(data: &Data) {
+        assert_eq!(data.id, 15);
+        assert_eq!(data.patient_username, "amina");
+        assert_eq!(data.doctor_username, "jo");
+        assert_eq!(data.blob_id, 15);
+    }
+
+    #[test]
+    fn data_from_baseline_layout() {
+        let (data, record_id, link, content_type, payload) = with_payload_split::<Data>(DATA_V0);
+        assert_eq!(record_id, 15);
+        assert_eq!(link, BlobLink::Data(15));
+        assert_eq!(content_type, "application/octet-stream");
+        assert_eq!(payload, vec![9, 8, 7]);
+        assert_data(&data);
+        assert_data(&round_trip(&data));
+    }
+
+    #[test]
+    fn open_appointment_from_baseline_layout_is_unscheduled() {
+        let appointment: Appointment = from_fixture(OPEN_APPOINTMENT_V0);
+        assert_eq!(appointment.id, 17);
+        assert_eq!(appointment.status, AppointmentStatus::Unscheduled);
+        assert_eq!(round_trip(&appointment).reason, "follow-up");
+    }
+
+    #[test]
+    fn appointment_history_moves_out_of_the_record() {
+        let (appointment, mut splits) =
+            collect_legacy_splits(|| from_fixture::<Appointment>(APPOINTMENT_V4));
+        assert_eq!(appointment.id, 16);
+        assert_eq!(appointment.status, AppointmentStatus::Confirmed);
+        assert_eq!(appointment.slot_start, 100);
+        assert_eq!(appointment.slot_end, 200);
+        assert_eq!(round_trip(&appointment).appointment_type, "visit");
+
+        assert_eq!(splits.len(), 1);
+        match splits.remove(0) {
+            LegacySplit::StatusHistory {
+                appointment_id,
+                changes,
+            } => {
+                assert_eq!(appointment_id, 16);
+                let statuses: Vec<_> = changes.iter().map(|change| change.status).collect();
+                assert_eq!(
+                    statuses,
+                    vec![AppointmentStatus::Requested, AppointmentStatus::Confirmed]
+                );
+                assert_eq!(changes[1].by, "aaaaa-aa");
+            }
+            LegacySplit::Payload { .. }
+            | LegacySplit::Reschedules { .. }
+            | LegacySplit::Prescription { .. } => panic!("expected the status history"),
+        }
+    }
+
+    #[test]
+    fn appointment_reschedules_move_out_of_the_record() {
+        let (appointment, mut splits) =
+            collect_legacy_splits(|| from_fixture::<Appointment>(APPOINTMENT_V5));
+        assert_eq!(appointment.id, 18);
+        assert_eq!(appointment.slot_start, 300);
+        assert_eq!(round_trip(&appointment).appointment_type, "visit");
+
+        assert_eq!(splits.len(), 1);
+        match splits.remove(0) {
+            LegacySplit::Reschedules {
+                appointment_id,
+                reschedules,
+            } => {
+                assert_eq!(appointment_id, 18);
+                assert_eq!(reschedules.len(), 1);
+                assert_eq!(reschedules[0].from_start, 100);
+                assert_eq!(reschedules[0].to_start, 300);
+                assert_eq!(reschedules[0].by, "aaaaa-aa");
+            }
+            LegacySplit::Payload { .. }
+            | LegacySplit::StatusHistory { .. }
+            | LegacySplit::Prescription { .. } => panic!("expected the reschedules"),
+        }
+    }
+
+    #[test]
+    fn report_prescription_moves_out_of_the_record() {
+        let (report, mut splits) = collect_legacy_splits(|| from_fixture::<Report>(REPORT_V2));
+        assert_eq!(report.id, 18);
+        assert_eq!(report.diagnostic, "cold");
+        assert_eq!(round_trip(&report).recommendations, "sleep");
+
+        assert_eq!(splits.len(), 1);
+        match splits.remove(0) {
+            LegacySplit::Prescription {
+                report_id,
+                patient_id,
+                text,
+            } => {
+                assert_eq!(report_id, 18);
+                assert_eq!(patient_id, 7);
+                assert_eq!(text, "rest and fluids");
+            }
+            _ => panic!("expected the prescription"),
+        }
+    }
+
+    #[test]
+    fn envelope_counts_against_max_size() {
+        let patient = Patient {
+            id: 1,
+            username: "x".repeat(Patient::MAX_SIZE as usize),
+            identity_id: 1,
+        };
+        assert!(!fits(&patient));
+    }
+}
\ No newline at end of file
@@ -1,16 +1,20 @@
 //! Storage management and type definitions
 
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{Cell, DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{Cell, DefaultMemoryImpl, Log, StableBTreeMap};
 use std::cell::RefCell;
 
 use crate::models::{
-    Appointment, Availability, Calendly, Data, DocIdentity, Doctor, Identity, MedicalRecord,
-    Message, Patient, Report,
+    Allergy, Appointment, AuditEntry, Availability, AvailabilityRule, Blob, BlobChunk,
+    BlobChunkKey, Calendly, ConsentGrant, Data, Doctor, Identity, Immunization, IndexKey,
+    Interaction, LabResult, MedicalRecord, MedicalRecordRevision, Message, MigrationState, NameKey,
+    Patient, Prescription, Problem, Report, Reschedule, StatusChange, Vital,
 };
+use crate::schema::LegacyDocIdentity;
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 pub type IdCell = Cell<u64, Memory>;
+pub type AuditLog = Log<AuditEntry, Memory, Memory>;
 
 thread_local! {
     pub static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -57,7 +61,9 @@ thread_local! {
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
     ));
 
-    pub static DOCIDENTITY_STORAGE: RefCell<StableBTreeMap<u64, DocIdentity, Memory>> =
+    // Retired doctor identity table; drained into Doctor role grants by the
+    // upgrade migration. Do not reuse MemoryId 8.
+    pub static LEGACY_DOCIDENTITY_STORAGE: RefCell<StableBTreeMap<u64, LegacyDocIdentity, Memory>> =
     RefCell::new(StableBTreeMap::init(
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
     ));
@@ -75,5 +81,214 @@ thread_local! {
     pub static AVAILABILITY_STORAGE: RefCell<StableBTreeMap<u64, Availability, Memory>> =
     RefCell::new(StableBTreeMap::init(
         MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
-));
+    ));
+
+    pub static MIGRATION_STATE: RefCell<Cell<MigrationState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+            MigrationState::default(),
+        )
+        .expect("Cannot create the migration state")
+    );
+
+    pub static BLOB_STORAGE: RefCell<StableBTreeMap<u64, Blob, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    pub static BLOB_CHUNK_STORAGE: RefCell<StableBTreeMap<BlobChunkKey, BlobChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    pub static AVAILABILITY_RULE_STORAGE: RefCell<StableBTreeMap<u64, AvailabilityRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Secondary indexes, maintained by `index`
+    pub static APPOINTMENTS_BY_DOCTOR: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    pub static APPOINTMENTS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    pub static AVAILABILITY_BY_DOCTOR: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    pub static PATIENT_BY_USERNAME: RefCell<StableBTreeMap<NameKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    pub static PATIENT_BY_IDENTITY: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    pub static IDENTITY_BY_PRINCIPAL: RefCell<StableBTreeMap<NameKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    pub static DOCTOR_BY_PRINCIPAL: RefCell<StableBTreeMap<NameKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    // Append-only; entries are never rewritten, not even by migrations
+    pub static AUDIT_LOG: RefCell<AuditLog> = RefCell::new(
+        AuditLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        )
+        .expect("Cannot create the audit log")
+    );
+
+    pub static CONSENT_STORAGE: RefCell<StableBTreeMap<u64, ConsentGrant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    pub static CONSENTS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    pub static PRESCRIPTION_STORAGE: RefCell<StableBTreeMap<u64, Prescription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    pub static PRESCRIPTIONS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    pub static ALLERGY_STORAGE: RefCell<StableBTreeMap<u64, Allergy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    pub static ALLERGIES_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    pub static INTERACTION_STORAGE: RefCell<StableBTreeMap<u64, Interaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Keyed by a hash of the drug name, see `index::drug_key`
+    pub static INTERACTIONS_BY_DRUG: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    pub static IMMUNIZATION_STORAGE: RefCell<StableBTreeMap<u64, Immunization, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    pub static IMMUNIZATIONS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    pub static PROBLEM_STORAGE: RefCell<StableBTreeMap<u64, Problem, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    pub static PROBLEMS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    pub static LAB_RESULT_STORAGE: RefCell<StableBTreeMap<u64, LabResult, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    pub static LAB_RESULTS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    pub static VITAL_STORAGE: RefCell<StableBTreeMap<u64, Vital, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    pub static VITALS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    // Keyed by `(record id, revision)`, so a record's history is one range
+    pub static MEDICAL_RECORD_REVISIONS: RefCell<StableBTreeMap<IndexKey, MedicalRecordRevision, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    pub static MEDICAL_RECORDS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    pub static REPORTS_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    pub static REPORTS_BY_DOCTOR: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    pub static REPORTS_BY_APPOINTMENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    // Keyed by `(appointment id, sequence number)`, oldest change first
+    pub static APPOINTMENT_STATUS_HISTORY: RefCell<StableBTreeMap<IndexKey, StatusChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    // Linked under a hash of the patient's username
+    pub static DATA_BY_PATIENT: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    pub static MESSAGES_BY_PARTY: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    // Linked under a hash of the owner's principal
+    pub static BLOBS_BY_OWNER: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    pub static AVAILABILITY_RULES_BY_DOCTOR: RefCell<StableBTreeMap<IndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    // Keyed by `(appointment id, sequence number)`, oldest move first
+    pub static APPOINTMENT_RESCHEDULES: RefCell<StableBTreeMap<IndexKey, Reschedule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
 }
\ No newline at end of file
@@ -9,4 +9,8 @@ pub fn generate_id() -> u64 {
             counter.borrow_mut().set(current_value + 1)
         })
         .expect("cannot increment id counter")
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
\ No newline at end of file
@@ -0,0 +1,393 @@
+//! Vital sign readings and their trends
+//!
+//! Readings are stored one per measurement in a fixed unit per type. Stats
+//! summarize each component over a window; "since last visit" compares the
+//! latest reading with the last one taken at an earlier appointment, which is
+//! what a doctor writing the visit's `Report` looks at.
+
+use crate::appointment::get_appointment_by_id;
+use crate::audit::{audited, audited_create, record_reads};
+use crate::auth::{caller_context, CallerContext};
+use crate::error::Error;
+use crate::index::{index_vital, unindex_vital, vital_ids_by_patient};
+use crate::models::{
+    AuditAction, AuditEntity, ConsentScope, Role, Vital, VitalFilter, VitalMeasurement, VitalStats,
+    VitalType,
+};
+use crate::patient::get_patient_by_id;
+use crate::storage::VITAL_STORAGE;
+use crate::utils::generate_id;
+
+const VITAL_TYPES: [VitalType; 6] = [
+    VitalType::BloodPressure,
+    VitalType::HeartRate,
+    VitalType::Temperature,
+    VitalType::SpO2,
+    VitalType::Weight,
+    VitalType::Glucose,
+];
+
+#[ic_cdk::update]
+pub fn record_vital(
+    patient_id: u64,
+    measurement: VitalMeasurement,
+    measured_at: u64,
+    appointment_id: Option<u64>,
+) -> Result<Vital, Error> {
+    audited_create(
+        "record_vital",
+        AuditEntity::Vital,
+        |vital: &Vital| vital.id,
+        || {
+            validate_measurement(&measurement)?;
+
+            let now = ic_cdk::api::time();
+            if measured_at > now {
+                return Err(Error::InvalidInput {
+                    msg: "Measurement time cannot be in the future".to_string(),
+                });
+            }
+
+            // Check if the patient exists
+            if get_patient_by_id(&patient_id).is_none() {
+                return Err(Error::NotFound {
+                    msg: format!("Patient with id={} not found", patient_id),
+                });
+            }
+            if let Some(appointment_id) = appointment_id {
+                let at_visit = get_appointment_by_id(&appointment_id)
+                    .map_or(false, |appointment| appointment.patient_id == patient_id);
+                if !at_visit {
+                    return Err(Error::InvalidInput {
+                        msg: format!(
+                            "Appointment with id={} is not one of the patient's",
+                            appointment_id
+                        ),
+                    });
+                }
+            }
+
+            let caller = caller_context();
+            require_vitals_writer(&caller, patient_id)?;
+
+            let vital = Vital {
+                id: generate_id(),
+                patient_id,
+                measurement,
+                measured_at,
+                appointment_id,
+                recorded_at: now,
+                recorded_by: caller.principal,
+            };
+
+            VITAL_STORAGE.with(|service| service.borrow_mut().insert(vital.id, vital.clone()));
+            index_vital(&vital);
+            Ok(vital)
+        },
+    )
+}
+
+/// Readings of the patient in measurement order.
+#[ic_cdk::update]
+pub fn list_vitals(patient_id: u64, filter: VitalFilter) -> Result<Vec<Vital>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadRecords)?;
+
+    let vitals = get_vitals_by_patient(patient_id, &filter);
+    record_reads(
+        "list_vitals",
+        AuditEntity::Vital,
+        vitals.iter().map(|vital| vital.id),
+    );
+    Ok(vitals)
+}
+
+/// Min, max, mean, latest and change since the last visit of every vital
+/// sign component in the window.
+#[ic_cdk::update]
+pub fn get_vital_stats(patient_id: u64, filter: VitalFilter) -> Result<Vec<VitalStats>, Error> {
+    caller_context().require_patient_access(patient_id, ConsentScope::ReadRecords)?;
+
+    let vitals = get_vitals_by_patient(patient_id, &filter);
+    record_reads(
+        "get_vital_stats",
+        AuditEntity::Vital,
+        vitals.iter().map(|vital| vital.id),
+    );
+    Ok(vital_stats(&vitals))
+}
+
+/// Removes a reading entered in error.
+#[ic_cdk::update]
+pub fn delete_vital(vital_id: u64) -> Result<(), Error> {
+    audited(
+        "delete_vital",
+        AuditEntity::Vital,
+        vital_id,
+        AuditAction::Delete,
+        || {
+            let vital = get_vital_by_id(&vital_id).ok_or(Error::NotFound {
+                msg: format!("Vital with id={} not found", vital_id),
+            })?;
+            require_vitals_writer(&caller_context(), vital.patient_id)?;
+
+            VITAL_STORAGE.with(|service| service.borrow_mut().remove(&vital_id));
+            unindex_vital(&vital);
+            Ok(())
+        },
+    )
+}
+
+pub fn get_vital_by_id(vital_id: &u64) -> Option<Vital> {
+    VITAL_STORAGE.with(|service| service.borrow().get(vital_id))
+}
+
+/// Readings of the patient matching the filter, oldest first.
+pub fn get_vitals_by_patient(patient_id: u64, filter: &VitalFilter) -> Vec<Vital> {
+    let mut vitals: Vec<Vital> = VITAL_STORAGE.with(|service| {
+        let storage = service.borrow();
+        vital_ids_by_patient(patient_id)
+            .iter()
+            .filter_map(|vital_id| storage.get(vital_id))
+            .filter(|vital| {
+                filter.vital_type.map_or(true, |vital_type| {
+                    vital_type_of(&vital.measurement) == vital_type
+                }) && filter.from.map_or(true, |from| vital.measured_at >= from)
+                    && filter.to.map_or(true, |to| vital.measured_at < to)
+            })
+            .collect()
+    });
+    vitals.sort_by_key(|vital| vital.measured_at);
+    vitals
+}
+
+/// Stats per vital sign component of readings sorted oldest first.
+pub fn vital_stats(vitals: &[Vital]) -> Vec<VitalStats> {
+    let mut stats = Vec::new();
+    for vital_type in VITAL_TYPES {
+        let readings: Vec<&Vital> = vitals
+            .iter()
+            .filter(|vital| vital_type_of(&vital.measurement) == vital_type)
+            .collect();
+        let latest = match readings.last() {
+            Some(latest) => *latest,
+            None => continue,
+        };
+
+        // Last reading taken at a visit other than the latest reading's
+        let baseline = readings.iter().rev().skip(1).find(|vital| {
+            vital.appointment_id.is_some() && vital.appointment_id != latest.appointment_id
+        });
+
+        for (index, (component, latest_value)) in
+            components(&latest.measurement).into_iter().enumerate()
+        {
+            let values: Vec<f64> = readings
+                .iter()
+                .map(|vital| components(&vital.measurement)[index].1)
+                .collect();
+            stats.push(VitalStats {
+                vital_type,
+                component: component.to_string(),
+                unit: unit_of(vital_type).to_string(),
+                count: values.len() as u64,
+                min: values.iter().copied().fold(f64::INFINITY, f64::min),
+                max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                mean: values.iter().sum::<f64>() / values.len() as f64,
+                latest: latest_value,
+                latest_at: latest.measured_at,
+                change_since_last_visit: baseline
+                    .map(|vital| latest_value - components(&vital.measurement)[index].1),
+            });
+        }
+    }
+    stats
+}
+
+pub fn vital_type_of(measurement: &VitalMeasurement) -> VitalType {
+    match measurement {
+        VitalMeasurement::BloodPressure { .. } => VitalType::BloodPressure,
+        VitalMeasurement::HeartRate(_) => VitalType::HeartRate,
+        VitalMeasurement::Temperature(_) => VitalType::Temperature,
+        VitalMeasurement::SpO2(_) => VitalType::SpO2,
+        VitalMeasurement::Weight(_) => VitalType::Weight,
+        VitalMeasurement::Glucose(_) => VitalType::Glucose,
+    }
+}
+
+fn components(measurement: &VitalMeasurement) -> Vec<(&'static str, f64)> {
+    match measurement {
+        VitalMeasurement::BloodPressure {
+            systolic,
+            diastolic,
+        } => vec![("systolic", *systolic), ("diastolic", *diastolic)],
+        VitalMeasurement::HeartRate(value)
+        | VitalMeasurement::Temperature(value)
+        | VitalMeasurement::SpO2(value)
+        | VitalMeasurement::Weight(value)
+        | VitalMeasurement::Glucose(value) => vec![("value", *value)],
+    }
+}
+
+fn unit_of(vital_type: VitalType) -> &'static str {
+    match vital_type {
+        VitalType::BloodPressure => "mmHg",
+        VitalType::HeartRate => "bpm",
+        VitalType::Temperature => "Cel",
+        VitalType::SpO2 => "%",
+        VitalType::Weight => "kg",
+        VitalType::Glucose => "mmol/L",
+    }
+}
+
+// Nurses take most readings, patients log home readings and doctors their own
+fn require_vitals_writer(caller: &CallerContext, patient_id: u64) -> Result<(), Error> {
+    if caller.has_role(Role::Nurse) {
+        caller.require_patient_access(patient_id, ConsentScope::WriteRecords)
+    } else {
+        caller.require_chart_writer(patient_id)
+    }
+}
+
+// Bounds reject unit mix-ups such as Fahrenheit or pounds, not unusual values
+fn validate_measurement(measurement: &VitalMeasurement) -> Result<(), Error> {
+    let plausible = match measurement {
+        VitalMeasurement::BloodPressure {
+            systolic,
+            diastolic,
+        } => {
+            (40.0..=300.0).contains(systolic)
+                && (20.0..=200.0).contains(diastolic)
+                && diastolic < systolic
+        }
+        VitalMeasurement::HeartRate(bpm) => (20.0..=300.0).contains(bpm),
+        VitalMeasurement::Temperature(celsius) => (25.0..=45.0).contains(celsius),
+        VitalMeasurement::SpO2(percent) => (50.0..=100.0).contains(percent),
+        VitalMeasurement::Weight(kg) => (0.2..=650.0).contains(kg),
+        VitalMeasurement::Glucose(mmol) => (0.5..=60.0).contains(mmol),
+    };
+
+    if plausible {
+        Ok(())
+    } else {
+        let vital_type = vital_type_of(measurement);
+        Err(Error::InvalidInput {
+            msg: format!(
+                "Implausible {:?} reading; values are expected in {}",
+                vital_type,
+                unit_of(vital_type)
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(measured_at: u64, measurement: VitalMeasurement, visit: Option<u64>) -> Vital {
+        Vital {
+            id: measured_at,
+            patient_id: 1,
+            measurement,
+            measured_at,
+            appointment_id: visit,
+            recorded_at: measured_at,
+            recorded_by: String::new(),
+        }
+    }
+
+    #[test]
+    fn no_readings_give_no_stats() {
+        assert!(vital_stats(&[]).is_empty());
+    }
+
+    #[test]
+    fn stats_summarize_the_readings() {
+        let vitals = [
+            reading(1, VitalMeasurement::HeartRate(70.0), Some(10)),
+            reading(2, VitalMeasurement::HeartRate(80.0), None),
+            reading(3, VitalMeasurement::HeartRate(75.0), Some(20)),
+        ];
+        let stats = vital_stats(&vitals);
+
+        assert_eq!(stats.len(), 1);
+        let heart_rate = &stats[0];
+        assert_eq!(heart_rate.vital_type, VitalType::HeartRate);
+        assert_eq!(heart_rate.component, "value");
+        assert_eq!(heart_rate.unit, "bpm");
+        assert_eq!(heart_rate.count, 3);
+        assert_eq!(heart_rate.min, 70.0);
+        assert_eq!(heart_rate.max, 80.0);
+        assert_eq!(heart_rate.mean, 75.0);
+        assert_eq!(heart_rate.latest, 75.0);
+        assert_eq!(heart_rate.latest_at, 3);
+        assert_eq!(heart_rate.change_since_last_visit, Some(5.0));
+    }
+
+    #[test]
+    fn home_readings_compare_with_the_last_visit() {
+        let vitals = [
+            reading(1, VitalMeasurement::Weight(82.0), Some(10)),
+            reading(2, VitalMeasurement::Weight(81.0), None),
+            reading(3, VitalMeasurement::Weight(79.5), None),
+        ];
+        let stats = vital_stats(&vitals);
+
+        assert_eq!(stats[0].latest, 79.5);
+        assert_eq!(stats[0].change_since_last_visit, Some(-2.5));
+    }
+
+    #[test]
+    fn readings_of_a_single_visit_have_no_change() {
+        let vitals = [
+            reading(1, VitalMeasurement::SpO2(96.0), Some(10)),
+            reading(2, VitalMeasurement::SpO2(98.0), Some(10)),
+        ];
+        assert_eq!(vital_stats(&vitals)[0].change_since_last_visit, None);
+
+        let home_only = [reading(1, VitalMeasurement::Glucose(5.5), None)];
+        assert_eq!(vital_stats(&home_only)[0].change_since_last_visit, None);
+    }
+
+    #[test]
+    fn blood_pressure_has_a_stat_per_component() {
+        let vitals = [
+            reading(1, VitalMeasurement::Temperature(37.0), None),
+            reading(
+                2,
+                VitalMeasurement::BloodPressure {
+                    systolic: 140.0,
+                    diastolic: 90.0,
+                },
+                Some(10),
+            ),
+            reading(
+                3,
+                VitalMeasurement::BloodPressure {
+                    systolic: 120.0,
+                    diastolic: 80.0,
+                },
+                Some(20),
+            ),
+        ];
+        let stats = vital_stats(&vitals);
+
+        // Types come in a fixed order whatever order the readings are in
+        let components: Vec<_> = stats
+            .iter()
+            .map(|stat| (stat.vital_type, stat.component.as_str()))
+            .collect();
+        assert_eq!(
+            components,
+            vec![
+                (VitalType::BloodPressure, "systolic"),
+                (VitalType::BloodPressure, "diastolic"),
+                (VitalType::Temperature, "value"),
+            ]
+        );
+        assert_eq!(stats[0].mean, 130.0);
+        assert_eq!(stats[0].change_since_last_visit, Some(-20.0));
+        assert_eq!(stats[1].min, 80.0);
+        assert_eq!(stats[1].change_since_last_visit, Some(-10.0));
+    }
+}
\ No newline at end of file